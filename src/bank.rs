@@ -1,25 +1,101 @@
-use std::mem;
+use std::io::{Error, ErrorKind, Result};
 
 use byteorder::{BigEndian, ByteOrder};
 use log::{debug, trace};
 
+const HEADER_SIZE: usize = 12;
+
 pub enum Bank {
     Uncompressed(Vec<u8>),
     Compressed(Vec<u8>),
 }
 
 impl Bank {
-    pub fn data(self) -> Vec<u8> {
+    pub fn data(self) -> Result<Vec<u8>> {
+        match self {
+            Bank::Uncompressed(data) => Ok(data),
+            Bank::Compressed(data) => unpack(&data),
+        }
+    }
+
+    /// Size of the data once unpacked
+    pub fn unpacked_size(&self) -> Result<usize> {
         match self {
-            Bank::Uncompressed(data) => data,
-            Bank::Compressed(data) => {
-                let mut unpacker = Unpacker::new(&data);
-                unpacker.unpack()
+            Bank::Uncompressed(data) => Ok(data.len()),
+            Bank::Compressed(data) => Ok(read_header(data)?.datasize as usize),
+        }
+    }
+
+    /// Unpack straight into `dst`, which must be `unpacked_size` bytes
+    pub fn read_into(&self, dst: &mut [u8]) -> Result<()> {
+        match self {
+            Bank::Uncompressed(data) => {
+                check_size(data.len(), dst)?;
+                dst.copy_from_slice(data);
+                Ok(())
             }
+            Bank::Compressed(data) => unpack_into(data, dst),
         }
     }
 }
 
+fn check_size(size: usize, dst: &[u8]) -> Result<()> {
+    if size != dst.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Unpacked size {} does not match destination size {}",
+                size,
+                dst.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Trailer stored at the end of a packed bank entry
+#[derive(Debug)]
+pub struct PackedHeader {
+    /// Size of the unpacked data
+    pub datasize: u32,
+    /// Checksum which is zero after a successful unpack
+    pub crc: u32,
+}
+
+/// Read the size and checksum of a packed bank entry without unpacking it
+pub fn read_header(data: &[u8]) -> Result<PackedHeader> {
+    if data.len() < HEADER_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Packed data too short: {} bytes", data.len()),
+        ));
+    }
+    let end = data.len();
+    Ok(PackedHeader {
+        datasize: BigEndian::read_u32(&data[end - 4..]),
+        crc: BigEndian::read_u32(&data[end - 8..]),
+    })
+}
+
+/// Unpack a compressed bank entry, verifying its checksum
+pub fn unpack(data: &[u8]) -> Result<Vec<u8>> {
+    let header = read_header(data)?;
+    let mut output = vec![0; header.datasize as usize];
+    unpack_into(data, &mut output)?;
+    Ok(output)
+}
+
+/// Unpack a compressed bank entry into `dst`, verifying its checksum. The
+/// size of `dst` must match the unpacked size in the header.
+pub fn unpack_into(data: &[u8], dst: &mut [u8]) -> Result<()> {
+    let header = read_header(data)?;
+    check_size(header.datasize as usize, dst)?;
+    let mut unpacker = Unpacker::new(data, dst);
+    unpacker.unpack()
+}
+
+/// The packed stream is decoded from its end, so the output is filled from
+/// the back of the destination towards the front
 struct Unpacker<'a> {
     data: &'a [u8],
     i: usize,
@@ -27,11 +103,14 @@ struct Unpacker<'a> {
     datasize: u32,
     crc: u32,
     chk: u32,
-    output: Vec<u8>,
+    output: &'a mut [u8],
+    /// Index of the last byte written to the output
+    pos: usize,
 }
 
 impl<'a> Unpacker<'a> {
-    fn new(data: &'a [u8]) -> Unpacker<'a> {
+    fn new(data: &'a [u8], output: &'a mut [u8]) -> Unpacker<'a> {
+        let pos = output.len();
         Unpacker {
             data,
             i: 0,
@@ -39,10 +118,20 @@ impl<'a> Unpacker<'a> {
             datasize: 0,
             crc: 0,
             chk: 0,
-            output: Vec::new(),
+            output,
+            pos,
         }
     }
 
+    fn written(&self) -> usize {
+        self.output.len() - self.pos
+    }
+
+    fn push(&mut self, val: u8) {
+        self.pos -= 1;
+        self.output[self.pos] = val;
+    }
+
     fn read_reverse_be_u32(&mut self) -> u32 {
         let result = BigEndian::read_u32(&self.data[self.i..]);
         if self.i >= 4 {
@@ -62,27 +151,45 @@ impl<'a> Unpacker<'a> {
         cf
     }
 
-    fn dec_unk1(&mut self, num_chunks: u32, add_count: u32) {
+    fn consume(&mut self, count: u32) -> Result<()> {
+        self.datasize = self.datasize.checked_sub(count).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Packed data overruns its size by {}", count - self.datasize),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn dec_unk1(&mut self, num_chunks: u32, add_count: u32) -> Result<()> {
         let mut count = self.get_code(num_chunks) + add_count + 1;
         trace!("dec_unk1({}, {}) count={}", num_chunks, add_count, count);
-        self.datasize -= count;
+        self.consume(count)?;
         while count > 0 {
             count -= 1;
             let val = self.get_code(8) as u8;
-            self.output.push(val);
+            self.push(val);
         }
+        Ok(())
     }
 
-    fn dec_unk2(&mut self, num_chunks: u32) {
+    fn dec_unk2(&mut self, num_chunks: u32) -> Result<()> {
         let i = self.get_code(num_chunks) as usize;
         let mut count = self.size + 1;
         trace!("dec_unk2({}) i={} count={}", num_chunks, i, count);
-        self.datasize -= count;
+        if i == 0 || i > self.written() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Back reference {} outside of unpacked data", i),
+            ));
+        }
+        self.consume(count)?;
         while count > 0 {
             count -= 1;
-            let val = self.output[self.output.len() - i];
-            self.output.push(val);
+            let val = self.output[self.pos - 1 + i];
+            self.push(val);
         }
+        Ok(())
     }
 
     fn get_code(&mut self, num_chunks: u32) -> u32 {
@@ -107,7 +214,7 @@ impl<'a> Unpacker<'a> {
         rcf
     }
 
-    fn unpack(&mut self) -> Vec<u8> {
+    fn unpack(&mut self) -> Result<()> {
         debug!("Unpack()");
         self.i = self.data.len() - 4;
         self.size = 0;
@@ -119,29 +226,75 @@ impl<'a> Unpacker<'a> {
             if !self.next_chunk() {
                 self.size = 1;
                 if !self.next_chunk() {
-                    self.dec_unk1(3, 0);
+                    self.dec_unk1(3, 0)?;
                 } else {
-                    self.dec_unk2(8);
+                    self.dec_unk2(8)?;
                 }
             } else {
                 let c = self.get_code(2);
                 if c == 3 {
-                    self.dec_unk1(8, 8);
+                    self.dec_unk1(8, 8)?;
                 } else if c < 2 {
                     self.size = c + 2;
-                    self.dec_unk2(c + 9);
+                    self.dec_unk2(c + 9)?;
                 } else {
                     self.size = self.get_code(8);
-                    self.dec_unk2(12);
+                    self.dec_unk2(12)?;
                 }
             }
         }
         if self.crc != 0 {
-            panic!("CRC Error: {}", self.crc);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("CRC Error: {}", self.crc),
+            ));
         }
-        self.output.reverse();
-        let mut new_output = Vec::new();
-        mem::swap(&mut self.output, &mut new_output);
-        new_output
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "AnoHild" followed by "Another World" twice, packed with both kinds
+    /// of literal runs and back references of 2, 3 and 13 bytes
+    #[rustfmt::skip]
+    const PACKED: [u8; 36] = [
+        0x00, 0x1c, 0x02, 0x25, 0x2d, 0x0b, 0x0a, 0xc0, 0x0c, 0x1c, 0x13, 0xb7,
+        0xb1, 0x70, 0xb5, 0x32, 0x70, 0x27, 0x57, 0xb2, 0x71, 0xb1, 0x31, 0x07,
+        // First bits, checksum and unpacked size
+        0x00, 0x00, 0x00, 0x01, 0x91, 0xed, 0xc8, 0xd4, 0x00, 0x00, 0x00, 0x21,
+    ];
+    const UNPACKED: &[u8] = b"AnoHildAnother WorldAnother World";
+
+    #[test]
+    fn unpacks_known_data() {
+        let bank = Bank::Compressed(PACKED.to_vec());
+        assert_eq!(bank.unpacked_size().unwrap(), UNPACKED.len());
+        let mut dst = vec![0; UNPACKED.len()];
+        bank.read_into(&mut dst).unwrap();
+        assert_eq!(dst, UNPACKED);
+        assert_eq!(bank.data().unwrap(), UNPACKED);
+    }
+
+    #[test]
+    fn checksum_mismatch_is_an_error() {
+        let mut packed = PACKED;
+        packed[31] ^= 1;
+        let error = unpack(&packed).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("CRC Error"));
+    }
+
+    #[test]
+    fn wrong_sizes_are_errors() {
+        let mut dst = vec![0; UNPACKED.len() - 1];
+        assert!(unpack_into(&PACKED, &mut dst).is_err());
+        assert!(unpack(&PACKED[..HEADER_SIZE - 1]).is_err());
+        // A bigger size runs out of packed data or back references
+        let mut packed = PACKED;
+        packed[35] += 1;
+        assert!(unpack(&packed).is_err());
     }
 }
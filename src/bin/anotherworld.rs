@@ -1,57 +1,524 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 
+use anotherworld::capture;
+use anotherworld::capture::{CaptureOutput, Export, ExportFilter, FrameDump};
+use anotherworld::demo::{Demo, DemoRecorder};
 use anotherworld::engine;
-use anotherworld::input;
+use anotherworld::mixer;
+use anotherworld::parts;
+use anotherworld::png;
 use anotherworld::resource;
-use anotherworld::resource::AssetPlatform;
+use anotherworld::resource::{AssetPlatform, ResourceError};
+use anotherworld::settings::{self, Settings};
+use anotherworld::state::{self, SaveState};
+use anotherworld::strings;
+use anotherworld::sync;
 use anotherworld::sys;
+use anotherworld::system::{HeadlessSys, System, WindowGeometry};
 use anotherworld::video;
 use anotherworld::vm;
 
+const AUTOSAVE_FILE: &str = "autosave.bin";
+
 #[derive(Parser)]
 #[command(
     name = "Another World",
     about = "A virtual machine for running Another World"
 )]
 struct Opt {
+    /// Read settings from PATH, command line flags take precedence
+    #[arg(long, default_value = settings::CONFIG_FILE, value_name = "PATH")]
+    config: PathBuf,
+    /// Write the settings in use to the config file, including changes made
+    /// while playing
+    #[arg(long)]
+    save_settings: bool,
     /// Set path of game assets
     #[arg(long, default_value = "data", value_name = "PATH")]
     asset_path: PathBuf,
-    /// Start with game part
-    #[arg(long, default_value = "2")]
+    /// Override platform detection (pc, amiga or atari)
+    #[arg(long)]
+    platform: Option<AssetPlatform>,
+    /// Start with game part (1-10)
+    #[arg(long, default_value = "2", value_parser = clap::value_parser!(u8).range(1..=10))]
     game_part: u8,
     /// Disable protection bypass
     #[arg(long)]
     no_bypass: bool,
-    /// Enable hires graphics
+    /// Ask for confirmation before quitting from the menu
+    #[arg(long)]
+    confirm_quit: bool,
+    /// Skip the protection screens when the game asks for them
+    #[arg(long)]
+    skip_protection: bool,
+    /// Start at the password screen and enter CODE
+    #[arg(long, value_parser = parse_code)]
+    code: Option<String>,
+    /// Save the game to autosave.bin in the asset directory every time a new
+    /// part starts
+    #[arg(long)]
+    autosave: bool,
+    /// Write save states uncompressed, for inspecting them
     #[arg(long)]
+    uncompressed_saves: bool,
+    /// Resume from autosave.bin in the asset directory if it exists
+    #[arg(long = "continue")]
+    resume: bool,
+    /// Write a checksum of every displayed frame to PATH
+    #[arg(long, value_name = "PATH")]
+    checksum_log: Option<PathBuf>,
+    /// Seed the random numbers of the scripts with N instead of a random
+    /// value, to reproduce a run
+    #[arg(
+        long,
+        value_name = "N",
+        allow_negative_numbers = true,
+        conflicts_with = "play"
+    )]
+    seed: Option<i16>,
+    /// Record the game input of every frame to a demo file at PATH
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["resume", "play"])]
+    record: Option<PathBuf>,
+    /// Play the demo at PATH, recorded with --record and the same options
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["resume", "code"])]
+    play: Option<PathBuf>,
+    /// Unpack all game data at startup for faster part transitions
+    #[arg(long)]
+    preload: bool,
+    /// Log resources loaded over memory used by another resource
+    #[arg(long)]
+    check_overlaps: bool,
+    /// Directory of 320x200 PNG images replacing the background bitmaps,
+    /// named background_<resource id in hex>.png
+    #[arg(long, value_name = "PATH")]
+    backgrounds: Option<PathBuf>,
+    /// Directory for screenshots and page dumps
+    #[arg(long, default_value = ".", value_name = "PATH")]
+    screenshot_dir: PathBuf,
+    /// File name for screenshots and page dumps, {name} is replaced by what
+    /// is captured and {timestamp} by the current time
+    #[arg(
+        long,
+        default_value = capture::DEFAULT_TEMPLATE,
+        value_name = "TEMPLATE",
+        value_parser = parse_template
+    )]
+    screenshot_name: String,
+    /// Write the displayed frames as numbered PNG files to DIR, relative to
+    /// the screenshot directory, 50 files per second of play
+    #[arg(long, value_name = "DIR")]
+    dump_frames: Option<PathBuf>,
+    /// Scale up screenshots, page dumps and dumped frames by N
+    #[arg(
+        long,
+        default_value = "1",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..=8)
+    )]
+    export_scale: u32,
+    /// How captures are scaled up with --export-scale: nearest repeats
+    /// pixels, linear smooths them after applying the palette, whatever the
+    /// window uses
+    #[arg(
+        long,
+        default_value = "nearest",
+        value_name = "FILTER",
+        value_parser = capture::parse_export_filter
+    )]
+    export_filter: ExportFilter,
+    /// Stop dumping frames after N frames
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "dump_frames",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    max_frames: Option<u32>,
+    /// Quit after N displayed frames and print a summary of the run
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    frames: Option<u64>,
+    /// Run without a window or audio, with a virtual clock. Needs --frames.
+    #[arg(long, requires = "frames")]
+    headless: bool,
+    /// Flip the image horizontally
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    mirror: Option<bool>,
+    /// Swap left and right, for use with --mirror
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    mirror_input: Option<bool>,
+    /// Color of the margins around the game, as RRGGBB in hex
+    #[arg(long, default_value = "000000", value_name = "HEX", value_parser = settings::parse_color)]
+    letterbox_color: (u8, u8, u8),
+    /// Skip drawing up to N frames in a row when the game can't keep up
+    #[arg(
+        long,
+        default_value = "0",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(0..=10)
+    )]
+    max_frameskip: u32,
+    /// Busy-wait the end of each frame for more even frame pacing, uses more
+    /// CPU
+    #[arg(long)]
+    precise_pacing: bool,
+    /// Run frames as fast as possible without sleeping, the game and the
+    /// music still see the original frame timing. Useful with --dump-frames.
+    #[arg(long, conflicts_with = "precise_pacing")]
+    unpaced: bool,
+    /// Run the game's 50 Hz time base at N Hz, 100 plays twice as fast and 0
+    /// as fast as possible like --unpaced. The music keeps its tempo unless
+    /// it is frame synced.
+    #[arg(
+        long,
+        default_value = "50",
+        value_name = "N",
+        conflicts_with = "unpaced",
+        value_parser = clap::value_parser!(u32).range(0..=1000)
+    )]
+    fps: u32,
+    /// Fill large polygons on several threads, helps at higher scales
+    #[arg(long)]
+    parallel_render: bool,
+    /// Step the music once per frame instead of on a timer, for reproducible
+    /// runs
+    #[arg(long)]
+    frame_synced_music: bool,
+    /// Enable hires graphics, same as --scale 2
+    #[arg(long, conflicts_with = "scale")]
     hires: bool,
+    /// Render at N times the original 320x200 resolution
+    #[arg(
+        long,
+        default_value = "1",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..=video::MAX_SCALE as i64)
+    )]
+    scale: u32,
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    scanlines: Option<bool>,
+    /// Run without opening an audio device
+    #[arg(long)]
+    no_audio: bool,
+    /// Stop the scripts with the ` key and inspect them from a console on
+    /// standard input
     #[arg(long)]
-    scanlines: bool,
+    debug: bool,
+    /// Start with the audio muted, Ctrl+M unmutes it
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    mute: Option<bool>,
+    /// Load translated strings from a file, F10 switches between English
+    /// and the loaded languages
+    #[arg(long = "strings", value_name = "FILE")]
+    string_files: Vec<PathBuf>,
+    /// Language to start with, English or the file name of --strings
+    /// without the extension
+    #[arg(long, value_name = "NAME")]
+    lang: Option<String>,
+    /// Draw [MISSING id] for strings that do not exist, and
+    /// [UNTRANSLATED id] for strings missing from the active language
+    #[arg(long)]
+    show_missing_strings: bool,
+    /// Soften the audio output with a low-pass filter like the Amiga's
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    amiga_filter: Option<bool>,
+    /// Add an echo to the audio output
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    echo: Option<bool>,
+    /// Echo delay in milliseconds
+    #[arg(
+        long,
+        default_value = "150",
+        value_name = "MS",
+        value_parser = clap::value_parser!(u32).range(1..=1000)
+    )]
+    echo_delay: u32,
+    /// Part of the echo fed back into the delay line, between 0 and 0.95
+    #[arg(long, default_value = "0.35", value_parser = settings::parse_echo_feedback)]
+    echo_feedback: f32,
+    /// Audio buffer size in sample frames, a power of two between 64 and 8192
+    #[arg(long, value_name = "FRAMES", value_parser = settings::parse_audio_buffer)]
+    audio_buffer: Option<u16>,
+    /// Output 16 bit audio, mixing with more precision than the original
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    audio_16bit: Option<bool>,
+    /// Smooth the edges of polygons
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    antialias: Option<bool>,
+    /// Enable CRT filter (bleed, vignette and aperture grille)
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_name = "BOOL"
+    )]
+    crt: Option<bool>,
+    #[command(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the game parts that can be given to --game-part
+    Parts,
+    /// Show the variables and threads that differ between two save states
+    DiffStates {
+        /// Save state shown with - lines
+        old: PathBuf,
+        /// Save state shown with + lines
+        new: PathBuf,
+    },
+    /// Enter each known level code on the password screen and show the part
+    /// it leads to with the game data in use
+    Codes {
+        /// Only show the codes leading to this part (1-10)
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=10))]
+        part: Option<u8>,
+    },
+    /// Show when a save state was made and in which part
+    SaveInfo {
+        file: PathBuf,
+        /// Write the thumbnail of the saved frame to this PNG file
+        #[arg(long)]
+        thumbnail: Option<PathBuf>,
+    },
+}
+
+fn parse_code(s: &str) -> Result<String, String> {
+    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!("Invalid code {}, expected letters only", s));
+    }
+    Ok(s.to_ascii_uppercase())
+}
+
+fn parse_template(s: &str) -> Result<String, String> {
+    if !s.contains("{name}") {
+        return Err(format!(
+            "Invalid file name {}, expected {{name}} to tell captures apart",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Settings from the config file, overridden by the flags given on the
+/// command line
+fn settings(opt: &Opt, matches: &ArgMatches) -> Settings {
+    let mut settings = match Settings::load_or_default(&opt.config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let given = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if given("asset_path") {
+        settings.asset_path = opt.asset_path.clone();
+    }
+    if opt.platform.is_some() {
+        settings.platform = opt.platform;
+    }
+    if opt.hires {
+        settings.scale = 2;
+    } else if given("scale") {
+        settings.scale = opt.scale;
+    }
+    if given("letterbox_color") {
+        settings.letterbox_color = opt.letterbox_color;
+    }
+    if given("echo_delay") {
+        settings.echo_delay = opt.echo_delay;
+    }
+    if given("echo_feedback") {
+        settings.echo_feedback = opt.echo_feedback;
+    }
+    if opt.audio_buffer.is_some() {
+        settings.audio_buffer = opt.audio_buffer;
+    }
+    settings.scanlines = opt.scanlines.unwrap_or(settings.scanlines);
+    settings.crt = opt.crt.unwrap_or(settings.crt);
+    settings.antialias = opt.antialias.unwrap_or(settings.antialias);
+    settings.mirror = opt.mirror.unwrap_or(settings.mirror);
+    settings.mirror_input = opt.mirror_input.unwrap_or(settings.mirror_input);
+    settings.amiga_filter = opt.amiga_filter.unwrap_or(settings.amiga_filter);
+    settings.echo = opt.echo.unwrap_or(settings.echo);
+    settings.audio_16bit = opt.audio_16bit.unwrap_or(settings.audio_16bit);
+    settings.mute = opt.mute.unwrap_or(settings.mute);
+    settings
 }
 
 fn main() -> std::io::Result<()> {
-    let opt = Opt::parse();
+    let matches = Opt::command().get_matches();
+    let opt = Opt::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     pretty_env_logger::init();
-    let memlist_reader = resource::MemlistReader::detect_platform(opt.asset_path);
-    let resource = memlist_reader.read_memlist()?;
+    match &opt.cmd {
+        Some(Command::Parts) => {
+            list_parts();
+            return Ok(());
+        }
+        Some(Command::DiffStates { old, new }) => return diff_states(old, new),
+        Some(Command::SaveInfo { file, thumbnail }) => {
+            return save_info(file, thumbnail.as_deref())
+        }
+        _ => {}
+    }
+    let settings = settings(&opt, &matches);
+    if let Some(Command::Codes { part }) = opt.cmd {
+        return list_codes(&settings, part);
+    }
+    if opt.save_settings {
+        if let Err(e) = settings.save(&opt.config) {
+            eprintln!("Could not save {}: {}", opt.config.display(), e);
+            std::process::exit(1);
+        }
+    }
+    let memlist_reader = match settings.platform {
+        Some(platform) => resource::MemlistReader::new(settings.asset_path.clone(), platform),
+        None => resource::MemlistReader::detect_platform(settings.asset_path.clone()),
+    };
+    let mut resource = memlist_reader.open().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
     let asset_platform = resource.asset_platform;
+    resource.set_check_overlaps(opt.check_overlaps);
+    resource.set_background_dir(opt.backgrounds.clone());
+    if opt.preload {
+        if let Err(e) = resource.preload() {
+            eprintln!("Could not preload game data: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    let sdl_context = sdl2::init().unwrap();
+    let zoom = settings.scale;
+    let (width, height) = (320 * zoom as usize, 200 * zoom as usize);
 
-    let (width, height, zoom) = if opt.hires {
-        (640, 400, 2)
-    } else {
-        (320, 200, 1)
-    };
+    let mut video = video::Video::new(width, height);
+    video.set_antialias(settings.antialias);
+    video.set_parallel_render(opt.parallel_render);
+    video.set_show_missing_strings(opt.show_missing_strings);
+    for path in &opt.string_files {
+        match strings::StringTable::load(path) {
+            Ok(table) => {
+                let selected = opt.lang.as_deref() == Some(table.name.as_str());
+                let index = video.add_string_table(table);
+                if selected {
+                    video.set_language(Some(index));
+                }
+            }
+            Err(e) => {
+                eprintln!("Could not load strings: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(lang) = &opt.lang {
+        if !lang.eq_ignore_ascii_case("english") && video.language_name() != lang {
+            eprintln!(
+                "Unknown language {}, expected English or a --strings file name",
+                lang
+            );
+            std::process::exit(1);
+        }
+    }
+    if opt.headless {
+        let sys = HeadlessSys::new(width, height);
+        let vm = vm::VirtualMachine::new(resource, video, sys, zoom, opt.seed);
+        run(opt, settings, asset_platform, vm);
+        return Ok(());
+    }
 
-    let event_pump = sdl_context.event_pump().unwrap();
-    let user_input = input::UserInput::new(event_pump);
-    let sys = sys::SDLSys::new(sdl_context, width, height, opt.scanlines);
-    let video = video::Video::new(width, height);
-    let mut vm = vm::VirtualMachine::new(resource, video, sys, user_input, zoom);
+    let sdl_context = sdl2::init().unwrap();
+    let mut geometry = WindowGeometry::default();
+    if let Some(size) = settings.window_size {
+        geometry.size = size;
+        geometry.position = settings.window_position;
+    }
+    geometry.fullscreen = settings.fullscreen;
+    let mut sys = sys::SDLSys::new(
+        sdl_context,
+        width,
+        height,
+        settings.scanlines,
+        settings.crt,
+        &geometry,
+    );
+    sys.set_audio_buffer(settings.audio_buffer);
+    sys.set_audio_16bit(settings.audio_16bit);
+    sys.set_audio(!opt.no_audio);
+    sys.set_mirror(settings.mirror);
+    sys.set_mirror_input(settings.mirror_input);
+    sys.set_precise_sleep(opt.precise_pacing);
+    let (r, g, b) = settings.letterbox_color;
+    sys.set_letterbox_color(r, g, b);
+    let vm = vm::VirtualMachine::new(resource, video, sys, zoom, opt.seed);
+    run(opt, settings, asset_platform, vm);
+    Ok(())
+}
+
+fn run<S: System>(
+    opt: Opt,
+    settings: Settings,
+    asset_platform: AssetPlatform,
+    mut vm: vm::VirtualMachine<S>,
+) {
     if !opt.no_bypass {
         vm.set_variable(0xbc, 0x10);
         vm.set_variable(0xc6, 0x80);
@@ -63,8 +530,289 @@ fn main() -> std::io::Result<()> {
         vm.set_variable(0xf2, value);
     }
 
-    let mut engine = engine::Engine::new(vm, opt.game_part);
+    {
+        let mut write_guard = sync::write(vm.mixer());
+        write_guard.set_muted(settings.mute);
+        if settings.amiga_filter {
+            write_guard.set_low_pass(Some(mixer::LowPass::new(mixer::LowPass::AMIGA_CUTOFF)));
+        }
+        if settings.echo {
+            write_guard.set_echo(Some(mixer::Echo::new(
+                settings.echo_delay,
+                settings.echo_feedback,
+            )));
+        }
+    }
+    vm.set_save_dir(settings.asset_path.clone());
+    // Kept next to the save<slot>.aws files
+    let autosave_path = settings.asset_path.join(AUTOSAVE_FILE);
+    if opt.save_settings {
+        vm.set_settings(settings, opt.config.clone());
+    }
+    vm.set_skip_protection(opt.skip_protection);
+    vm.set_max_frameskip(opt.max_frameskip);
+    // The music timer follows the wall clock, headless runs use a virtual
+    // one and demos need the music marks at the same frames
+    let deterministic = opt.headless || opt.record.is_some() || opt.play.is_some();
+    vm.set_frame_synced_music(opt.frame_synced_music || deterministic);
+    vm.set_debug(opt.debug);
+    if opt.unpaced {
+        vm.set_pacing(vm::PacingMode::Unpaced);
+    } else {
+        vm.set_frame_rate(opt.fps);
+    }
+    let export = Export {
+        filter: opt.export_filter,
+        scale: opt.export_scale,
+    };
+    let mut capture_output = CaptureOutput::new(opt.screenshot_dir, opt.screenshot_name);
+    capture_output.set_export(export);
+    if let Err(e) = capture_output.prepare() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(dir) = &opt.dump_frames {
+        let dir = capture_output.dir().join(dir);
+        match FrameDump::start(dir, opt.max_frames, export) {
+            Ok(frame_dump) => vm.set_frame_dump(Some(frame_dump)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    vm.set_capture_output(capture_output);
+    if let Some(path) = &opt.checksum_log {
+        if let Err(e) = vm.set_checksum_log(path) {
+            eprintln!("Could not create {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+    vm.set_compress_saves(!opt.uncompressed_saves);
+    if opt.autosave {
+        vm.set_autosave_path(Some(autosave_path.clone()));
+    }
+
+    let autosave = if opt.resume {
+        match SaveState::load(&autosave_path) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                eprintln!("Not continuing from {}: {}", autosave_path.display(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut game_part = match &opt.code {
+        Some(code) => {
+            vm.set_code(code);
+            10
+        }
+        None => opt.game_part,
+    };
+    if let Some(path) = &opt.play {
+        match Demo::load(path) {
+            Ok(demo) => {
+                game_part = parts::part_number(demo.part_id).unwrap();
+                vm.set_demo(demo);
+            }
+            Err(e) => {
+                eprintln!("Could not load {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &opt.record {
+        let part_id = parts::part_id(game_part).unwrap_or_else(|| {
+            eprintln!("Unknown part number: {}", game_part);
+            std::process::exit(1);
+        });
+        let seed = vm.variable(state::VM_VARIABLE_RANDOM_SEED);
+        match DemoRecorder::create(path, part_id, seed) {
+            Ok(recorder) => vm.set_demo_recorder(recorder),
+            Err(e) => {
+                eprintln!("Could not create {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let engine = match autosave {
+        Some(state) => engine::Engine::from_state(vm, &state),
+        None => engine::Engine::new(vm, game_part),
+    };
+    let mut engine = engine.unwrap_or_else(|e| {
+        eprintln!("Could not start the game: {}", e);
+        std::process::exit(1);
+    });
+
+    engine.set_confirm_quit(opt.confirm_quit);
+    let result = match opt.frames {
+        Some(frames) => run_frames(&mut engine, frames),
+        None => engine.run(),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run_frames<S: System>(engine: &mut engine::Engine<S>, frames: u64) -> Result<(), ResourceError> {
+    let start = Instant::now();
+    let mut displayed = 0;
+    for frame in engine.frames() {
+        if frame.blitted {
+            displayed += 1;
+            if displayed == frames {
+                break;
+            }
+        }
+    }
+    engine.finish();
+    let elapsed = start.elapsed();
+    if let Some(e) = engine.take_error() {
+        return Err(e);
+    }
+
+    let vm = engine.vm();
+    let part = vm.current_part();
+    let hero = vm.hero_state();
+    if displayed < frames {
+        println!("Quit after {} of {} frames", displayed, frames);
+    }
+    println!("Frames:    {}", displayed);
+    println!(
+        "Part:      0x{:04x} {}",
+        part,
+        parts::part_name(part).unwrap_or_default()
+    );
+    println!(
+        "Hero:      left/right {} up/down {} action {}",
+        hero.pos_left_right, hero.pos_up_down, hero.action
+    );
+    println!("Scroll:    {}", hero.scroll_y);
+    println!(
+        "Wall time: {:.3} s ({:.1} frames/s)",
+        elapsed.as_secs_f64(),
+        displayed as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    Ok(())
+}
+
+fn list_parts() {
+    println!("part  id      palette code  video1 video2  description");
+    for (i, part) in parts::PARTS.iter().enumerate() {
+        let video2 = match part.video2 {
+            Some(video2) => format!("0x{:02x}", video2),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:>4}  0x{:04x}  0x{:02x}    0x{:02x}  0x{:02x}   {:<6}  {}",
+            i + 1,
+            parts::GAME_PART_FIRST + i as u16,
+            part.palette,
+            part.code,
+            part.video1,
+            video2,
+            part.description.unwrap_or("-")
+        );
+    }
+}
+
+/// Frames the password screen gets to accept a code
+const CODE_FRAMES: usize = 2000;
+
+/// Types `code` on the password screen of a headless game and returns the
+/// part it switches to, `None` if the code isn't accepted
+fn part_for_code(resource: resource::Resource, code: &str) -> Result<Option<u16>, ResourceError> {
+    let video = video::Video::new(320, 200);
+    let mut vm = vm::VirtualMachine::new(resource, video, HeadlessSys::new(320, 200), 1, None);
+    vm.set_pacing(vm::PacingMode::Unpaced);
+    vm.set_code(code);
+    let mut engine = engine::Engine::new(vm, 10)?;
+    for _ in 0..CODE_FRAMES {
+        if engine.step().is_none() {
+            break;
+        }
+        let part = engine.vm().current_part();
+        if part != parts::GAME_PART10 {
+            engine.finish();
+            return Ok(Some(part));
+        }
+    }
+    engine.finish();
+    engine.take_error().map_or(Ok(None), Err)
+}
+
+fn list_codes(settings: &Settings, part_num: Option<u8>) -> std::io::Result<()> {
+    for code in parts::PC_CODES.iter() {
+        let reader = match settings.platform {
+            Some(platform) => resource::MemlistReader::new(settings.asset_path.clone(), platform),
+            None => resource::MemlistReader::detect_platform(settings.asset_path.clone()),
+        };
+        let resource = reader.read_memlist()?;
+        let part = part_for_code(resource, code)?;
+        if part_num.is_some() && part.and_then(parts::part_number) != part_num {
+            continue;
+        }
+        match part.and_then(parts::part_name) {
+            Some(name) => println!("{}  part {}", code, name),
+            None => println!("{}  not accepted", code),
+        }
+    }
+    Ok(())
+}
+
+fn diff_states(old: &Path, new: &Path) -> std::io::Result<()> {
+    let load = |path: &Path| {
+        SaveState::load(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    };
+    let (old_state, new_state) = (load(old)?, load(new)?);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "--- {}", old.display())?;
+    writeln!(out, "+++ {}", new.display())?;
+    old_state.write_diff(&new_state, &mut out)?;
+    Ok(())
+}
 
-    engine.run();
+fn save_info(file: &Path, thumbnail_path: Option<&Path>) -> std::io::Result<()> {
+    let state = SaveState::load(file)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+    let name = parts::part_name(state.part_id).unwrap_or_else(|| "unknown".to_string());
+    println!("Part:      0x{:04x} {}", state.part_id, name);
+    match state
+        .timestamp
+        .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+    {
+        Some(time) => {
+            let local = time.with_timezone(&chrono::Local);
+            println!("Saved:     {}", local.format("%Y-%m-%d %H:%M:%S"));
+        }
+        None => println!("Saved:     unknown"),
+    }
+    match &state.thumbnail {
+        Some(thumbnail) => println!("Thumbnail: {}x{}", thumbnail.width, thumbnail.height),
+        None => println!("Thumbnail: none"),
+    }
+    if let Some(path) = thumbnail_path {
+        let thumbnail = state.thumbnail.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} has no thumbnail", file.display()),
+            )
+        })?;
+        png::save_indexed(
+            path,
+            thumbnail.width,
+            thumbnail.height,
+            &thumbnail.palette(),
+            &thumbnail.pixels,
+        )?;
+        println!("Wrote thumbnail to {}", path.display());
+    }
     Ok(())
 }
@@ -0,0 +1,157 @@
+use std::cmp;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use anotherworld::parts;
+use anotherworld::resource;
+use anotherworld::resource::{AssetPlatform, Resource};
+
+const BYTES_PER_LINE: usize = 16;
+
+#[derive(Parser)]
+#[command(
+    name = "Another World memory viewer",
+    about = "Hex dump of the resource memory after a part has been set up"
+)]
+struct Opt {
+    /// Set path of game assets
+    #[arg(long, default_value = "data", value_name = "PATH")]
+    asset_path: PathBuf,
+    /// Override platform detection (pc, amiga or atari)
+    #[arg(long)]
+    platform: Option<AssetPlatform>,
+    /// Game part to set up (1-10)
+    #[arg(long, default_value = "2")]
+    game_part: u8,
+    /// First address to dump, decimal or hex with 0x prefix
+    #[arg(long, value_parser = parse_address)]
+    start: Option<usize>,
+    /// End address (exclusive), defaults to the end of the loaded segments
+    #[arg(long, value_parser = parse_address)]
+    end: Option<usize>,
+}
+
+fn parse_address(s: &str) -> Result<usize, String> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|e| format!("Invalid address {}: {}", s, e))
+}
+
+struct Segment {
+    name: &'static str,
+    resource_id: usize,
+    start: usize,
+    size: usize,
+}
+
+fn segments(res: &Resource, part: &parts::Part) -> Vec<Segment> {
+    let mut entries = vec![
+        ("seg_palettes", part.palette, res.seg_palettes),
+        ("seg_bytecode", part.code, res.seg_bytecode),
+        ("seg_cinematic", part.video1, res.seg_cinematic),
+    ];
+    if let Some(video2) = part.video2 {
+        entries.push(("seg_video2", video2, res.seg_video2));
+    }
+    let mut segments: Vec<Segment> = entries
+        .into_iter()
+        .map(|(name, resource_id, start)| Segment {
+            name,
+            resource_id,
+            start,
+            size: res.mem_list[resource_id].size(),
+        })
+        .collect();
+    segments.sort_by_key(|s| s.start);
+    segments
+}
+
+fn main() -> std::io::Result<()> {
+    let opt = Opt::parse();
+    pretty_env_logger::init();
+    let memlist_reader = match opt.platform {
+        Some(platform) => resource::MemlistReader::new(opt.asset_path, platform),
+        None => resource::MemlistReader::detect_platform(opt.asset_path),
+    };
+    let mut res = memlist_reader.open().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let part_id = match parts::part_id(opt.game_part) {
+        Some(part_id) => part_id,
+        None => {
+            eprintln!("Unknown part number: {}", opt.game_part);
+            std::process::exit(1);
+        }
+    };
+    res.setup_part(part_id)?;
+    let part = parts::Part::for_id(part_id).unwrap();
+    let segments = segments(&res, part);
+
+    let loaded_end = segments.iter().map(|s| s.start + s.size).max().unwrap_or(0);
+    let start = opt.start.unwrap_or(0).min(res.memory.len());
+    let end = opt.end.unwrap_or(loaded_end).min(res.memory.len());
+
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    writeln!(out, "Part {} (0x{:04x})", opt.game_part, part_id)?;
+    for s in &segments {
+        writeln!(
+            out,
+            "  {:<14} resource 0x{:02x} at 0x{:05x}-0x{:05x} ({} bytes)",
+            s.name,
+            s.resource_id,
+            s.start,
+            s.start + s.size,
+            s.size
+        )?;
+    }
+    writeln!(out)?;
+
+    let mut addr = start;
+    while addr < end {
+        for s in segments.iter().filter(|s| s.start == addr) {
+            writeln!(out, "-- {} (resource 0x{:02x}) --", s.name, s.resource_id)?;
+        }
+        // Lines are aligned to 16 bytes but are cut short where a segment starts
+        let mut line_end = cmp::min(end, (addr / BYTES_PER_LINE + 1) * BYTES_PER_LINE);
+        if let Some(next) = segments
+            .iter()
+            .map(|s| s.start)
+            .filter(|&s| s > addr && s < line_end)
+            .min()
+        {
+            line_end = next;
+        }
+        write_line(&mut out, addr, &res.memory[addr..line_end])?;
+        addr = line_end;
+    }
+    Ok(())
+}
+
+fn write_line<W: Write>(out: &mut W, addr: usize, bytes: &[u8]) -> std::io::Result<()> {
+    let column = addr % BYTES_PER_LINE;
+    let mut hex = "   ".repeat(column);
+    let mut ascii = " ".repeat(column);
+    for b in bytes {
+        hex.push_str(&format!("{:02x} ", b));
+        ascii.push(if b.is_ascii_graphic() || *b == b' ' {
+            *b as char
+        } else {
+            '.'
+        });
+    }
+    writeln!(
+        out,
+        "{:05x}  {:<width$} |{:<16}|",
+        addr - column,
+        hex,
+        ascii,
+        width = BYTES_PER_LINE * 3
+    )
+}
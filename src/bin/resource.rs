@@ -1,14 +1,26 @@
-use std::path::PathBuf;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 use std::{thread, time};
 
-use clap::{Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use sdl2::audio::AudioSpecDesired;
 
-use anotherworld::input;
+use anotherworld::disasm::{Cfg, EdgeKind, Instruction};
 use anotherworld::mixer;
+use anotherworld::overlay;
+use anotherworld::parts;
+use anotherworld::player::{PlayerDirection, PlayerInput};
+use anotherworld::png;
 use anotherworld::resource;
+use anotherworld::resource::AssetPlatform;
+use anotherworld::settings::{self, Settings};
+use anotherworld::sync;
 use anotherworld::sys;
+use anotherworld::system::{System, WindowGeometry};
 use anotherworld::video;
+use anotherworld::wav;
 
 #[derive(Parser)]
 #[command(
@@ -16,24 +28,241 @@ use anotherworld::video;
     about = "A tool to inspect Another World resources"
 )]
 struct Opt {
+    /// Read the asset path and platform from PATH, command line flags take
+    /// precedence
+    #[arg(long, default_value = settings::CONFIG_FILE, value_name = "PATH")]
+    config: PathBuf,
     /// Set path of game assets
     #[arg(long, default_value = "data", value_name = "PATH")]
     asset_path: PathBuf,
+    /// Override platform detection (pc, amiga or atari)
+    #[arg(long)]
+    platform: Option<AssetPlatform>,
     #[command(subcommand)]
     cmd: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
+    /// Sound test: pick a sound resource with up and down and play it with
+    /// the action button
     List {},
+    /// Export a cinematic shape to SVG on stdout
+    Svg {
+        /// Resource id of the PolyCinematic entry
+        resource_id: usize,
+        /// Byte offset of the shape within the resource
+        offset: usize,
+        /// Palette number within the part's palette resource
+        #[arg(
+            long,
+            default_value = "0",
+            value_parser = clap::value_parser!(u8).range(0..NUM_PALETTES as i64)
+        )]
+        palette: u8,
+        /// Zoom factor, where 64 is 1:1
+        #[arg(long, default_value = "64")]
+        zoom: u32,
+    },
+    /// Render a cinematic shape to a PNG file
+    RenderShape {
+        /// Resource id of the PolyCinematic entry
+        resource_id: usize,
+        /// Byte offset of the shape within the resource
+        offset: usize,
+        /// Palette number within the part's palette resource
+        #[arg(
+            long,
+            default_value = "0",
+            value_parser = clap::value_parser!(u8).range(0..NUM_PALETTES as i64)
+        )]
+        palette: u8,
+        /// Zoom factor, where 64 is 1:1
+        #[arg(long, default_value = "64")]
+        zoom: u32,
+        /// Draw every polygon in color N instead of the shape's own colors
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=0x0f))]
+        color: Option<u8>,
+        /// Color the shape is drawn on
+        #[arg(
+            long,
+            default_value = "0",
+            value_name = "N",
+            value_parser = clap::value_parser!(u8).range(0..=0x0f)
+        )]
+        background: u8,
+        /// Output file, shape_<resource_id>_<offset>.png by default
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Browse the background bitmaps of the PolyAnim entries, left and right
+    /// step through the entries, up and down through the part's palettes
+    Backgrounds {
+        /// Game part whose palettes are used (1-10)
+        #[arg(long, default_value = "1")]
+        part: u8,
+    },
+    /// Print the structure of a music module as text
+    Music {
+        /// Resource id of the Music entry
+        resource_id: u16,
+    },
+    /// Play a sound resource and exit when it has finished
+    PlaySound {
+        /// Resource id of the Sound entry
+        resource_id: u16,
+        /// Index into the frequency table, like the game scripts use
+        #[arg(
+            long,
+            default_value = "20",
+            value_parser = clap::value_parser!(u8).range(0..mixer::FREQUENCE_TABLE.len() as i64)
+        )]
+        freq: u8,
+        /// Volume, between 0 and 63
+        #[arg(
+            long,
+            default_value = "63",
+            value_parser = clap::value_parser!(u8).range(0..=0x3f)
+        )]
+        vol: u8,
+    },
+    /// Export a sound resource to a mono 8 bit WAV file
+    ExportSound {
+        /// Resource id of the Sound entry
+        resource_id: u16,
+        /// Index into the frequency table, the sample rate of the file
+        #[arg(
+            long,
+            default_value = "20",
+            value_parser = clap::value_parser!(u8).range(0..mixer::FREQUENCE_TABLE.len() as i64)
+        )]
+        freq: u8,
+        /// Output file, sound_<resource_id>.wav by default
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Export every sound resource to WAV files in a directory
+    ExportAllSounds {
+        /// Directory the sound_<resource_id>.wav files are written to
+        dir: PathBuf,
+        /// Index into the frequency table, the sample rate of the files
+        #[arg(
+            long,
+            default_value = "20",
+            value_parser = clap::value_parser!(u8).range(0..mixer::FREQUENCE_TABLE.len() as i64)
+        )]
+        freq: u8,
+    },
+    /// Write the unpacked data of a resource to a file
+    Dump {
+        /// Resource id of the memlist entry
+        resource_id: u16,
+        /// Output file, resource_<resource_id>.bin by default
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Export the control-flow graph of a part's bytecode as DOT on stdout
+    Cfg {
+        /// Game part (1-10)
+        part: u8,
+    },
+    /// Print a part's bytecode from start to end, one instruction per line
+    Disasm {
+        /// Game part (1-10)
+        part: u8,
+    },
 }
 
 fn main() -> std::io::Result<()> {
-    let opt = Opt::parse();
+    let matches = Opt::command().get_matches();
+    let opt = Opt::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     pretty_env_logger::init();
-    let memlist_reader = resource::MemlistReader::detect_platform(opt.asset_path);
-    let mut res = memlist_reader.read_memlist()?;
+    let mut settings = match Settings::load_or_default(&opt.config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if matches.value_source("asset_path") == Some(ValueSource::CommandLine) {
+        settings.asset_path = opt.asset_path;
+    }
+    if opt.platform.is_some() {
+        settings.platform = opt.platform;
+    }
+    let memlist_reader = match settings.platform {
+        Some(platform) => resource::MemlistReader::new(settings.asset_path, platform),
+        None => resource::MemlistReader::detect_platform(settings.asset_path),
+    };
+    let mut res = memlist_reader.open().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
 
+    match opt.cmd {
+        Command::List {} => list(res),
+        Command::Svg {
+            resource_id,
+            offset,
+            palette,
+            zoom,
+        } => svg(res, resource_id, offset, palette, zoom),
+        Command::RenderShape {
+            resource_id,
+            offset,
+            palette,
+            zoom,
+            color,
+            background,
+            output,
+        } => {
+            let output = output.unwrap_or_else(|| {
+                PathBuf::from(format!("shape_{:02x}_{:04x}.png", resource_id, offset))
+            });
+            let shape = Shape {
+                resource_id,
+                offset,
+                palette,
+                zoom,
+            };
+            render_shape(res, shape, color, background, &output)
+        }
+        Command::Backgrounds { part } => backgrounds(res, part),
+        Command::Music { resource_id } => music(res, resource_id),
+        Command::PlaySound {
+            resource_id,
+            freq,
+            vol,
+        } => play_sound(res, resource_id, freq, vol),
+        Command::ExportSound {
+            resource_id,
+            freq,
+            output,
+        } => {
+            let output = output.unwrap_or_else(|| sound_file_name(resource_id));
+            export_sound(&mut res, resource_id, freq, &output)
+        }
+        Command::ExportAllSounds { dir, freq } => export_all_sounds(res, &dir, freq),
+        Command::Dump {
+            resource_id,
+            output,
+        } => {
+            let output = output
+                .unwrap_or_else(|| PathBuf::from(format!("resource_{:02x}.bin", resource_id)));
+            dump(&res, resource_id, &output)
+        }
+        Command::Cfg { part } => cfg(res, part),
+        Command::Disasm { part } => disasm(res, part),
+    }
+}
+
+/// Index into the frequency table the sound test plays sounds at
+const SOUND_TEST_FREQ: usize = 20;
+
+/// Sounds listed per screen in the sound test
+const SOUND_TEST_ROWS: usize = 22;
+
+fn list(mut res: resource::Resource) -> std::io::Result<()> {
     let sdl_context = sdl2::init().unwrap();
 
     let (width, height, _zoom) = if false { (640, 400, 2) } else { (320, 200, 1) };
@@ -143,44 +372,527 @@ fn main() -> std::io::Result<()> {
     video.palette_requested = Some(palette);
     video.change_page_ptr1(0);
 
-    let event_pump = sdl_context.event_pump().unwrap();
-    let mut user_input = input::UserInput::new(event_pump);
-
-    let mut sys = sys::SDLSys::new(sdl_context, width, height, false);
+    let mut sys = sys::SDLSys::new(
+        sdl_context,
+        width,
+        height,
+        false,
+        false,
+        &WindowGeometry::default(),
+    );
 
     let mixer = Arc::new(RwLock::new(mixer::Mixer::new()));
     sys.start_audio(mixer.clone());
 
-    match opt.cmd {
-        Command::List {} => {
-            for i in 0..res.mem_list.len() {
-                println!("i : {}", i);
-                if res.mem_list[i].entry_type == resource::EntryType::Sound {
-                    let resource_id = i as u16;
-                    video.fill_video_page(0, 0);
-                    video.draw_string(
-                        1,
-                        1,
-                        10,
-                        &format!("Resource: {:03} - {:#?}", i, res.mem_list[i].entry_type),
-                        1,
-                    );
-                    video.update_display(&mut sys, 0);
-
-                    res.load_memory_entry(resource_id);
-                    if let Some(chunk) = res.get_entry_mixer_chunk(resource_id) {
-                        let mut write_guard = mixer.write().expect("Expected non-poisoned RwLock");
-                        let vol = 255;
-                        write_guard.play_channel(0, chunk, 10000, vol);
-                    }
-                    if user_input.process_events().quit {
-                        return Ok(());
-                    }
-                    res.invalidate_resource();
-                    thread::sleep(time::Duration::from_millis(1000));
+    let sounds: Vec<u16> = res
+        .mem_list
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.entry_type == resource::EntryType::Sound)
+        .map(|(i, _)| i as u16)
+        .collect();
+
+    let frequency = mixer::FREQUENCE_TABLE[SOUND_TEST_FREQ];
+    let mut cursor = 0;
+    let mut previous = PlayerInput::new();
+    let mut redraw = true;
+    loop {
+        if redraw {
+            video.fill_video_page(0, 0);
+            video.draw_string(1, 1, 0, "Sound test", 1);
+            video.draw_string(1, 1, 8, "Action: play, hold to loop", 1);
+            let top = cursor / SOUND_TEST_ROWS * SOUND_TEST_ROWS;
+            for (row, &resource_id) in sounds.iter().enumerate().skip(top).take(SOUND_TEST_ROWS) {
+                let marker = if row == cursor { '>' } else { ' ' };
+                let line = format!(
+                    "{} {:02x}  {:>6} bytes",
+                    marker,
+                    resource_id,
+                    res.mem_list[resource_id as usize].size()
+                );
+                video.draw_string(1, 1, 24 + 8 * (row - top) as u16, &line, 1);
+            }
+            video.update_display(&mut sys, 0, &overlay::Overlay::new());
+            redraw = false;
+        }
+
+        let input = sys.process_events();
+        if input.quit || input.menu {
+            return Ok(());
+        }
+        if input.resized {
+            redraw = true;
+        }
+        let pressed = input.direction - previous.direction;
+        let selected = cursor;
+        if !sounds.is_empty() && pressed.contains(PlayerDirection::DOWN) {
+            cursor = (cursor + 1) % sounds.len();
+        }
+        if !sounds.is_empty() && pressed.contains(PlayerDirection::UP) {
+            cursor = (cursor + sounds.len() - 1) % sounds.len();
+        }
+        if cursor != selected {
+            redraw = true;
+            sync::write(&mixer).stop_channel(0);
+        }
+
+        // Pressing the button plays the sound, holding it down restarts the
+        // sound whenever it ends
+        let playing = sync::read(&mixer).is_playing(0);
+        if input.button && (!previous.button || !playing) {
+            if let Some(&resource_id) = sounds.get(cursor) {
+                res.load_memory_entry(resource_id)?;
+                if let Some(chunk) = res.get_entry_mixer_chunk(resource_id) {
+                    let mut write_guard = sync::write(&mixer);
+                    write_guard.play_channel(0, chunk, frequency, 0x3f);
                 }
+                res.invalidate_resource();
             }
         }
+        previous = input;
+        thread::sleep(time::Duration::from_millis(20));
     }
+}
+
+/// Sets up the part using the video resource `resource_id` and returns the
+/// palette numbered `palette` and the start of the resource in memory
+fn setup_shape_part(
+    res: &mut resource::Resource,
+    resource_id: usize,
+    palette: u8,
+) -> std::io::Result<(video::Palette, usize)> {
+    let (part_index, video2) = parts::PARTS
+        .iter()
+        .enumerate()
+        .find_map(|(i, part)| {
+            if part.video1 == resource_id {
+                Some((i, false))
+            } else if part.video2 == Some(resource_id) {
+                Some((i, true))
+            } else {
+                None
+            }
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Resource {} is not used as video by any part", resource_id),
+            )
+        })?;
+    res.setup_part(parts::GAME_PART_FIRST + part_index as u16)?;
+
+    let palette_offset = res.seg_palettes + palette as usize * 32;
+    let palette = video::Palette::from_bytes(&res.memory[palette_offset..palette_offset + 32]);
+
+    let segment = if video2 {
+        res.seg_video2
+    } else {
+        res.seg_cinematic
+    };
+    Ok((palette, segment))
+}
+
+fn svg(
+    mut res: resource::Resource,
+    resource_id: usize,
+    offset: usize,
+    palette: u8,
+    zoom: u32,
+) -> std::io::Result<()> {
+    let (palette, segment) = setup_shape_part(&mut res, resource_id, palette)?;
+    let mut buffer = Cursor::new(&res.memory[segment..]);
+    buffer.set_position(offset as u64);
+    let origin = video::Point { x: 160, y: 100 };
+    let polygons = video::Video::read_polygons(&mut buffer, 0xff, zoom, origin)?;
+
+    println!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 320 200\" width=\"320\" height=\"200\">"
+    );
+    for (polygon, color, point) in polygons {
+        let fill = match color {
+            0..=0x0f => {
+                let c = palette.entries[color as usize];
+                format!("fill=\"#{:02x}{:02x}{:02x}\"", c.r, c.g, c.b)
+            }
+            // Blended with the page below
+            0x10 => "fill=\"#ffffff\" fill-opacity=\"0.25\"".to_string(),
+            // Copied from the background page
+            _ => "fill=\"none\" stroke=\"#808080\"".to_string(),
+        };
+        if polygon.is_point() {
+            println!(
+                "  <rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\" {}/>",
+                point.x, point.y, fill
+            );
+        } else {
+            let points: Vec<String> = polygon
+                .vertices_at(point)
+                .iter()
+                .map(|p| format!("{},{}", p.x, p.y))
+                .collect();
+            println!("  <polygon points=\"{}\" {}/>", points.join(" "), fill);
+        }
+    }
+    println!("</svg>");
+    Ok(())
+}
+
+/// A shape within a cinematic resource, as given on the command line
+struct Shape {
+    resource_id: usize,
+    offset: usize,
+    palette: u8,
+    zoom: u32,
+}
+
+fn render_shape(
+    mut res: resource::Resource,
+    shape: Shape,
+    color: Option<u8>,
+    background: u8,
+    output: &Path,
+) -> std::io::Result<()> {
+    let (palette, segment) = setup_shape_part(&mut res, shape.resource_id, shape.palette)?;
+
+    let (width, height) = (320, 200);
+    let mut video = video::Video::new(width, height);
+    // Copied polygons read from page 0, so draw on top of the same background
+    video.fill_video_page(0, background);
+    video.fill_video_page(1, background);
+    video.change_page_ptr1(1);
+
+    let mut buffer = Cursor::new(&res.memory[segment..]);
+    buffer.set_position(shape.offset as u64);
+    let origin = video::Point { x: 160, y: 100 };
+    video.read_and_draw_polygon(&mut buffer, color.unwrap_or(0xff), shape.zoom, origin)?;
+
+    png::save_indexed(output, width, height, &palette, &video.page(1).data)?;
+    println!("Wrote {}", output.display());
+    Ok(())
+}
+
+/// Palettes in a palette resource
+const NUM_PALETTES: usize = 32;
+
+fn backgrounds(mut res: resource::Resource, part_num: u8) -> std::io::Result<()> {
+    let part_id = parts::part_id(part_num).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown part number: {}", part_num),
+        )
+    })?;
+    res.setup_part(part_id)?;
+    let entries: Vec<u16> = res
+        .mem_list
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.entry_type == resource::EntryType::PolyAnim)
+        .map(|(i, _)| i as u16)
+        .collect();
+    if entries.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No PolyAnim resources",
+        ));
+    }
+
+    let sdl_context = sdl2::init().unwrap();
+    let (width, height) = (320, 200);
+    let mut video = video::Video::new(width, height);
+    let mut sys = sys::SDLSys::new(
+        sdl_context,
+        width,
+        height,
+        false,
+        false,
+        &WindowGeometry::default(),
+    );
+
+    let mut index = 0;
+    let mut palette = 0;
+    let mut previous = PlayerDirection::empty();
+    let mut redraw = true;
+    loop {
+        if redraw {
+            let resource_id = entries[index];
+            println!("Resource 0x{:02x}, palette {}", resource_id, palette);
+            res.load_memory_entry(resource_id)?;
+            res.copy_vid_ptr = false;
+            video.copy_page_buffer(&res.video_page_data());
+            res.invalidate_resource();
+
+            let offset = res.seg_palettes + palette * 32;
+            video.palette_requested =
+                Some(video::Palette::from_bytes(&res.memory[offset..offset + 32]));
+            video.update_display(&mut sys, 0, &overlay::Overlay::new());
+            redraw = false;
+        }
+
+        let input = sys.process_events();
+        if input.quit || input.menu {
+            return Ok(());
+        }
+        if input.resized {
+            redraw = true;
+        }
+        let pressed = input.direction - previous;
+        previous = input.direction;
+        if pressed.contains(PlayerDirection::RIGHT) {
+            index = (index + 1) % entries.len();
+            redraw = true;
+        }
+        if pressed.contains(PlayerDirection::LEFT) {
+            index = (index + entries.len() - 1) % entries.len();
+            redraw = true;
+        }
+        if pressed.contains(PlayerDirection::UP) {
+            palette = (palette + 1) % NUM_PALETTES;
+            redraw = true;
+        }
+        if pressed.contains(PlayerDirection::DOWN) {
+            palette = (palette + NUM_PALETTES - 1) % NUM_PALETTES;
+            redraw = true;
+        }
+        thread::sleep(time::Duration::from_millis(20));
+    }
+}
+
+fn setup_part(res: &mut resource::Resource, part_num: u8) -> std::io::Result<()> {
+    let part_id = parts::part_id(part_num).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown part number: {}", part_num),
+        )
+    })?;
+    res.setup_part(part_id)?;
+    Ok(())
+}
+
+fn cfg(mut res: resource::Resource, part_num: u8) -> std::io::Result<()> {
+    setup_part(&mut res, part_num)?;
+    let cfg = Cfg::build(res.bytecode());
+
+    println!("digraph part{} {{", part_num);
+    println!("  node [shape=box, fontname=monospace];");
+    for block in cfg.blocks.values() {
+        let mut label = String::new();
+        for instruction in &block.instructions {
+            label.push_str(&format!("{:04x}: {}\\l", instruction.offset, instruction));
+        }
+        println!("  b{:04x} [label=\"{}\"];", block.start, label);
+        for (target, kind) in &block.successors {
+            let style = match kind {
+                EdgeKind::Fallthrough => "",
+                EdgeKind::Jump => " [color=blue]",
+                EdgeKind::Call => " [style=dashed]",
+            };
+            println!("  b{:04x} -> b{:04x}{};", block.start, target, style);
+        }
+    }
+    for (thread_id, entry) in &cfg.thread_entries {
+        println!(
+            "  t{:02x}_{:04x} [label=\"thread {}\", shape=ellipse];",
+            thread_id, entry, thread_id
+        );
+        println!("  t{:02x}_{:04x} -> b{:04x};", thread_id, entry, entry);
+    }
+    println!("}}");
+    Ok(())
+}
+
+/// Decodes the bytecode linearly, so data between routines shows up as
+/// instructions too. Bytes that aren't an opcode are printed as `db`.
+fn disasm(mut res: resource::Resource, part_num: u8) -> std::io::Result<()> {
+    setup_part(&mut res, part_num)?;
+    let code = res.bytecode();
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let mut offset = 0;
+    while offset < code.len() {
+        match Instruction::decode(code, offset) {
+            Some(instruction) => {
+                let bytes: Vec<String> = code[offset..instruction.next_offset()]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                writeln!(
+                    out,
+                    "{:04x}: {:<24} {}",
+                    offset,
+                    bytes.join(" "),
+                    instruction
+                )?;
+                offset = instruction.next_offset();
+            }
+            None => {
+                writeln!(
+                    out,
+                    "{:04x}: {:<24} db 0x{:02x}",
+                    offset,
+                    format!("{:02x}", code[offset]),
+                    code[offset]
+                )?;
+                offset += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn music(mut res: resource::Resource, resource_id: u16) -> std::io::Result<()> {
+    res.load_music_entry(resource_id)?;
+    let mut delay = 0;
+    let module = res
+        .load_sfx_module(resource_id, &mut delay, 0)?
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Resource 0x{:x} is not a music module", resource_id),
+            )
+        })?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "resource: 0x{:02x}", resource_id)?;
+    writeln!(out, "delay: {}", delay)?;
+    module.write_structure(&mut out)
+}
+
+/// Looping sounds are stopped after this long
+const MAX_SOUND_MS: u64 = 5000;
+
+/// Loads a sound resource and returns its samples
+fn load_sound(
+    res: &mut resource::Resource,
+    resource_id: u16,
+) -> std::io::Result<mixer::MixerChunk> {
+    let is_sound = res
+        .mem_list
+        .get(resource_id as usize)
+        .map(|entry| entry.entry_type == resource::EntryType::Sound);
+    if is_sound != Some(true) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Resource 0x{:x} is not a sound", resource_id),
+        ));
+    }
+    res.load_memory_entry(resource_id)?;
+    let chunk = res.get_entry_mixer_chunk(resource_id).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Could not load sound resource 0x{:x}", resource_id),
+        )
+    })?;
+    res.invalidate_resource();
+    Ok(chunk)
+}
+
+fn play_sound(
+    mut res: resource::Resource,
+    resource_id: u16,
+    freq: u8,
+    vol: u8,
+) -> std::io::Result<()> {
+    let chunk = load_sound(&mut res, resource_id)?;
+
+    let sdl_context = sdl2::init().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let desired_spec = AudioSpecDesired {
+        freq: Some(mixer::SOUND_SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: None,
+    };
+    let mixer = Arc::new(RwLock::new(mixer::Mixer::new()));
+    let device = audio_subsystem
+        .open_playback(None, &desired_spec, |_| mixer::MixerAudio(mixer.clone()))
+        .unwrap();
+
+    let frequency = mixer::FREQUENCE_TABLE[freq as usize];
+    println!(
+        "Playing sound 0x{:02x} at {} Hz, volume {}",
+        resource_id, frequency, vol
+    );
+    sync::write(&mixer).play_channel(0, chunk, frequency, vol);
+    device.resume();
+
+    let start = time::Instant::now();
+    while sync::read(&mixer).is_playing(0) {
+        if start.elapsed() >= time::Duration::from_millis(MAX_SOUND_MS) {
+            println!("Stopping looping sound after {} ms", MAX_SOUND_MS);
+            break;
+        }
+        thread::sleep(time::Duration::from_millis(20));
+    }
+    // Let the last mixed buffer play out
+    thread::sleep(time::Duration::from_millis(200));
+    Ok(())
+}
+
+fn sound_file_name(resource_id: u16) -> PathBuf {
+    PathBuf::from(format!("sound_{:02x}.wav", resource_id))
+}
+
+/// The samples have no rate of their own, the scripts pick one from the
+/// frequency table whenever a sound is played
+fn export_sound(
+    res: &mut resource::Resource,
+    resource_id: u16,
+    freq: u8,
+    output: &Path,
+) -> std::io::Result<()> {
+    let chunk = load_sound(res, resource_id)?;
+    let sample_rate = mixer::FREQUENCE_TABLE[freq as usize] as u32;
+    wav::save_mono_i8(output, sample_rate, chunk.data())?;
+    if chunk.loop_len() > 0 {
+        println!(
+            "Wrote {}, {} samples at {} Hz, loops {} samples from {}",
+            output.display(),
+            chunk.data().len(),
+            sample_rate,
+            chunk.loop_len(),
+            chunk.loop_pos()
+        );
+    } else {
+        println!(
+            "Wrote {}, {} samples at {} Hz",
+            output.display(),
+            chunk.data().len(),
+            sample_rate
+        );
+    }
+    Ok(())
+}
+
+fn export_all_sounds(mut res: resource::Resource, dir: &Path, freq: u8) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let sounds: Vec<u16> = res
+        .mem_list
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.entry_type == resource::EntryType::Sound)
+        .map(|(i, _)| i as u16)
+        .collect();
+    for resource_id in sounds {
+        export_sound(
+            &mut res,
+            resource_id,
+            freq,
+            &dir.join(sound_file_name(resource_id)),
+        )?;
+    }
+    Ok(())
+}
+
+fn dump(res: &resource::Resource, resource_id: u16, output: &Path) -> std::io::Result<()> {
+    let data = res.decompress_entry(resource_id)?;
+    std::fs::write(output, &data)?;
+    let entry = &res.mem_list[resource_id as usize];
+    println!(
+        "Wrote {}, {:?} resource 0x{:02x}, {} bytes packed, {} bytes unpacked",
+        output.display(),
+        entry.entry_type,
+        resource_id,
+        entry.packed_size(),
+        data.len()
+    );
     Ok(())
 }
@@ -0,0 +1,254 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use log::{error, info};
+
+use crate::png;
+use crate::util;
+use crate::video::Palette;
+
+/// Default file name for captures, `{name}` is replaced by what is captured
+/// and `{timestamp}` by the local time
+pub const DEFAULT_TEMPLATE: &str = "{name}-{timestamp}.png";
+
+/// How captures are scaled up
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFilter {
+    /// Repeat each pixel, keeping the image indexed
+    Nearest,
+    /// Blend neighbouring pixels after applying the palette
+    Linear,
+}
+
+pub fn parse_export_filter(s: &str) -> std::result::Result<ExportFilter, String> {
+    match s {
+        "nearest" => Ok(ExportFilter::Nearest),
+        "linear" => Ok(ExportFilter::Linear),
+        _ => Err(format!("Invalid filter {}, expected nearest or linear", s)),
+    }
+}
+
+/// Size and filter of the saved images, independent of the window
+#[derive(Clone, Copy, Debug)]
+pub struct Export {
+    pub filter: ExportFilter,
+    pub scale: u32,
+}
+
+impl Export {
+    /// Writes the indexed `width` x `height` page `pixels` to `path`, scaled
+    /// up by `scale`
+    pub fn save(
+        &self,
+        path: &Path,
+        (width, height): (usize, usize),
+        palette: &Palette,
+        pixels: &[u8],
+    ) -> Result<()> {
+        let scaled_size = (width * self.scale as usize, height * self.scale as usize);
+        match self.filter {
+            _ if self.scale == 1 => png::save_indexed(path, width, height, palette, pixels),
+            ExportFilter::Nearest => {
+                let scaled = util::scale_nearest(pixels, width, height, self.scale);
+                png::save_indexed(path, scaled_size.0, scaled_size.1, palette, &scaled)
+            }
+            ExportFilter::Linear => {
+                let rgba = palette.to_rgba(&pixels[..width * height]);
+                let scaled = util::scale_bilinear(&rgba, (width, height), scaled_size);
+                png::save_rgba(path, scaled_size.0, scaled_size.1, &scaled)
+            }
+        }
+    }
+}
+
+impl Default for Export {
+    fn default() -> Export {
+        Export {
+            filter: ExportFilter::Nearest,
+            scale: 1,
+        }
+    }
+}
+
+/// Where screenshots and other captures are written and how they are named
+pub struct CaptureOutput {
+    dir: PathBuf,
+    template: String,
+    export: Export,
+}
+
+impl CaptureOutput {
+    pub fn new(dir: PathBuf, template: String) -> CaptureOutput {
+        CaptureOutput {
+            dir,
+            template,
+            export: Export::default(),
+        }
+    }
+
+    /// Scale captures up with `export`
+    pub fn set_export(&mut self, export: Export) {
+        self.export = export;
+    }
+
+    pub fn export(&self) -> Export {
+        self.export
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Creates the output directory if it doesn't exist and checks that it
+    /// can be written to
+    pub fn prepare(&self) -> Result<()> {
+        let error = |e: Error| {
+            Error::new(
+                e.kind(),
+                format!("Capture directory {}: {}", self.dir.display(), e),
+            )
+        };
+        fs::create_dir_all(&self.dir).map_err(error)?;
+        if fs::metadata(&self.dir)
+            .map_err(error)?
+            .permissions()
+            .readonly()
+        {
+            return Err(error(Error::new(
+                ErrorKind::PermissionDenied,
+                "directory is read-only",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Path of the capture `name` taken at `timestamp`, see `timestamp()`
+    pub fn path(&self, name: &str, timestamp: &str) -> Result<PathBuf> {
+        self.prepare()?;
+        let file_name = self
+            .template
+            .replace("{name}", name)
+            .replace("{timestamp}", timestamp);
+        Ok(self.dir.join(file_name))
+    }
+}
+
+impl Default for CaptureOutput {
+    fn default() -> CaptureOutput {
+        CaptureOutput::new(PathBuf::from("."), DEFAULT_TEMPLATE.to_string())
+    }
+}
+
+/// Local time with milliseconds, for naming captures
+pub fn timestamp() -> String {
+    chrono::Local::now().format("%Y%m%d-%H%M%S-%3f").to_string()
+}
+
+/// Frames waiting to be written before the game is held up
+const FRAME_QUEUE_LEN: usize = 32;
+
+/// Rate of the dumped sequence, the game paces its frames in 20 ms slices
+pub const FRAME_DUMP_RATE: u32 = 50;
+
+struct Frame {
+    number: u32,
+    repeat: u32,
+    width: usize,
+    height: usize,
+    palette: Palette,
+    pixels: Vec<u8>,
+}
+
+/// Writes every displayed frame as numbered PNG files, at a constant rate of
+/// `FRAME_DUMP_RATE` files per second. Frames are encoded and written on a
+/// separate thread so the game isn't slowed down.
+pub struct FrameDump {
+    sender: Option<SyncSender<Frame>>,
+    writer: Option<JoinHandle<()>>,
+    count: u32,
+    limit: Option<u32>,
+}
+
+impl FrameDump {
+    /// Starts dumping frames to `dir`, stopping after `limit` frames if given
+    pub fn start(dir: PathBuf, limit: Option<u32>, export: Export) -> Result<FrameDump> {
+        CaptureOutput::new(dir.clone(), String::new()).prepare()?;
+        let (sender, receiver) = mpsc::sync_channel::<Frame>(FRAME_QUEUE_LEN);
+        let writer = thread::spawn(move || {
+            let frame_path = |number: u32| dir.join(format!("frame_{:06}.png", number));
+            for frame in receiver {
+                let path = frame_path(frame.number);
+                let size = (frame.width, frame.height);
+                let mut result = export.save(&path, size, &frame.palette, &frame.pixels);
+                // Frames shown for several slices are repeated
+                for number in frame.number + 1..frame.number + frame.repeat {
+                    if result.is_err() {
+                        break;
+                    }
+                    result = fs::copy(&path, frame_path(number)).map(|_| ());
+                }
+                if let Err(e) = result {
+                    error!("Could not save {}: {}", path.display(), e);
+                }
+            }
+        });
+        Ok(FrameDump {
+            sender: Some(sender),
+            writer: Some(writer),
+            count: 0,
+            limit,
+        })
+    }
+
+    /// Queues a `width` x `height` frame which is displayed for `slices`
+    /// 20 ms slices
+    pub fn push(
+        &mut self,
+        (width, height): (usize, usize),
+        palette: &Palette,
+        pixels: &[u8],
+        slices: u32,
+    ) {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        let mut repeat = slices.max(1);
+        if let Some(limit) = self.limit {
+            repeat = repeat.min(limit - self.count);
+        }
+        let frame = Frame {
+            number: self.count + 1,
+            repeat,
+            width,
+            height,
+            palette: *palette,
+            pixels: pixels.to_vec(),
+        };
+        self.count += repeat;
+        if sender.send(frame).is_err() {
+            error!("Frame dump writer stopped, no longer dumping frames");
+            self.sender = None;
+        } else if Some(self.count) == self.limit {
+            info!(
+                "Dumped {} frames ({} s), stopping",
+                self.count,
+                self.count / FRAME_DUMP_RATE
+            );
+            self.sender = None;
+        }
+    }
+
+    /// Waits for the queued frames to be written
+    pub fn finish(&mut self) {
+        self.sender = None;
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("Frame dump writer panicked");
+            }
+        }
+    }
+}
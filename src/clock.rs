@@ -0,0 +1,68 @@
+use std::{hint, thread, time};
+
+/// How long before the deadline `sleep_precise` stops sleeping and spins
+const SPIN_MS: u64 = 2;
+
+/// Time source used for frame pacing
+pub enum Clock {
+    /// Wall clock time, sleeping blocks the calling thread
+    Real(time::Instant),
+    /// Manually driven time in milliseconds, sleeping only advances the counter
+    Virtual(u64),
+}
+
+impl Clock {
+    pub fn real() -> Clock {
+        Clock::Real(time::Instant::now())
+    }
+
+    pub fn virtual_clock() -> Clock {
+        Clock::Virtual(0)
+    }
+
+    /// Milliseconds elapsed since the clock was created
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            Clock::Real(start) => (start.elapsed().as_millis() & u64::MAX as u128) as u64,
+            Clock::Virtual(now) => *now,
+        }
+    }
+
+    pub fn sleep(&mut self, ms: u64) {
+        match self {
+            Clock::Real(_) => thread::sleep(time::Duration::from_millis(ms)),
+            Clock::Virtual(now) => *now += ms,
+        }
+    }
+
+    /// Sleeps for most of the time and busy-waits for the last milliseconds,
+    /// since sleeping tends to overshoot by the scheduler granularity. This
+    /// keeps a core busy while waiting.
+    pub fn sleep_precise(&mut self, ms: u64) {
+        match self {
+            Clock::Real(_) => {
+                let deadline = time::Instant::now() + time::Duration::from_millis(ms);
+                if ms > SPIN_MS {
+                    thread::sleep(time::Duration::from_millis(ms - SPIN_MS));
+                }
+                while time::Instant::now() < deadline {
+                    hint::spin_loop();
+                }
+            }
+            Clock::Virtual(now) => *now += ms,
+        }
+    }
+
+    /// Move a virtual clock forward, this is a no-op for the real clock
+    pub fn advance(&mut self, ms: u64) {
+        if let Clock::Virtual(now) = self {
+            *now += ms;
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::real()
+    }
+}
@@ -0,0 +1,89 @@
+//! Commands of the debug console, read from standard input while the
+//! scripts are stopped
+
+use std::io::{self, BufRead, Write};
+
+use crate::state::NUM_VARIABLES;
+
+pub const HELP: &str = "\
+p VAR        print a variable
+set VAR VAL  set a variable
+s            run one instruction and stop again
+c            continue running
+t            list the threads
+h            show this help
+Numbers are decimal, or hex with 0x";
+
+pub enum Command {
+    Print(usize),
+    Set(usize, i16),
+    Step,
+    Continue,
+    Threads,
+    Help,
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_variable(s: &str) -> Result<usize, String> {
+    match parse_number(s) {
+        Some(var) if (0..NUM_VARIABLES as i64).contains(&var) => Ok(var as usize),
+        _ => Err(format!(
+            "Invalid variable {}, expected 0 to 0x{:x}",
+            s,
+            NUM_VARIABLES - 1
+        )),
+    }
+}
+
+/// Values are given as signed or as unsigned 16 bit numbers
+fn parse_value(s: &str) -> Result<i16, String> {
+    match parse_number(s) {
+        Some(value) if (i16::MIN as i64..=u16::MAX as i64).contains(&value) => Ok(value as i16),
+        _ => Err(format!("Invalid value {}, expected a 16 bit number", s)),
+    }
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["p", var] => Ok(Command::Print(parse_variable(var)?)),
+        ["set", var, value] => Ok(Command::Set(parse_variable(var)?, parse_value(value)?)),
+        ["s"] => Ok(Command::Step),
+        ["c"] => Ok(Command::Continue),
+        ["t"] => Ok(Command::Threads),
+        ["h"] | ["help"] => Ok(Command::Help),
+        _ => Err(format!("Unknown command {}, type h for help", line.trim())),
+    }
+}
+
+/// Prompts until a valid command is typed. Returns `None` at the end of
+/// the input.
+pub fn read_command() -> Option<Command> {
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().ok()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse(&line) {
+            Ok(command) => return Some(command),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Recorded input demos. A demo starts with the part and the random seed of
+//! the run, followed by the game input of every frame.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::parts;
+use crate::player::{PlayerDirection, PlayerInput};
+
+const MAGIC: &[u8; 4] = b"AWDM";
+const VERSION: u8 = 1;
+
+const FLAG_BUTTON: u8 = 0x01;
+const FLAG_CODE: u8 = 0x02;
+
+fn write_input<W: Write>(writer: &mut W, input: &PlayerInput) -> Result<()> {
+    let mut flags = 0;
+    if input.button {
+        flags |= FLAG_BUTTON;
+    }
+    if input.code {
+        flags |= FLAG_CODE;
+    }
+    // The scripts only see backspace and uppercase letters
+    let last_char = if input.last_char.is_ascii() {
+        input.last_char as u8
+    } else {
+        0
+    };
+    writer.write_all(&[input.direction.bits(), flags, last_char])
+}
+
+fn read_input<R: Read>(reader: &mut R) -> Result<PlayerInput> {
+    let mut frame = [0; 3];
+    reader.read_exact(&mut frame)?;
+    let mut input = PlayerInput::new();
+    input.direction = PlayerDirection::from_bits_truncate(frame[0]);
+    input.button = frame[1] & FLAG_BUTTON != 0;
+    input.code = frame[1] & FLAG_CODE != 0;
+    input.last_char = frame[2] as char;
+    Ok(input)
+}
+
+pub struct Demo {
+    pub part_id: u16,
+    pub seed: i16,
+    inputs: VecDeque<PlayerInput>,
+}
+
+impl Demo {
+    pub fn load(path: &Path) -> Result<Demo> {
+        let data = fs::read(path)?;
+        let mut reader = Cursor::new(data.as_slice());
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a demo"));
+        }
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported demo version {}", version),
+            ));
+        }
+        let part_id = reader.read_u16::<BigEndian>()?;
+        if parts::part_number(part_id).is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid part 0x{:04x} in demo", part_id),
+            ));
+        }
+        let seed = reader.read_i16::<BigEndian>()?;
+        let mut inputs = VecDeque::new();
+        while (reader.position() as usize) < data.len() {
+            inputs.push_back(read_input(&mut reader)?);
+        }
+        Ok(Demo {
+            part_id,
+            seed,
+            inputs,
+        })
+    }
+
+    /// Input of the next frame, `None` at the end of the demo
+    pub fn next_input(&mut self) -> Option<PlayerInput> {
+        self.inputs.pop_front()
+    }
+}
+
+/// Writes the input of each frame to a demo file as it is played
+pub struct DemoRecorder {
+    writer: BufWriter<File>,
+    frames: u64,
+}
+
+impl DemoRecorder {
+    pub fn create(path: &Path, part_id: u16, seed: i16) -> Result<DemoRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u16::<BigEndian>(part_id)?;
+        writer.write_i16::<BigEndian>(seed)?;
+        Ok(DemoRecorder { writer, frames: 0 })
+    }
+
+    pub fn record(&mut self, input: &PlayerInput) -> Result<()> {
+        self.frames += 1;
+        write_input(&mut self.writer, input)
+    }
+
+    /// Number of frames recorded so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("aw-{}-{}.awd", name, std::process::id()))
+    }
+
+    fn input(direction: PlayerDirection, button: bool, code: bool, last_char: char) -> PlayerInput {
+        let mut input = PlayerInput::new();
+        input.direction = direction;
+        input.button = button;
+        input.code = code;
+        input.last_char = last_char;
+        input
+    }
+
+    #[test]
+    fn recorded_demos_load_back() {
+        let path = temp_path("demo-roundtrip");
+        let inputs = [
+            input(PlayerDirection::empty(), false, false, '\0'),
+            input(
+                PlayerDirection::LEFT | PlayerDirection::UP,
+                true,
+                false,
+                '\0',
+            ),
+            input(
+                PlayerDirection::RIGHT | PlayerDirection::DOWN,
+                false,
+                true,
+                'B',
+            ),
+            input(PlayerDirection::all(), true, true, '\x08'),
+        ];
+        let mut recorder = DemoRecorder::create(&path, parts::GAME_PART2, -1234).unwrap();
+        for input in &inputs {
+            recorder.record(input).unwrap();
+        }
+        // Not something the scripts can see, stored as no key
+        recorder
+            .record(&input(PlayerDirection::LEFT, false, false, 'é'))
+            .unwrap();
+        assert_eq!(recorder.frames(), 5);
+        recorder.finish().unwrap();
+
+        let mut demo = Demo::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(demo.part_id, parts::GAME_PART2);
+        assert_eq!(demo.seed, -1234);
+        for expected in &inputs {
+            let actual = demo.next_input().unwrap();
+            assert_eq!(actual.direction.bits(), expected.direction.bits());
+            assert_eq!(actual.button, expected.button);
+            assert_eq!(actual.code, expected.code);
+            assert_eq!(actual.last_char, expected.last_char);
+        }
+        let last = demo.next_input().unwrap();
+        assert_eq!(last.direction.bits(), PlayerDirection::LEFT.bits());
+        assert_eq!(last.last_char, '\0');
+        assert!(demo.next_input().is_none());
+    }
+
+    #[test]
+    fn bad_versions_and_parts_are_rejected() {
+        let path = temp_path("demo-bad-header");
+        let bad_headers: [&[u8]; 3] = [
+            b"AWDM\x02\x3e\x81\x00\x00",
+            b"AWDM\x01\x12\x34\x00\x00",
+            b"AWSV\x01\x3e\x81\x00\x00",
+        ];
+        for header in &bad_headers {
+            fs::write(&path, header).unwrap();
+            let e = Demo::load(&path).err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
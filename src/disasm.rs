@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::opcode::Opcode;
+
+/// A decoded bytecode instruction
+pub struct Instruction {
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub operands: Vec<u8>,
+}
+
+impl Instruction {
+    /// Decodes the instruction at `offset`. Returns `None` for illegal
+    /// opcodes and for instructions running past the end of `code`.
+    pub fn decode(code: &[u8], offset: usize) -> Option<Instruction> {
+        let opcode = Opcode::try_decode(*code.get(offset)?)?;
+        let first_operand = code.get(offset + 1).copied().unwrap_or(0);
+        let len = opcode.operand_len(first_operand);
+        let operands = code.get(offset + 1..offset + 1 + len)?.to_vec();
+        Some(Instruction {
+            offset,
+            opcode,
+            operands,
+        })
+    }
+
+    /// Size in bytes including the opcode
+    pub fn size(&self) -> usize {
+        1 + self.operands.len()
+    }
+
+    pub fn next_offset(&self) -> usize {
+        self.offset + self.size()
+    }
+
+    fn byte(&self, index: usize) -> u8 {
+        self.operands[index]
+    }
+
+    fn word(&self, index: usize) -> u16 {
+        BigEndian::read_u16(&self.operands[index..])
+    }
+
+    /// Target of a jump or call within the bytecode segment
+    pub fn branch_target(&self) -> Option<usize> {
+        let target = match self.opcode {
+            Opcode::Call | Opcode::Jmp => self.word(0),
+            Opcode::Jnz => self.word(1),
+            Opcode::CondJmp => self.word(self.operands.len() - 2),
+            _ => return None,
+        };
+        Some(target as usize)
+    }
+
+    /// Thread id and entry point installed by `SetSetVect`
+    pub fn thread_entry(&self) -> Option<(u8, usize)> {
+        match self.opcode {
+            Opcode::SetSetVect => Some((self.byte(0), self.word(1) as usize)),
+            _ => None,
+        }
+    }
+
+    /// Whether execution may continue with the following instruction
+    pub fn falls_through(&self) -> bool {
+        !matches!(self.opcode, Opcode::Jmp | Opcode::Ret | Opcode::KillThread)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.opcode {
+            Opcode::MovConst => write!(f, "mov v{:02x}, {}", self.byte(0), self.word(1) as i16),
+            Opcode::Mov => write!(f, "mov v{:02x}, v{:02x}", self.byte(0), self.byte(1)),
+            Opcode::Add => write!(f, "add v{:02x}, v{:02x}", self.byte(0), self.byte(1)),
+            Opcode::AddConst => write!(f, "add v{:02x}, {}", self.byte(0), self.word(1) as i16),
+            Opcode::Call => write!(f, "call 0x{:04x}", self.word(0)),
+            Opcode::Ret => write!(f, "ret"),
+            Opcode::PauseThread => write!(f, "pause_thread"),
+            Opcode::Jmp => write!(f, "jmp 0x{:04x}", self.word(0)),
+            Opcode::SetSetVect => {
+                write!(f, "set_set_vect {}, 0x{:04x}", self.byte(0), self.word(1))
+            }
+            Opcode::Jnz => write!(f, "jnz v{:02x}, 0x{:04x}", self.byte(0), self.word(1)),
+            Opcode::CondJmp => {
+                let condition = self.byte(0);
+                let op = match condition & 7 {
+                    0 => "==",
+                    1 => "!=",
+                    2 => ">",
+                    3 => ">=",
+                    4 => "<",
+                    5 => "<=",
+                    _ => "??",
+                };
+                let operand = if condition & 0x80 > 0 {
+                    format!("v{:02x}", self.byte(2))
+                } else if condition & 0x40 > 0 {
+                    format!("{}", self.word(2) as i16)
+                } else {
+                    format!("{}", self.byte(2))
+                };
+                write!(
+                    f,
+                    "jmp_if v{:02x} {} {}, 0x{:04x}",
+                    self.byte(1),
+                    op,
+                    operand,
+                    self.branch_target().unwrap_or(0)
+                )
+            }
+            Opcode::SetPalette => write!(f, "set_palette 0x{:04x}", self.word(0)),
+            Opcode::ResetThread => write!(
+                f,
+                "reset_thread {}, {}, {}",
+                self.byte(0),
+                self.byte(1),
+                self.byte(2)
+            ),
+            Opcode::SelectVideoPage => write!(f, "select_video_page 0x{:02x}", self.byte(0)),
+            Opcode::FillVideoPage => write!(
+                f,
+                "fill_video_page 0x{:02x}, {}",
+                self.byte(0),
+                self.byte(1)
+            ),
+            Opcode::CopyVideoPage => write!(
+                f,
+                "copy_video_page 0x{:02x}, 0x{:02x}",
+                self.byte(0),
+                self.byte(1)
+            ),
+            Opcode::BlitFrameBuffer => write!(f, "blit_frame_buffer 0x{:02x}", self.byte(0)),
+            Opcode::KillThread => write!(f, "kill_thread"),
+            Opcode::DrawString => write!(
+                f,
+                "draw_string 0x{:04x}, {}, {}, {}",
+                self.word(0),
+                self.byte(2),
+                self.byte(3),
+                self.byte(4)
+            ),
+            Opcode::Sub => write!(f, "sub v{:02x}, v{:02x}", self.byte(0), self.byte(1)),
+            Opcode::And => write!(f, "and v{:02x}, 0x{:04x}", self.byte(0), self.word(1)),
+            Opcode::Or => write!(f, "or v{:02x}, 0x{:04x}", self.byte(0), self.word(1)),
+            Opcode::Shl => write!(f, "shl v{:02x}, {}", self.byte(0), self.word(1)),
+            Opcode::Shr => write!(f, "shr v{:02x}, {}", self.byte(0), self.word(1)),
+            Opcode::PlaySound => write!(
+                f,
+                "play_sound 0x{:04x}, {}, {}, {}",
+                self.word(0),
+                self.byte(2),
+                self.byte(3),
+                self.byte(4)
+            ),
+            Opcode::UpdateMemList => write!(f, "update_memlist 0x{:04x}", self.word(0)),
+            Opcode::PlayMusic => write!(
+                f,
+                "play_music 0x{:04x}, {}, {}",
+                self.word(0),
+                self.word(2),
+                self.byte(4)
+            ),
+            Opcode::DrawPolySprite(val) => {
+                let operands: Vec<String> =
+                    self.operands.iter().map(|b| format!("{:02x}", b)).collect();
+                write!(f, "draw_poly_sprite 0x{:02x}, {}", val, operands.join(" "))
+            }
+            Opcode::DrawPolyBackground(val) => {
+                let offset = ((val as u16 & 0x7f) << 8 | self.byte(0) as u16) as usize * 2;
+                write!(
+                    f,
+                    "draw_poly_background 0x{:04x}, {}, {}",
+                    offset,
+                    self.byte(1),
+                    self.byte(2)
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    Call,
+}
+
+pub struct BasicBlock {
+    pub start: usize,
+    pub instructions: Vec<Instruction>,
+    pub successors: Vec<(usize, EdgeKind)>,
+}
+
+/// Control-flow graph of a part's bytecode, reachable from the start of
+/// thread 0 and from the thread entry points installed by `SetSetVect`
+pub struct Cfg {
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    /// Thread id and entry offset, thread 0 starts at offset 0
+    pub thread_entries: Vec<(u8, usize)>,
+}
+
+impl Cfg {
+    pub fn build(code: &[u8]) -> Cfg {
+        let mut thread_entries = vec![(0, 0)];
+        let mut leaders = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        let mut work = vec![0];
+        leaders.insert(0);
+
+        while let Some(offset) = work.pop() {
+            if !visited.insert(offset) {
+                continue;
+            }
+            let instruction = match Instruction::decode(code, offset) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            if let Some((thread_id, entry)) = instruction.thread_entry() {
+                if !thread_entries.contains(&(thread_id, entry)) {
+                    thread_entries.push((thread_id, entry));
+                }
+                leaders.insert(entry);
+                work.push(entry);
+            }
+            if let Some(target) = instruction.branch_target() {
+                leaders.insert(target);
+                leaders.insert(instruction.next_offset());
+                work.push(target);
+            }
+            if instruction.falls_through() {
+                work.push(instruction.next_offset());
+            } else {
+                leaders.insert(instruction.next_offset());
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.iter().filter(|l| visited.contains(l)) {
+            let mut instructions = Vec::new();
+            let mut successors = Vec::new();
+            let mut offset = start;
+            while let Some(instruction) = Instruction::decode(code, offset) {
+                offset = instruction.next_offset();
+                let falls_through = instruction.falls_through();
+                if let Some(target) = instruction.branch_target() {
+                    let kind = match instruction.opcode {
+                        Opcode::Call => EdgeKind::Call,
+                        _ => EdgeKind::Jump,
+                    };
+                    successors.push((target, kind));
+                }
+                let ends_block = instruction.branch_target().is_some() || !falls_through;
+                instructions.push(instruction);
+                if ends_block || leaders.contains(&offset) {
+                    if falls_through {
+                        successors.push((offset, EdgeKind::Fallthrough));
+                    }
+                    break;
+                }
+            }
+            blocks.insert(
+                start,
+                BasicBlock {
+                    start,
+                    instructions,
+                    successors,
+                },
+            );
+        }
+
+        Cfg {
+            blocks,
+            thread_entries,
+        }
+    }
+}
@@ -1,36 +1,338 @@
+use log::info;
+
+use crate::overlay::Overlay;
 use crate::parts;
+use crate::player::{PlayerDirection, PlayerInput};
+use crate::resource::ResourceError;
+use crate::state::SaveState;
+use crate::system::System;
 use crate::vm::VirtualMachine;
 
-pub struct Engine {
-    vm: VirtualMachine,
-}
-
-impl Engine {
-    pub fn new(mut vm: VirtualMachine, part_num: u8) -> Engine {
-        let part = match part_num {
-            1 => parts::GAME_PART1,
-            2 => parts::GAME_PART2,
-            3 => parts::GAME_PART3,
-            4 => parts::GAME_PART4,
-            5 => parts::GAME_PART5,
-            6 => parts::GAME_PART6,
-            7 => parts::GAME_PART7,
-            8 => parts::GAME_PART8,
-            9 => parts::GAME_PART9,
-            10 => parts::GAME_PART10,
-            n => panic!("Unknown part number: {}", n),
+const MENU_FRAME_MS: u64 = 20;
+const MENU_LEFT: u16 = 96;
+const MENU_TOP: u16 = 76;
+const MENU_WIDTH: u16 = 128;
+const MENU_ROW_HEIGHT: u16 = 12;
+const QUIT_PROMPT: &str = "Quit? (Y/N)";
+const QUIT_PROMPT_LEFT: u16 = 108;
+const QUIT_PROMPT_TOP: u16 = 90;
+const PAUSED_TEXT: &str = "PAUSED";
+const PAUSED_LEFT: u16 = 128;
+const PAUSED_TOP: u16 = 90;
+
+#[derive(Clone, Copy)]
+enum MenuItem {
+    Resume,
+    RestartPart,
+    Quit,
+}
+
+const MENU_ITEMS: [MenuItem; 3] = [MenuItem::Resume, MenuItem::RestartPart, MenuItem::Quit];
+
+impl MenuItem {
+    fn label(self) -> &'static str {
+        match self {
+            MenuItem::Resume => "Resume",
+            MenuItem::RestartPart => "Restart part",
+            MenuItem::Quit => "Quit",
+        }
+    }
+}
+
+/// Menu opened with Escape. The scripts are not run while it is open.
+struct Menu {
+    selected: usize,
+}
+
+impl Menu {
+    fn new() -> Menu {
+        Menu { selected: 0 }
+    }
+
+    /// Menu item at a position in 320x200 coordinates
+    fn item_at(&self, (x, y): (u16, u16)) -> Option<usize> {
+        if !(MENU_LEFT..MENU_LEFT + MENU_WIDTH).contains(&x) || y < MENU_TOP + 6 {
+            return None;
+        }
+        let index = ((y - MENU_TOP - 6) / MENU_ROW_HEIGHT) as usize;
+        if index < MENU_ITEMS.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn overlay(&self, background: u8, foreground: u8) -> Overlay {
+        let mut overlay = Overlay::new();
+        let height = MENU_ITEMS.len() as u16 * MENU_ROW_HEIGHT + 12;
+        overlay.rect(MENU_LEFT, MENU_TOP, MENU_WIDTH, height, background);
+        for (i, item) in MENU_ITEMS.iter().enumerate() {
+            let marker = if i == self.selected { '>' } else { ' ' };
+            let text = format!("{} {}", marker, item.label());
+            let y = MENU_TOP + 8 + i as u16 * MENU_ROW_HEIGHT;
+            overlay.text(MENU_LEFT + 8, y, foreground, &text);
+        }
+        overlay
+    }
+}
+
+/// What happened in one step of the engine
+#[derive(Clone, Copy, Debug)]
+pub struct FrameInfo {
+    /// Frames displayed so far
+    pub frame_number: u64,
+    /// Part being played
+    pub part_id: u16,
+    /// Whether the step displayed a new frame, steps spent in the menu or
+    /// in scripts that didn't draw don't
+    pub blitted: bool,
+}
+
+/// Iterator stepping the engine, see `Engine::frames`
+pub struct Frames<'a, S: System> {
+    engine: &'a mut Engine<S>,
+}
+
+impl<'a, S: System> Iterator for Frames<'a, S> {
+    type Item = FrameInfo;
+
+    fn next(&mut self) -> Option<FrameInfo> {
+        self.engine.step()
+    }
+}
+
+pub struct Engine<S: System> {
+    vm: VirtualMachine<S>,
+    menu: Option<Menu>,
+    last_input: PlayerInput,
+    confirm_quit: bool,
+    quit_prompt: bool,
+    paused: bool,
+    quit: bool,
+    error: Option<ResourceError>,
+}
+
+impl<S: System> Engine<S> {
+    pub fn new(mut vm: VirtualMachine<S>, part_num: u8) -> Result<Engine<S>, ResourceError> {
+        let part = parts::part_id(part_num).ok_or(ResourceError::UnknownPartNumber(part_num))?;
+        vm.init_for_part(part)?;
+        Ok(Engine {
+            vm,
+            menu: None,
+            last_input: PlayerInput::new(),
+            confirm_quit: false,
+            quit_prompt: false,
+            paused: false,
+            quit: false,
+            error: None,
+        })
+    }
+
+    /// Resume from a save state instead of starting a part
+    pub fn from_state(
+        mut vm: VirtualMachine<S>,
+        state: &SaveState,
+    ) -> Result<Engine<S>, ResourceError> {
+        vm.load_state(state)?;
+        Ok(Engine {
+            vm,
+            menu: None,
+            last_input: PlayerInput::new(),
+            confirm_quit: false,
+            quit_prompt: false,
+            paused: false,
+            quit: false,
+            error: None,
+        })
+    }
+
+    /// Ask for confirmation before quitting from the menu. Closing the
+    /// window still quits right away.
+    pub fn set_confirm_quit(&mut self, confirm_quit: bool) {
+        self.confirm_quit = confirm_quit;
+    }
+
+    /// Runs until the game is quit, or game data fails to load
+    pub fn run(&mut self) -> Result<(), ResourceError> {
+        while self.step().is_some() {}
+        self.finish();
+        self.take_error().map_or(Ok(()), Err)
+    }
+
+    /// Runs one iteration of the main loop, which runs the scripts for one
+    /// frame or updates the menu. Returns `None` once the game is quit.
+    pub fn step(&mut self) -> Option<FrameInfo> {
+        if self.quit {
+            return None;
+        }
+        let frame_number = self.vm.frame_number();
+        let input = self.vm.poll_input();
+        if input.quit {
+            self.quit = true;
+        } else if self.quit_prompt {
+            self.quit = self.update_quit_prompt(&input);
+        } else if self.menu.is_some() {
+            if let Some(MenuItem::Quit) = self.update_menu(&input) {
+                if self.confirm_quit {
+                    self.quit_prompt = true;
+                } else {
+                    self.quit = true;
+                }
+            }
+        } else if input.menu {
+            self.menu = Some(Menu::new());
+        } else {
+            if input.state_slot != self.last_input.state_slot {
+                info!("Save slot {}", input.state_slot);
+            }
+            if input.save {
+                self.vm.save_slot(input.state_slot);
+            }
+            if input.load {
+                self.vm.load_slot(input.state_slot);
+            }
+            if let Some(part) = input.jump_to_part {
+                self.vm.request_part(part);
+            }
+            // Letters are typed on the code screen, P included
+            if input.pause && self.vm.current_part() != parts::GAME_PART10 {
+                self.paused = !self.paused;
+                self.vm.set_paused(self.paused);
+            }
+            if self.paused && input.step {
+                self.vm.step_frame(&input);
+            } else if self.paused {
+                self.vm.update_player_input(&input);
+                self.show_paused();
+            } else {
+                self.vm.check_thread_requests();
+                self.vm.update_player_input(&input);
+                self.vm.host_frame();
+            }
+        }
+        if let Some(e) = self.vm.take_error() {
+            self.error = Some(e);
+            self.quit = true;
+        }
+        self.last_input = input;
+        if self.quit {
+            return None;
+        }
+        Some(FrameInfo {
+            frame_number: self.vm.frame_number(),
+            part_id: self.vm.current_part(),
+            blitted: self.vm.frame_number() != frame_number,
+        })
+    }
+
+    /// Steps the engine as an iterator, for example
+    /// `engine.frames().take(600)`. Call `finish` when done.
+    pub fn frames(&mut self) -> Frames<'_, S> {
+        Frames { engine: self }
+    }
+
+    /// Flushes the output written while running, like the checksum log and
+    /// dumped frames, and stops the audio
+    pub fn finish(&mut self) {
+        self.vm.finish_output();
+        self.vm.stop_audio();
+    }
+
+    /// Error from loading game data that ended the game
+    pub fn take_error(&mut self) -> Option<ResourceError> {
+        self.error.take()
+    }
+
+    pub fn vm(&self) -> &VirtualMachine<S> {
+        &self.vm
+    }
+
+    /// Handles navigation while the menu is open and redraws it. Returns the
+    /// confirmed item, if any.
+    fn update_menu(&mut self, input: &PlayerInput) -> Option<MenuItem> {
+        let pressed = |d: PlayerDirection| {
+            input.direction.contains(d) && !self.last_input.direction.contains(d)
         };
-        vm.init_for_part(part);
-        Engine { vm }
+        let up = pressed(PlayerDirection::UP);
+        let down = pressed(PlayerDirection::DOWN);
+        let mut confirm = input.button && !self.last_input.button;
+
+        let menu = self.menu.as_mut()?;
+        if input.menu {
+            self.menu = None;
+            return Some(MenuItem::Resume);
+        }
+        if input.cursor != self.last_input.cursor || input.click {
+            if let Some(index) = input.cursor.and_then(|cursor| menu.item_at(cursor)) {
+                menu.selected = index;
+                confirm |= input.click;
+            }
+        }
+        if up {
+            menu.selected = (menu.selected + MENU_ITEMS.len() - 1) % MENU_ITEMS.len();
+        }
+        if down {
+            menu.selected = (menu.selected + 1) % MENU_ITEMS.len();
+        }
+
+        if confirm {
+            let item = MENU_ITEMS[menu.selected];
+            self.menu = None;
+            if let MenuItem::RestartPart = item {
+                if let Err(e) = self.vm.restart_part() {
+                    self.error = Some(e);
+                    self.quit = true;
+                }
+            }
+            return Some(item);
+        }
+
+        let (background, foreground) = self.vm.overlay_colors();
+        let overlay = menu.overlay(background, foreground);
+        self.vm.redisplay(&overlay);
+        self.vm.sleep(MENU_FRAME_MS);
+        None
     }
 
-    pub fn run(&mut self) {
-        loop {
-            self.vm.check_thread_requests();
-            if !self.vm.update_player_input() {
-                return;
+    /// Shows the pause banner over the frame, waiting one menu frame
+    fn show_paused(&mut self) {
+        let (background, foreground) = self.vm.overlay_colors();
+        let mut overlay = Overlay::new();
+        let width = PAUSED_TEXT.len() as u16 * 8 + 16;
+        overlay.rect(PAUSED_LEFT, PAUSED_TOP, width, 20, background);
+        overlay.text(PAUSED_LEFT + 8, PAUSED_TOP + 6, foreground, PAUSED_TEXT);
+        self.vm.redisplay(&overlay);
+        self.vm.sleep(MENU_FRAME_MS);
+    }
+
+    /// Shows the quit prompt over the frame until it is answered. Returns
+    /// whether quitting was confirmed.
+    fn update_quit_prompt(&mut self, input: &PlayerInput) -> bool {
+        match input.last_char {
+            'Y' => return true,
+            'N' => {
+                self.quit_prompt = false;
+                return false;
             }
-            self.vm.host_frame();
+            _ => {}
         }
+        if input.menu {
+            self.quit_prompt = false;
+            return false;
+        }
+
+        let (background, foreground) = self.vm.overlay_colors();
+        let mut overlay = Overlay::new();
+        let width = QUIT_PROMPT.len() as u16 * 8 + 16;
+        overlay.rect(QUIT_PROMPT_LEFT, QUIT_PROMPT_TOP, width, 20, background);
+        overlay.text(
+            QUIT_PROMPT_LEFT + 8,
+            QUIT_PROMPT_TOP + 6,
+            foreground,
+            QUIT_PROMPT,
+        );
+        self.vm.redisplay(&overlay);
+        self.vm.sleep(MENU_FRAME_MS);
+        false
     }
 }
@@ -0,0 +1,344 @@
+//! Decoder for the deflate format (RFC 1951) used inside zlib streams, for
+//! reading PNG images. Huffman codes are decoded one bit at a time with the
+//! canonical code counts, which is slow but small.
+
+use std::io::{Error, ErrorKind, Result};
+
+const MAX_BITS: usize = 15;
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which the lengths of the code length code are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Corrupt deflate data: {}", message),
+    )
+}
+
+/// Reads bits starting from the least significant bit of each byte
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    num_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            bits: 0,
+            num_bits: 0,
+        }
+    }
+
+    fn read(&mut self, count: u32) -> Result<u32> {
+        while self.num_bits < count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| invalid("unexpected end of data"))?;
+            self.pos += 1;
+            self.bits |= (byte as u32) << self.num_bits;
+            self.num_bits += 8;
+        }
+        let value = self.bits & ((1 << count) - 1);
+        self.bits >>= count;
+        self.num_bits -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits left in the current byte. At most 7 bits are ever
+    /// buffered, so they all belong to that byte.
+    fn align(&mut self) {
+        self.bits = 0;
+        self.num_bits = 0;
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + count)
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        self.pos += count;
+        Ok(bytes)
+    }
+}
+
+/// Canonical Huffman code, given by the number of codes of each length and
+/// the symbols sorted by code
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        // First code of each length and the index of its symbol
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= reader.read(1)? as usize;
+            let count = count as usize;
+            if code < first + count {
+                return self
+                    .symbols
+                    .get(index + code - first)
+                    .copied()
+                    .ok_or_else(|| invalid("invalid code"));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid code"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let num_literals = reader.read(5)? as usize + 257;
+    let num_distances = reader.read(5)? as usize + 1;
+    let num_code_lengths = reader.read(4)? as usize + 4;
+    let mut code_lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..num_code_lengths] {
+        code_lengths[i] = reader.read(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![0; num_literals + num_distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *i
+                    .checked_sub(1)
+                    .and_then(|p| lengths.get(p))
+                    .ok_or_else(|| invalid("repeat without a length"))?;
+                (previous, 3 + reader.read(2)? as usize)
+            }
+            17 => (0, 3 + reader.read(3)? as usize),
+            _ => (0, 11 + reader.read(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(invalid("too many code lengths"));
+        }
+        lengths[i..i + repeat].fill(length);
+        i += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(invalid("no end of block code"));
+    }
+    let (literals, distances) = lengths.split_at(num_literals);
+    Ok((Huffman::new(literals), Huffman::new(distances)))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    max_size: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol < END_OF_BLOCK {
+            if out.len() == max_size {
+                return Err(invalid("more data than expected"));
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+        let index = (symbol - END_OF_BLOCK - 1) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err(invalid("invalid length"));
+        }
+        let length =
+            LENGTH_BASE[index] as usize + reader.read(LENGTH_EXTRA[index] as u32)? as usize;
+        let index = distances.decode(reader)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(invalid("invalid distance"));
+        }
+        let distance =
+            DISTANCE_BASE[index] as usize + reader.read(DISTANCE_EXTRA[index] as u32)? as usize;
+        if distance > out.len() {
+            return Err(invalid("distance before the start"));
+        }
+        if out.len() + length > max_size {
+            return Err(invalid("more data than expected"));
+        }
+        // Copies may overlap the bytes they produce
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+/// Decompresses a raw deflate stream, ignoring any data after its last block.
+/// Streams that inflate to more than `max_size` bytes are an error, so that a
+/// small corrupt file can't take all the memory.
+pub fn inflate(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    let mut reader = BitReader::new(data);
+    let mut out = Vec::new();
+    loop {
+        let last = reader.read(1)? == 1;
+        match reader.read(2)? {
+            0 => {
+                reader.align();
+                let header = reader.read_bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(invalid("stored block length mismatch"));
+                }
+                if out.len() + len as usize > max_size {
+                    return Err(invalid("more data than expected"));
+                }
+                out.extend_from_slice(reader.read_bytes(len as usize)?);
+            }
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut reader, &mut out, max_size, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut out, max_size, &literals, &distances)?;
+            }
+            _ => return Err(invalid("invalid block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Decompresses a zlib stream of at most `max_size` bytes, without checking
+/// its Adler-32 checksum
+pub fn zlib_decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    if data.len() < 2 {
+        return Err(invalid("missing zlib header"));
+    }
+    let (cmf, flags) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || !(cmf as u16 * 256 + flags as u16).is_multiple_of(31) {
+        return Err(invalid("invalid zlib header"));
+    }
+    if flags & 0x20 != 0 {
+        return Err(invalid("preset dictionaries are not supported"));
+    }
+    inflate(&data[2..], max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// 100000 zero bytes compressed to 120
+    fn zeros() -> Vec<u8> {
+        hex(&format!(
+            "78daedc13101000000c2a0f54f6d0d0fa0{}80570386af0001",
+            "00".repeat(96)
+        ))
+    }
+
+    #[test]
+    fn inflates_stored_fixed_and_dynamic_blocks() {
+        let stored = [0x78, 0x01, 0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'w', b'!'];
+        assert_eq!(zlib_decompress(&stored, 3).unwrap(), b"aw!");
+        let fixed = hex("78da73cccb2fc9482d5208cf2fca490100227e04fa");
+        assert_eq!(zlib_decompress(&fixed, 13).unwrap(), b"Another World");
+        let dynamic =
+            hex("78da2589c10d003010826605f7dfa1d57b18090041c094fe8f508b4cbad978b2353cb99d16df");
+        assert_eq!(
+            zlib_decompress(&dynamic, 60).unwrap(),
+            &b"aaacabaaabcaacaaababcaaabbbababaaacaabcaabcbaabaababaabbbaca"[..]
+        );
+        assert_eq!(
+            zlib_decompress(&zeros(), 100_000).unwrap(),
+            vec![0; 100_000]
+        );
+    }
+
+    #[test]
+    fn output_is_bounded() {
+        let stored = [0x78, 0x01, 0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'w', b'!'];
+        assert!(zlib_decompress(&stored, 2).is_err());
+        let fixed = hex("78da73cccb2fc9482d5208cf2fca490100227e04fa");
+        assert!(zlib_decompress(&fixed, 12).is_err());
+        let error = zlib_decompress(&zeros(), 1000).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupt_streams_are_errors() {
+        let fixed = hex("78da73cccb2fc9482d5208cf2fca490100227e04fa");
+        for len in 0..fixed.len() - 4 {
+            assert!(zlib_decompress(&fixed[..len], 13).is_err(), "{}", len);
+        }
+        // Compression method 7 and a bad header checksum
+        assert!(zlib_decompress(&[0x77, 0xda, 0x03, 0x00], 0).is_err());
+        assert!(zlib_decompress(&[0x78, 0xdb, 0x03, 0x00], 0).is_err());
+        // Stored block with a wrong length complement, and block type 3
+        assert!(inflate(&[0x01, 0x01, 0x00, 0xff, 0xff, 0x00], 1).is_err());
+        assert!(inflate(&[0x07], 1).is_err());
+    }
+}
@@ -1,13 +1,29 @@
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod, Scancode};
+use sdl2::mouse::MouseButton;
 use sdl2::EventPump;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-
 
+use crate::parts;
 use crate::player::{PlayerDirection, PlayerInput};
 
+fn to_game_coordinates(
+    (left, top, width, height): (i32, i32, i32, i32),
+    x: i32,
+    y: i32,
+) -> Option<(u16, u16)> {
+    let (x, y) = (x - left, y - top);
+    if x < 0 || y < 0 || x >= width || y >= height {
+        return None;
+    }
+    Some(((x * 320 / width) as u16, (y * 200 / height) as u16))
+}
+
 pub struct UserInput {
     event_pump: EventPump,
     player_input: PlayerInput,
+    viewport: (i32, i32, i32, i32),
+    mirror: bool,
+    mirror_input: bool,
 }
 
 impl UserInput {
@@ -15,18 +31,89 @@ impl UserInput {
         Self {
             event_pump,
             player_input: PlayerInput::new(),
+            viewport: (0, 0, 320, 200),
+            mirror: false,
+            mirror_input: false,
         }
     }
 
+    /// Where the game is drawn in the window, see `SDLSys::window_viewport`
+    pub fn set_viewport(&mut self, (x, y, width, height): (i32, i32, u32, u32)) {
+        self.viewport = (x, y, width as i32, height as i32);
+    }
+
+    /// The display is mirrored, so mouse positions are flipped to match
+    pub fn set_mirror(&mut self, mirror: bool) {
+        self.mirror = mirror;
+    }
+
+    /// Swap left and right
+    pub fn set_mirror_input(&mut self, mirror_input: bool) {
+        self.mirror_input = mirror_input;
+    }
+
     pub fn process_events(&mut self) -> PlayerInput {
         let mut last_char = '\0';
+        let viewport = self.viewport;
+        let mirror = self.mirror;
+        let cursor_at = |x, y| {
+            to_game_coordinates(viewport, x, y)
+                .map(|(x, y)| if mirror { (319 - x, y) } else { (x, y) })
+        };
         for event in self.event_pump.poll_iter() {
             match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
+                Event::Quit { .. } => self.player_input.quit = true,
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => self.player_input.resized = true,
+                Event::MouseMotion { x, y, .. } => self.player_input.cursor = cursor_at(x, y),
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } => {
+                    self.player_input.cursor = cursor_at(x, y);
+                    self.player_input.click = self.player_input.cursor.is_some();
+                }
+                // Movement keys are positional, WASD on QWERTY and ZQSD on
+                // AZERTY
+                Event::KeyDown {
+                    scancode: Some(Scancode::A),
+                    ..
+                } => self.player_input.direction |= PlayerDirection::LEFT,
+                Event::KeyDown {
+                    scancode: Some(Scancode::D),
+                    ..
+                } => self.player_input.direction |= PlayerDirection::RIGHT,
+                Event::KeyDown {
+                    scancode: Some(Scancode::W),
+                    ..
+                } => self.player_input.direction |= PlayerDirection::UP,
+                Event::KeyDown {
+                    scancode: Some(Scancode::S),
+                    ..
+                } => self.player_input.direction |= PlayerDirection::DOWN,
+                Event::KeyDown {
+                    keycode: Some(Keycode::M),
+                    keymod,
                     ..
-                } => self.player_input.quit = true,
+                } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    self.player_input.toggle_mute = true
+                }
+                // Held down, these would toggle the pause or step frames at
+                // the key repeat rate
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat,
+                    ..
+                } => self.player_input.pause |= !repeat,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Period),
+                    repeat,
+                    ..
+                } => self.player_input.step |= !repeat,
                 Event::KeyDown { keycode, .. } => match keycode.unwrap() {
                     Keycode::Left => self.player_input.direction |= PlayerDirection::LEFT,
                     Keycode::Right => self.player_input.direction |= PlayerDirection::RIGHT,
@@ -36,73 +123,126 @@ impl UserInput {
                         self.player_input.button = true
                     }
                     Keycode::Backspace => last_char = '\x08',
-                    Keycode::A => {
-                        self.player_input.direction |= PlayerDirection::LEFT;
-                        last_char = 'A';
-                    }
-                    Keycode::B => last_char = 'B',
-                    Keycode::C => {
-                        self.player_input.code = true;
-                        last_char = 'C';
-                    }
-                    Keycode::D => {
-                        self.player_input.direction |= PlayerDirection::RIGHT;
-                        last_char = 'D';
+                    Keycode::Escape => self.player_input.menu = true,
+                    Keycode::F1 => self.player_input.toggle_scanlines = true,
+                    Keycode::F2 => self.player_input.toggle_crt = true,
+                    Keycode::F3 => self.player_input.toggle_vu_meter = true,
+                    Keycode::F4 => self.player_input.restart_part = true,
+                    Keycode::F5 => self.player_input.save = true,
+                    Keycode::F6 => self.player_input.toggle_memory_usage = true,
+                    Keycode::F7 => self.player_input.toggle_page_grid = true,
+                    Keycode::F8 => self.player_input.next_scale = true,
+                    Keycode::F9 => self.player_input.load = true,
+                    Keycode::F10 => self.player_input.next_language = true,
+                    Keycode::F11 => self.player_input.toggle_palette = true,
+                    Keycode::F12 => self.player_input.screenshot = true,
+                    Keycode::PrintScreen => self.player_input.dump_pages = true,
+                    Keycode::LeftBracket => self.player_input.music_slower = true,
+                    Keycode::RightBracket => self.player_input.music_faster = true,
+                    Keycode::PageUp => self.player_input.music_previous_order = true,
+                    Keycode::PageDown => self.player_input.music_next_order = true,
+                    Keycode::Backquote => self.player_input.debug_break = true,
+                    Keycode::Tab => self.player_input.fast_forward = true,
+                    key @ (Keycode::Kp1
+                    | Keycode::Kp2
+                    | Keycode::Kp3
+                    | Keycode::Kp4
+                    | Keycode::Kp5
+                    | Keycode::Kp6
+                    | Keycode::Kp7
+                    | Keycode::Kp8
+                    | Keycode::Kp9) => {
+                        let part_num = key as i32 - Keycode::Kp1 as i32 + 1;
+                        self.player_input.jump_to_part = parts::part_id(part_num as u8);
                     }
-                    Keycode::E => last_char = 'E',
-                    Keycode::F => last_char = 'F',
-                    Keycode::G => last_char = 'G',
-                    Keycode::H => last_char = 'H',
-                    Keycode::I => last_char = 'I',
-                    Keycode::J => last_char = 'J',
-                    Keycode::K => last_char = 'K',
-                    Keycode::L => last_char = 'L',
-                    Keycode::M => last_char = 'M',
-                    Keycode::N => last_char = 'N',
-                    Keycode::O => last_char = 'O',
-                    Keycode::P => last_char = 'P',
-                    Keycode::Q => last_char = 'Q',
-                    Keycode::R => last_char = 'R',
-                    Keycode::S => {
-                        self.player_input.direction |= PlayerDirection::DOWN;
-                        last_char = 'S';
+                    Keycode::Kp0 => self.player_input.jump_to_part = parts::part_id(10),
+                    Keycode::C => self.player_input.code = true,
+                    key @ (Keycode::Num0
+                    | Keycode::Num1
+                    | Keycode::Num2
+                    | Keycode::Num3
+                    | Keycode::Num4
+                    | Keycode::Num5
+                    | Keycode::Num6
+                    | Keycode::Num7
+                    | Keycode::Num8
+                    | Keycode::Num9) => {
+                        let slot = key as i32 - Keycode::Num0 as i32;
+                        self.player_input.state_slot = slot.clamp(0, 9) as i8;
                     }
-                    Keycode::T => last_char = 'T',
-                    Keycode::U => last_char = 'U',
-                    Keycode::V => last_char = 'V',
-                    Keycode::W => {
-                        self.player_input.direction |= PlayerDirection::UP;
-                        last_char = 'W';
-                    }
-                    Keycode::X => last_char = 'X',
-                    Keycode::Y => last_char = 'Y',
-                    Keycode::Z => last_char = 'Z',
                     _ => {}
                 },
+                Event::KeyUp {
+                    scancode: Some(Scancode::A),
+                    ..
+                } => self.player_input.direction &= !PlayerDirection::LEFT,
+                Event::KeyUp {
+                    scancode: Some(Scancode::D),
+                    ..
+                } => self.player_input.direction &= !PlayerDirection::RIGHT,
+                Event::KeyUp {
+                    scancode: Some(Scancode::W),
+                    ..
+                } => self.player_input.direction &= !PlayerDirection::UP,
+                Event::KeyUp {
+                    scancode: Some(Scancode::S),
+                    ..
+                } => self.player_input.direction &= !PlayerDirection::DOWN,
                 Event::KeyUp { keycode, .. } => match keycode.unwrap() {
-                    Keycode::Left | Keycode::A => {
-                        self.player_input.direction &= !PlayerDirection::LEFT
-                    }
-                    Keycode::Right | Keycode::D => {
-                        self.player_input.direction &= !PlayerDirection::RIGHT
-                    }
-                    Keycode::Up | Keycode::W => self.player_input.direction &= !PlayerDirection::UP,
-                    Keycode::Down | Keycode::S => {
-                        self.player_input.direction &= !PlayerDirection::DOWN
-                    }
+                    Keycode::Left => self.player_input.direction &= !PlayerDirection::LEFT,
+                    Keycode::Right => self.player_input.direction &= !PlayerDirection::RIGHT,
+                    Keycode::Up => self.player_input.direction &= !PlayerDirection::UP,
+                    Keycode::Down => self.player_input.direction &= !PlayerDirection::DOWN,
                     Keycode::LShift | Keycode::Space | Keycode::Return => {
                         self.player_input.button = false
                     }
+                    Keycode::Tab => self.player_input.fast_forward = false,
                     _ => {}
                 },
+                // Typed letters come from text input so they follow the
+                // keyboard layout
+                Event::TextInput { text, .. } => {
+                    if let Some(c) = text.chars().next().filter(char::is_ascii_alphabetic) {
+                        last_char = c.to_ascii_uppercase();
+                    }
+                }
                 _ => {}
             }
         }
         self.player_input.last_char = last_char;
-        let result = self.player_input;
+        let mut result = self.player_input;
+        if self.mirror_input {
+            let left = result.direction.contains(PlayerDirection::LEFT);
+            let right = result.direction.contains(PlayerDirection::RIGHT);
+            result.direction.set(PlayerDirection::LEFT, right);
+            result.direction.set(PlayerDirection::RIGHT, left);
+        }
         self.player_input.code = false;
+        self.player_input.pause = false;
+        self.player_input.step = false;
+        self.player_input.jump_to_part = None;
+        self.player_input.save = false;
+        self.player_input.load = false;
+        self.player_input.click = false;
+        self.player_input.resized = false;
+        self.player_input.menu = false;
+        self.player_input.toggle_scanlines = false;
+        self.player_input.toggle_crt = false;
+        self.player_input.toggle_vu_meter = false;
+        self.player_input.toggle_mute = false;
+        self.player_input.next_language = false;
+        self.player_input.toggle_memory_usage = false;
+        self.player_input.toggle_palette = false;
+        self.player_input.toggle_page_grid = false;
+        self.player_input.next_scale = false;
+        self.player_input.screenshot = false;
+        self.player_input.dump_pages = false;
+        self.player_input.restart_part = false;
+        self.player_input.music_slower = false;
+        self.player_input.music_faster = false;
+        self.player_input.music_next_order = false;
+        self.player_input.music_previous_order = false;
+        self.player_input.debug_break = false;
         result
     }
 }
-
-
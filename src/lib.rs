@@ -1,16 +1,32 @@
 pub mod bank;
+pub mod capture;
+pub mod clock;
+pub mod demo;
+pub mod disasm;
 pub mod engine;
 pub mod resource;
+#[cfg(feature = "sdl")]
 pub mod sys;
+pub mod system;
 pub mod video;
 pub mod vm;
 
+mod debugger;
 mod font;
+mod inflate;
+#[cfg(feature = "sdl")]
 pub mod input;
+mod lz;
 pub mod mixer;
-mod opcode;
-mod parts;
-mod player;
-mod sfxplayer;
-mod strings;
+pub mod opcode;
+pub mod overlay;
+pub mod parts;
+pub mod player;
+pub mod png;
+pub mod settings;
+pub mod sfxplayer;
+pub mod state;
+pub mod strings;
+pub mod sync;
 mod util;
+pub mod wav;
@@ -0,0 +1,181 @@
+//! Small LZ77 compressor for save states, in the block format of LZ4. Each
+//! sequence starts with a token byte holding the number of literals in the
+//! high nibble and the match length minus `MIN_MATCH` in the low nibble. A
+//! nibble of 15 continues in extra bytes that are added up until one is
+//! below 255. The literals follow, then the match offset as a little-endian
+//! u16. The last sequence only has literals.
+
+use std::io::{Error, ErrorKind, Result};
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xffff;
+const HASH_BITS: u32 = 12;
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let literal_nibble = literals.len().min(15);
+    let match_nibble = matched.map_or(0, |(_, len)| (len - MIN_MATCH).min(15));
+    out.push(((literal_nibble << 4) | match_nibble) as u8);
+    if literal_nibble == 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, len)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_nibble == 15 {
+            write_length(out, len - MIN_MATCH - 15);
+        }
+    }
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= data.len() {
+        let h = hash(&data[pos..]);
+        let candidate = table[h];
+        table[h] = pos;
+        if candidate != usize::MAX
+            && pos - candidate <= MAX_OFFSET
+            && data[candidate..candidate + MIN_MATCH] == data[pos..pos + MIN_MATCH]
+        {
+            let mut len = MIN_MATCH;
+            while pos + len < data.len() && data[candidate + len] == data[pos + len] {
+                len += 1;
+            }
+            write_sequence(
+                &mut out,
+                &data[literal_start..pos],
+                Some((pos - candidate, len)),
+            );
+            pos += len;
+            literal_start = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    write_sequence(&mut out, &data[literal_start..], None);
+    out
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Corrupt compressed data: {}", message),
+    )
+}
+
+fn read_length(data: &[u8], pos: &mut usize, mut len: usize) -> Result<usize> {
+    loop {
+        let byte = *data.get(*pos).ok_or_else(|| invalid("truncated length"))?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+/// Inflates `data` to `size` bytes
+pub fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>> {
+    // A corrupt header may give any size, while a byte of input can't
+    // expand to more than about 255 bytes of output
+    let mut out = Vec::with_capacity(size.min(data.len().saturating_mul(255)));
+    let mut pos = 0;
+    loop {
+        let token = *data.get(pos).ok_or_else(|| invalid("missing token"))?;
+        pos += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(data, &mut pos, literals)?;
+        }
+        let end = pos + literals;
+        if end > data.len() || out.len() + literals > size {
+            return Err(invalid("literals out of bounds"));
+        }
+        out.extend_from_slice(&data[pos..end]);
+        pos = end;
+        if pos == data.len() {
+            break;
+        }
+
+        if pos + 2 > data.len() {
+            return Err(invalid("truncated offset"));
+        }
+        let offset = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        pos += 2;
+        let mut len = (token & 0x0f) as usize;
+        if len == 15 {
+            len = read_length(data, &mut pos, len)?;
+        }
+        len += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + len > size {
+            return Err(invalid("match out of bounds"));
+        }
+        // Matches may overlap the bytes they produce
+        let start = out.len() - offset;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+    if out.len() != size {
+        return Err(invalid("unexpected size"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Vec<u8> {
+        let mut data = b"Another World save state ".repeat(40);
+        // Long runs need the extra length bytes, the rest barely repeats
+        data.extend(vec![0; 1000]);
+        data.extend((0..2000u32).map(|i| (i * 7919 % 251) as u8));
+        data
+    }
+
+    #[test]
+    fn roundtrip() {
+        for data in &[vec![], vec![1, 2, 3], vec![9; 300], sample()] {
+            let compressed = compress(data);
+            assert_eq!(&decompress(&compressed, data.len()).unwrap(), data);
+        }
+        assert!(compress(&sample()).len() < sample().len() / 2);
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let data = sample();
+        let compressed = compress(&data);
+        for len in [0, 1, compressed.len() / 2, compressed.len() - 1].iter() {
+            let error = decompress(&compressed[..*len], data.len()).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn wrong_size_is_an_error() {
+        let data = sample();
+        let compressed = compress(&data);
+        assert!(decompress(&compressed, data.len() - 1).is_err());
+        assert!(decompress(&compressed, data.len() + 1).is_err());
+        // Doesn't try to allocate the size given up front
+        assert!(decompress(&compressed, u32::MAX as usize).is_err());
+    }
+}
@@ -1,9 +1,6 @@
 use std::sync::{Arc, RwLock};
-use std::thread::sleep;
-use std::time::Duration;
 
-use log::{debug, trace};
-use sdl2::audio::AudioCallback;
+use log::debug;
 
 use crate::sfxplayer::SfxPattern;
 
@@ -14,12 +11,53 @@ pub const FREQUENCE_TABLE: [u16; 40] = [
     0x6793, 0x6E19, 0x7485, 0x7BBD,
 ];
 
-const NUM_CHANNELS: usize = 4;
+pub const NUM_CHANNELS: usize = 4;
 
 pub const SOUND_SAMPLE_RATE: u32 = 22050;
 
 fn add_clamp(a: i16, b: i16) -> i8 {
-    (a + b).clamp(-128, 127) as i8
+    (a as i32 + b as i32).clamp(-128, 127) as i8
+}
+
+/// Output sample format of the mixer. The 8 bit format mixes like the
+/// original, the 16 bit one keeps the fraction of the interpolated samples
+/// and the volume scaling.
+trait Sample: Copy + Default {
+    /// Adds an interpolated sample, in 8 bit units with 8 bits of fraction,
+    /// at `volume`, clamping the sum
+    fn add(self, sample: i32, volume: u8) -> Self;
+    fn low_pass(self, low_pass: &mut LowPass) -> Self;
+    fn echo(self, echo: &mut Echo) -> Self;
+}
+
+impl Sample for i8 {
+    fn add(self, sample: i32, volume: u8) -> i8 {
+        let v = (sample >> 8) as i8 as i16 * volume as i16 / 0x40;
+        add_clamp(self as i16, v)
+    }
+
+    fn low_pass(self, low_pass: &mut LowPass) -> i8 {
+        low_pass.process(self as f32).round() as i8
+    }
+
+    fn echo(self, echo: &mut Echo) -> i8 {
+        echo.process(self as f32) as i8
+    }
+}
+
+impl Sample for i16 {
+    fn add(self, sample: i32, volume: u8) -> i16 {
+        let v = sample * volume as i32 / 0x40;
+        (self as i32 + v).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+
+    fn low_pass(self, low_pass: &mut LowPass) -> i16 {
+        (low_pass.process(self as f32 / 256.0) * 256.0).round() as i16
+    }
+
+    fn echo(self, echo: &mut Echo) -> i16 {
+        (echo.process(self as f32 / 256.0) * 256.0) as i16
+    }
 }
 
 pub struct MixerChunk {
@@ -40,6 +78,21 @@ impl MixerChunk {
         }
     }
 
+    /// Signed 8 bit samples, the start of the loop included
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Length of the part played again and again once the sound reaches
+    /// `loop_pos`, 0 for sounds that don't loop
+    pub fn loop_len(&self) -> usize {
+        self.loop_len
+    }
+
+    pub fn loop_pos(&self) -> usize {
+        self.loop_pos
+    }
+
     pub fn from_sfx_pattern(pattern: SfxPattern) -> MixerChunk {
         MixerChunk {
             data: pattern.sample_buffer,
@@ -50,17 +103,109 @@ impl MixerChunk {
     }
 }
 
+/// Delay line mixed back into the output at reduced gain, with the result
+/// fed back into the line
+pub struct Echo {
+    buffer: Vec<f32>,
+    pos: usize,
+    feedback: f32,
+}
+
+impl Echo {
+    pub fn new(delay_ms: u32, feedback: f32) -> Echo {
+        let len = (SOUND_SAMPLE_RATE * delay_ms / 1000).max(1) as usize;
+        Echo {
+            buffer: vec![0.0; len],
+            pos: 0,
+            feedback,
+        }
+    }
+
+    /// Processes a sample in 8 bit units
+    fn process(&mut self, sample: f32) -> f32 {
+        let mixed = (sample + self.buffer[self.pos] * self.feedback).clamp(-128.0, 127.0);
+        self.buffer[self.pos] = mixed;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        mixed
+    }
+}
+
+/// One-pole low-pass filter, like the analog output filter of the Amiga
+pub struct LowPass {
+    alpha: f32,
+    state: f32,
+}
+
+impl LowPass {
+    pub const AMIGA_CUTOFF: f32 = 4500.0;
+
+    pub fn new(cutoff: f32) -> LowPass {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / SOUND_SAMPLE_RATE as f32;
+        LowPass {
+            alpha: dt / (rc + dt),
+            state: 0.0,
+        }
+    }
+
+    /// Processes a sample in 8 bit units
+    fn process(&mut self, sample: f32) -> f32 {
+        self.state += self.alpha * (sample - self.state);
+        self.state
+    }
+}
+
 pub struct Mixer {
     channels: [Option<MixerChannel>; NUM_CHANNELS],
+    echo: Option<Echo>,
+    low_pass: Option<LowPass>,
+    peaks: [u8; NUM_CHANNELS],
+    muted: bool,
+    paused: bool,
 }
 
 impl Mixer {
     pub fn new() -> Mixer {
         Mixer {
             channels: [None, None, None, None],
+            echo: None,
+            low_pass: None,
+            peaks: [0; NUM_CHANNELS],
+            muted: false,
+            paused: false,
         }
     }
 
+    /// Silences the output. Channels keep playing, so the game does not
+    /// notice and unmuting resumes the sounds where they are.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Outputs silence without advancing the channels, so the sounds go on
+    /// from where they were when unpaused
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn set_echo(&mut self, echo: Option<Echo>) {
+        self.echo = echo;
+    }
+
+    /// Peak level of each channel, between 0 and 128, in the last mixed
+    /// buffer
+    pub fn peak_levels(&self) -> [u8; NUM_CHANNELS] {
+        self.peaks
+    }
+
+    pub fn set_low_pass(&mut self, low_pass: Option<LowPass>) {
+        self.low_pass = low_pass;
+    }
+
     pub fn play_channel(
         &mut self,
         channel: u8,
@@ -83,41 +228,38 @@ impl Mixer {
         }
     }
 
+    /// Whether `channel` is still playing a sample
+    pub fn is_playing(&self, channel: u8) -> bool {
+        self.channels[channel as usize].is_some()
+    }
+
     pub fn stop_all(&mut self) {
         for channel in self.channels.iter_mut() {
             channel.take();
         }
     }
-}
 
-impl Default for Mixer {
-    fn default() -> Self {
-        Self::new()
+    /// Renders `n` samples at `SOUND_SAMPLE_RATE` without an audio device,
+    /// using the same mixing as the audio callback
+    pub fn render_samples(&mut self, n: usize) -> Vec<i8> {
+        let mut out = vec![0; n];
+        self.mix(&mut out);
+        out
     }
-}
 
-#[derive(Clone)]
-pub struct MixerAudio(pub Arc<RwLock<Mixer>>);
-
-impl AudioCallback for MixerAudio {
-    type Channel = i8;
-
-    fn callback(&mut self, out: &mut [i8]) {
-        trace!("MixerAudio::callback()");
-        let mut write_guard = loop {
-            if let Ok(write_guard) = self.0.write() {
-                break write_guard;
-            }
-            sleep(Duration::from_millis(10));
-        };
+    fn mix<S: Sample>(&mut self, out: &mut [S]) {
         for s in out.iter_mut() {
-            *s = 0;
+            *s = S::default();
         }
 
-        for (chan_num, ch) in write_guard.channels.iter_mut().enumerate() {
+        self.peaks = [0; NUM_CHANNELS];
+        if self.paused {
+            return;
+        }
+        for (chan_num, ch) in self.channels.iter_mut().enumerate() {
             if let Some(ref mut channel) = ch {
                 for s in out.iter_mut() {
-                    let ilc = (channel.chunk_pos & 0xff) as i16;
+                    let ilc = (channel.chunk_pos & 0xff) as i32;
                     let p1 = channel.chunk_pos >> 8;
                     channel.chunk_pos += channel.chunk_inc;
 
@@ -140,13 +282,76 @@ impl AudioCallback for MixerAudio {
                     assert!(p2 < channel.chunk.data.len());
                     let b1 = channel.chunk.data[p1] as i8;
                     let b2 = channel.chunk.data[p2] as i8;
-                    let b = ((b1 as i16 * (0xff - ilc) + b2 as i16 * ilc) >> 8) as i8;
+                    let b = b1 as i32 * (0xff - ilc) + b2 as i32 * ilc;
 
-                    *s = add_clamp(*s as i16, b as i16 * channel.volume as i16 / 0x40);
+                    let v = (b >> 8) as i8 as i16 * channel.volume as i16 / 0x40;
+                    let peak = v.unsigned_abs().min(128) as u8;
+                    self.peaks[chan_num] = self.peaks[chan_num].max(peak);
+                    *s = s.add(b, channel.volume);
                     //debug!("j: {}, p1: {}, b1: {}, p2: {}, b2: {}, b: {}, sample: {}", j, p1, b1, p2, b2, b, *s);
                 }
             }
         }
+
+        if let Some(low_pass) = self.low_pass.as_mut() {
+            for s in out.iter_mut() {
+                *s = s.low_pass(low_pass);
+            }
+        }
+
+        if let Some(echo) = self.echo.as_mut() {
+            for s in out.iter_mut() {
+                *s = s.echo(echo);
+            }
+        }
+
+        if self.muted {
+            self.peaks = [0; NUM_CHANNELS];
+            for s in out.iter_mut() {
+                *s = S::default();
+            }
+        }
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct MixerAudio(pub Arc<RwLock<Mixer>>);
+
+/// Like `MixerAudio`, with 16 bit output
+#[derive(Clone)]
+pub struct MixerAudio16(pub Arc<RwLock<Mixer>>);
+
+/// Playback through SDL audio devices
+#[cfg(feature = "sdl")]
+mod sdl {
+    use log::trace;
+    use sdl2::audio::AudioCallback;
+
+    use super::{MixerAudio, MixerAudio16};
+    use crate::sync;
+
+    impl AudioCallback for MixerAudio {
+        type Channel = i8;
+
+        fn callback(&mut self, out: &mut [i8]) {
+            trace!("MixerAudio::callback()");
+            sync::write(&self.0).mix(out);
+        }
+    }
+
+    impl AudioCallback for MixerAudio16 {
+        type Channel = i16;
+
+        fn callback(&mut self, out: &mut [i16]) {
+            trace!("MixerAudio16::callback()");
+            sync::write(&self.0).mix(out);
+        }
     }
 }
 
@@ -167,3 +372,37 @@ impl MixerChannel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_are_clamped_without_overflow() {
+        assert_eq!(add_clamp(5, -3), 2);
+        assert_eq!(add_clamp(100, 100), 127);
+        assert_eq!(add_clamp(-100, -100), -128);
+        assert_eq!(add_clamp(i16::MAX, i16::MAX), 127);
+        assert_eq!(add_clamp(i16::MIN, i16::MIN), -128);
+
+        assert_eq!(10i8.add(20 << 8, 0x20), 20);
+        assert_eq!(120i8.add(127 << 8, 0x40), 127);
+        assert_eq!((-120i8).add(-128 << 8, 0x40), -128);
+        assert_eq!(i16::MAX.add(i16::MAX as i32, 0x40), i16::MAX);
+        assert_eq!(i16::MIN.add(i16::MIN as i32, 0x40), i16::MIN);
+    }
+
+    #[test]
+    fn four_full_scale_channels_are_clamped() {
+        for &(sample, expected) in &[(0x7f, 127), (0x80, -128)] {
+            let mut mixer = Mixer::new();
+            let data = vec![sample; 1024];
+            for channel in 0..NUM_CHANNELS as u8 {
+                let chunk = MixerChunk::new(&data, data.len(), 0);
+                mixer.play_channel(channel, chunk, SOUND_SAMPLE_RATE as u16, 0x3f);
+            }
+            let out = mixer.render_samples(512);
+            assert!(out.iter().all(|&s| s == expected), "{:?}", &out[..8]);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Opcode {
     MovConst,
     Mov,
@@ -33,7 +33,13 @@ pub enum Opcode {
 
 impl Opcode {
     pub fn decode(val: u8) -> Opcode {
-        match val {
+        Opcode::try_decode(val).unwrap_or_else(|| unimplemented!("Illegal opcode: 0x{:x}", val))
+    }
+
+    /// Like `decode`, but returns `None` for illegal opcodes instead of
+    /// panicking, for tools walking arbitrary data
+    pub fn try_decode(val: u8) -> Option<Opcode> {
+        let opcode = match val {
             0x00 => Opcode::MovConst,
             0x01 => Opcode::Mov,
             0x02 => Opcode::Add,
@@ -61,9 +67,67 @@ impl Opcode {
             0x18 => Opcode::PlaySound,
             0x19 => Opcode::UpdateMemList,
             0x1a => Opcode::PlayMusic,
-            0x1b..=0x3f => unimplemented!("Illegal opcode: 0x{:x}", val),
+            0x1b..=0x3f => return None,
             0x40..=0x7f => Opcode::DrawPolySprite(val),
             0x80..=0xff => Opcode::DrawPolyBackground(val),
+        };
+        Some(opcode)
+    }
+
+    /// Number of operand bytes following the opcode byte, matching what the
+    /// interpreter fetches. `CondJmp` is the only opcode whose length depends
+    /// on an operand, so `first_operand` is the byte following the opcode.
+    pub fn operand_len(&self, first_operand: u8) -> usize {
+        match *self {
+            Opcode::Ret | Opcode::PauseThread | Opcode::KillThread => 0,
+            Opcode::SelectVideoPage | Opcode::BlitFrameBuffer => 1,
+            Opcode::Mov
+            | Opcode::Add
+            | Opcode::Call
+            | Opcode::Jmp
+            | Opcode::SetPalette
+            | Opcode::FillVideoPage
+            | Opcode::CopyVideoPage
+            | Opcode::Sub
+            | Opcode::UpdateMemList => 2,
+            Opcode::MovConst
+            | Opcode::AddConst
+            | Opcode::SetSetVect
+            | Opcode::Jnz
+            | Opcode::ResetThread
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Shl
+            | Opcode::Shr => 3,
+            Opcode::DrawString | Opcode::PlaySound | Opcode::PlayMusic => 5,
+            Opcode::CondJmp => {
+                // condition, variable, operand (variable, word or byte), target
+                let operand = if first_operand & 0x80 > 0 {
+                    1
+                } else if first_operand & 0x40 > 0 {
+                    2
+                } else {
+                    1
+                };
+                2 + operand + 2
+            }
+            Opcode::DrawPolySprite(val) => {
+                // offset word, then x, y and zoom depending on the flag bits
+                let mut len = 2 + 1 + 1;
+                if val & 0x30 == 0 {
+                    len += 1;
+                }
+                if val & 0x0c == 0 {
+                    len += 1;
+                }
+                // zoom is only fetched when exactly one of the two low bits is set
+                if (val & 2 == 0) != (val & 1 == 0) {
+                    len += 1;
+                }
+                len
+            }
+            // low byte of the offset, x and y
+            Opcode::DrawPolyBackground(_) => 3,
         }
     }
 }
@@ -0,0 +1,159 @@
+use crate::font::FONT;
+use crate::video::Page;
+
+pub const OVERLAY_WIDTH: u16 = 320;
+pub const OVERLAY_HEIGHT: u16 = 200;
+
+enum OverlayItem {
+    Rect {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        color: u8,
+    },
+    Text {
+        x: u16,
+        y: u16,
+        color: u8,
+        text: String,
+    },
+}
+
+/// Graphics drawn on top of the displayed page, such as menus and debug
+/// information. Coordinates are in the original 320x200 resolution and are
+/// scaled to the page size when drawn. The overlay is always composed onto a
+/// copy of the page, so the pages the scripts draw into are left untouched.
+#[derive(Default)]
+pub struct Overlay {
+    items: Vec<OverlayItem>,
+}
+
+impl Overlay {
+    pub fn new() -> Overlay {
+        Overlay { items: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Filled rectangle
+    pub fn rect(&mut self, x: u16, y: u16, width: u16, height: u16, color: u8) {
+        self.items.push(OverlayItem::Rect {
+            x,
+            y,
+            width,
+            height,
+            color,
+        });
+    }
+
+    /// Text using the game font, each character is 8x8 pixels. Characters
+    /// outside of the font are drawn as spaces.
+    pub fn text(&mut self, x: u16, y: u16, color: u8, text: &str) {
+        self.items.push(OverlayItem::Text {
+            x,
+            y,
+            color,
+            text: text.to_string(),
+        });
+    }
+
+    pub fn draw(&self, page: &mut Page, page_width: usize) {
+        let scale = (page_width / OVERLAY_WIDTH as usize).max(1);
+        for item in &self.items {
+            match item {
+                OverlayItem::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                    color,
+                } => {
+                    let x = *x as usize * scale;
+                    let y = *y as usize * scale;
+                    let width = *width as usize * scale;
+                    let height = *height as usize * scale;
+                    fill_rect(&mut page.data, page_width, x, y, width, height, *color);
+                }
+                OverlayItem::Text { x, y, color, text } => {
+                    let mut x = *x as usize;
+                    for c in text.chars() {
+                        if x + 8 > OVERLAY_WIDTH as usize {
+                            break;
+                        }
+                        draw_glyph(
+                            &mut page.data,
+                            page_width,
+                            c,
+                            x * scale,
+                            *y as usize * scale,
+                            *color,
+                            scale,
+                        );
+                        x += 8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fill_rect(
+    buffer: &mut [u8],
+    page_width: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    color: u8,
+) {
+    if x >= page_width {
+        return;
+    }
+    let width = width.min(page_width - x);
+    for row in buffer.chunks_mut(page_width).skip(y).take(height) {
+        for b in &mut row[x..x + width] {
+            *b = color;
+        }
+    }
+}
+
+/// Draws a single font character at pixel position `x`, `y` of the buffer.
+/// Pixels falling outside of the buffer are clipped.
+pub(crate) fn draw_glyph(
+    buffer: &mut [u8],
+    page_width: usize,
+    character: char,
+    x: usize,
+    y: usize,
+    color: u8,
+    scale: usize,
+) {
+    let c = character as u32;
+    let index = if (0x20..0x80).contains(&c) {
+        c - 0x20
+    } else {
+        0
+    };
+    let offset = index as usize * 8;
+    let font_char = &FONT[offset..offset + 8];
+    for j in 0..8 * scale {
+        let row = y + j;
+        let start = row * page_width;
+        if start >= buffer.len() {
+            break;
+        }
+        for i in 0..8 * scale {
+            let ch = font_char[j / scale] << (i / scale);
+            if ch & 0x80 > 0 && x + i < page_width {
+                buffer[start + x + i] = color;
+            }
+        }
+    }
+}
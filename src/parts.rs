@@ -3,6 +3,15 @@ pub struct Part {
     pub code: usize,
     pub video1: usize,
     pub video2: Option<usize>,
+    pub description: Option<&'static str>,
+}
+
+impl Part {
+    /// The part started by `part_id`, one of the `GAME_PARTn` ids
+    pub fn for_id(part_id: u16) -> Option<&'static Part> {
+        let num = part_number(part_id)?;
+        PARTS.get(num as usize - 1)
+    }
 }
 
 pub const PARTS: [Part; 10] = [
@@ -11,69 +20,79 @@ pub const PARTS: [Part; 10] = [
         code: 0x15,
         video1: 0x16,
         video2: None,
-    }, // protection screens
+        description: Some("Protection screens"),
+    },
     Part {
         palette: 0x17,
         code: 0x18,
         video1: 0x19,
         video2: None,
-    }, // introduction cinematic
+        description: Some("Introduction cinematic"),
+    },
     Part {
         palette: 0x1A,
         code: 0x1B,
         video1: 0x1C,
         video2: Some(0x11),
+        description: None,
     },
     Part {
         palette: 0x1D,
         code: 0x1E,
         video1: 0x1F,
         video2: Some(0x11),
+        description: Some("Wake up in the suspended jail"),
     },
     Part {
         palette: 0x20,
         code: 0x21,
         video1: 0x22,
         video2: Some(0x11),
+        description: None,
     },
     Part {
         palette: 0x23,
         code: 0x24,
         video1: 0x25,
         video2: None,
-    }, // battlechar cinematic
+        description: Some("Battlechar sequence"),
+    },
     Part {
         palette: 0x26,
         code: 0x27,
         video1: 0x28,
         video2: Some(0x11),
+        description: None,
     },
     Part {
         palette: 0x29,
         code: 0x2A,
         video1: 0x2B,
         video2: Some(0x11),
+        description: None,
     },
     Part {
         palette: 0x7D,
         code: 0x7E,
         video1: 0x7F,
         video2: None,
+        description: None,
     },
     Part {
         palette: 0x7D,
         code: 0x7E,
         video1: 0x7F,
         video2: None,
-    }, // password screen
+        description: Some("Password screen"),
+    },
 ];
 
 pub const GAME_PART1: u16 = 0x3E80;
-pub const GAME_PART2: u16 = 0x3E81; //Introduction
+pub const GAME_PART2: u16 = 0x3E81;
 pub const GAME_PART3: u16 = 0x3E82;
-pub const GAME_PART4: u16 = 0x3E83; //Wake up in the suspended jail
+pub const GAME_PART4: u16 = 0x3E83;
 pub const GAME_PART5: u16 = 0x3E84;
-pub const GAME_PART6: u16 = 0x3E85; //BattleChar sequence
+pub const GAME_PART6: u16 = 0x3E85;
 pub const GAME_PART7: u16 = 0x3E86;
 pub const GAME_PART8: u16 = 0x3E87;
 pub const GAME_PART9: u16 = 0x3E88;
@@ -81,3 +100,38 @@ pub const GAME_PART10: u16 = 0x3E89;
 
 pub const GAME_PART_FIRST: u16 = GAME_PART1;
 pub const GAME_PART_LAST: u16 = GAME_PART10;
+
+/// Level codes of the PC version, in the order the game gives them. The
+/// password screen checks a code against a table in its own bytecode and
+/// requests the part of the checkpoint, so the part a code leads to is
+/// found by entering it.
+pub const PC_CODES: [&str; 23] = [
+    "LDKD", "HTDC", "CLLD", "LBKG", "XDDJ", "FXLC", "KRFK", "KLFB", "TTCT", "DDRX", "TBHK", "BRTD",
+    "CKJL", "LFCK", "BFLX", "XJRT", "HRTB", "HBHK", "JCGB", "HHFL", "TFBB", "TXHF", "JHJL",
+];
+
+/// Part id for a part number between 1 and 10
+pub fn part_id(part_num: u8) -> Option<u16> {
+    match part_num {
+        1..=10 => Some(GAME_PART_FIRST + part_num as u16 - 1),
+        _ => None,
+    }
+}
+
+/// Part number between 1 and 10 for a part id, the reverse of `part_id`
+pub fn part_number(part_id: u16) -> Option<u8> {
+    if (GAME_PART_FIRST..=GAME_PART_LAST).contains(&part_id) {
+        Some((part_id - GAME_PART_FIRST) as u8 + 1)
+    } else {
+        None
+    }
+}
+
+/// Part number and description of a part id, for display
+pub fn part_name(part_id: u16) -> Option<String> {
+    let num = part_number(part_id)?;
+    Some(match Part::for_id(part_id)?.description {
+        Some(description) => format!("{} ({})", num, description),
+        None => num.to_string(),
+    })
+}
@@ -16,11 +16,39 @@ pub struct PlayerInput {
     pub button: bool,
     pub code: bool,
     pub pause: bool,
+    /// Runs one frame while paused
+    pub step: bool,
     pub quit: bool,
+    pub menu: bool,
     pub last_char: char,
+    /// Mouse position in 320x200 coordinates, `None` outside of the game area
+    pub cursor: Option<(u16, u16)>,
+    pub click: bool,
+    pub resized: bool,
     pub save: bool,
     pub load: bool,
     pub state_slot: i8,
+    /// Part id to switch to, from the keypad
+    pub jump_to_part: Option<u16>,
+    pub toggle_scanlines: bool,
+    pub toggle_crt: bool,
+    pub toggle_vu_meter: bool,
+    pub toggle_mute: bool,
+    pub next_language: bool,
+    pub toggle_memory_usage: bool,
+    pub toggle_palette: bool,
+    pub toggle_page_grid: bool,
+    pub next_scale: bool,
+    pub screenshot: bool,
+    pub dump_pages: bool,
+    pub restart_part: bool,
+    pub music_slower: bool,
+    pub music_faster: bool,
+    pub music_next_order: bool,
+    pub music_previous_order: bool,
+    pub debug_break: bool,
+    /// Held down to run the game as fast as possible
+    pub fast_forward: bool,
 }
 
 impl PlayerInput {
@@ -30,11 +58,35 @@ impl PlayerInput {
             button: false,
             code: false,
             pause: false,
+            step: false,
             quit: false,
+            menu: false,
             last_char: '\0',
+            cursor: None,
+            click: false,
+            resized: false,
             save: false,
             load: false,
             state_slot: 0,
+            jump_to_part: None,
+            toggle_scanlines: false,
+            toggle_crt: false,
+            toggle_vu_meter: false,
+            toggle_mute: false,
+            next_language: false,
+            toggle_memory_usage: false,
+            toggle_palette: false,
+            toggle_page_grid: false,
+            next_scale: false,
+            screenshot: false,
+            dump_pages: false,
+            restart_part: false,
+            music_slower: false,
+            music_faster: false,
+            music_next_order: false,
+            music_previous_order: false,
+            debug_break: false,
+            fast_forward: false,
         }
     }
 }
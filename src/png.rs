@@ -0,0 +1,375 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use crate::inflate;
+use crate::util::{crc32, crc32_update};
+use crate::video::Palette;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+fn write_chunk<W: Write>(out: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> Result<()> {
+    out.write_u32::<BigEndian>(data.len() as u32)?;
+    out.write_all(chunk_type)?;
+    out.write_all(data)?;
+    let crc = crc32_update(crc32(chunk_type), data);
+    out.write_u32::<BigEndian>(crc)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Wraps `data` in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_STORED_BLOCK * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(if last { 1 } else { 0 });
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Writes an 8 bit indexed PNG image using the 16 colors of `palette`
+pub fn write_indexed<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    palette: &Palette,
+    pixels: &[u8],
+) -> Result<()> {
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::new();
+    header.write_u32::<BigEndian>(width as u32)?;
+    header.write_u32::<BigEndian>(height as u32)?;
+    // Bit depth 8, color type 3 (indexed), default compression, filter and
+    // no interlacing
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    let plte: Vec<u8> = palette
+        .entries
+        .iter()
+        .flat_map(|c| vec![c.r, c.g, c.b])
+        .collect();
+    write_chunk(out, b"PLTE", &plte)?;
+
+    // Every scanline starts with filter type 0. Pixels outside of the
+    // palette are mapped to the last color.
+    let max_index = palette.entries.len() as u8 - 1;
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width).take(height) {
+        raw.push(0);
+        raw.extend(row.iter().map(|&p| p.min(max_index)));
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+/// Writes an 8 bit RGBA PNG image, `pixels` holds 4 bytes per pixel
+pub fn write_rgba<W: Write>(out: &mut W, width: usize, height: usize, pixels: &[u8]) -> Result<()> {
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::new();
+    header.write_u32::<BigEndian>(width as u32)?;
+    header.write_u32::<BigEndian>(height as u32)?;
+    // Bit depth 8, color type 6 (RGBA), default compression, filter and no
+    // interlacing
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    let mut raw = Vec::with_capacity((width * 4 + 1) * height);
+    for row in pixels.chunks(width * 4).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+pub fn save_indexed(
+    path: &Path,
+    width: usize,
+    height: usize,
+    palette: &Palette,
+    pixels: &[u8],
+) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_indexed(&mut out, width, height, palette, pixels)?;
+    out.flush()
+}
+
+pub fn save_rgba(path: &Path, width: usize, height: usize, pixels: &[u8]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_rgba(&mut out, width, height, pixels)?;
+    out.flush()
+}
+
+/// Image read from a PNG file
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// RGB color of each pixel, alpha is dropped
+    pub pixels: Vec<(u8, u8, u8)>,
+    /// Palette index of each pixel, for indexed images
+    pub indices: Option<Vec<u8>>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reverses the filter of each scanline and returns the rows without their
+/// filter type byte
+fn unfilter(data: &[u8], stride: usize, height: usize, bpp: usize) -> Result<Vec<u8>> {
+    let mut rows = vec![0; stride * height];
+    for y in 0..height {
+        let line = &data[y * (stride + 1)..(y + 1) * (stride + 1)];
+        let filter = line[0];
+        let (done, rest) = rows.split_at_mut(y * stride);
+        let previous = if y > 0 {
+            &done[(y - 1) * stride..]
+        } else {
+            &[][..]
+        };
+        let row = &mut rest[..stride];
+        for x in 0..stride {
+            let left = if x >= bpp { row[x - bpp] } else { 0 };
+            let up = previous.get(x).copied().unwrap_or(0);
+            let up_left = if x >= bpp {
+                previous.get(x - bpp).copied().unwrap_or(0)
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(invalid(format!("Unknown PNG filter type {}", filter))),
+            };
+            row[x] = line[x + 1].wrapping_add(predicted);
+        }
+    }
+    Ok(rows)
+}
+
+/// Reads a PNG image that is not interlaced. Indexed and grayscale images
+/// may use 1 to 8 bits per pixel, RGB images and images with alpha 8 bits
+/// per component.
+pub fn read(data: &[u8]) -> Result<Image> {
+    if data.len() < SIGNATURE.len() || data[..SIGNATURE.len()] != SIGNATURE {
+        return Err(invalid("Not a PNG file".to_string()));
+    }
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut compressed = Vec::new();
+    let mut pos = SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let len = BigEndian::read_u32(&data[pos..]) as usize;
+        let chunk_type = &data[pos + 4..pos + 8];
+        let chunk = data
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| invalid("Truncated PNG chunk".to_string()))?;
+        pos += 12 + len;
+        match chunk_type {
+            b"IHDR" if len == 13 => header = Some(chunk),
+            b"PLTE" => {
+                palette = chunk.chunks_exact(3).map(|c| (c[0], c[1], c[2])).collect();
+            }
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+    let header = header.ok_or_else(|| invalid("Missing PNG header".to_string()))?;
+    let width = BigEndian::read_u32(header) as usize;
+    let height = BigEndian::read_u32(&header[4..]) as usize;
+    let (depth, color_type, interlace) = (header[8], header[9], header[12]);
+    if interlace != 0 {
+        return Err(invalid(
+            "Interlaced PNG images are not supported".to_string(),
+        ));
+    }
+    let channels = match (color_type, depth) {
+        (0, 1 | 2 | 4 | 8) | (3, 1 | 2 | 4 | 8) => 1,
+        (4, 8) => 2,
+        (2, 8) => 3,
+        (6, 8) => 4,
+        _ => {
+            return Err(invalid(format!(
+                "Unsupported PNG color type {} with bit depth {}",
+                color_type, depth
+            )))
+        }
+    };
+    let bits_per_pixel = channels * depth as usize;
+    // Each row starts with a filter type byte
+    let size = width
+        .checked_mul(bits_per_pixel)
+        .map(|bits| bits.div_ceil(8) + 1)
+        .and_then(|row| row.checked_mul(height))
+        .ok_or_else(|| invalid(format!("PNG image too large: {}x{}", width, height)))?;
+    let stride = (width * bits_per_pixel).div_ceil(8);
+    let raw = inflate::zlib_decompress(&compressed, size)?;
+    if raw.len() < size {
+        return Err(invalid("Truncated PNG image data".to_string()));
+    }
+    let rows = unfilter(&raw, stride, height, bits_per_pixel.div_ceil(8))?;
+
+    // Samples of the first channel, unpacked when several fit in a byte
+    let sample = |x: usize, y: usize, channel: usize| -> u8 {
+        let row = &rows[y * stride..];
+        if depth == 8 {
+            return row[x * channels + channel];
+        }
+        let bit = x * depth as usize;
+        let shift = 8 - depth as usize - bit % 8;
+        (row[bit / 8] >> shift) & ((1 << depth) - 1)
+    };
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut indices = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let color = match color_type {
+                3 => {
+                    let index = sample(x, y, 0);
+                    indices.push(index);
+                    *palette.get(index as usize).ok_or_else(|| {
+                        invalid(format!("PNG color index {} outside of the palette", index))
+                    })?
+                }
+                0 | 4 => {
+                    let max = (1u16 << depth) - 1;
+                    let v = (sample(x, y, 0) as u16 * 255 / max) as u8;
+                    (v, v, v)
+                }
+                _ => (sample(x, y, 0), sample(x, y, 1), sample(x, y, 2)),
+            };
+            pixels.push(color);
+        }
+    }
+    Ok(Image {
+        width,
+        height,
+        pixels,
+        indices: if color_type == 3 { Some(indices) } else { None },
+    })
+}
+
+pub fn load(path: &Path) -> Result<Image> {
+    read(&fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32, depth: u8, color_type: u8, raw: &[u8]) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+        let mut header = Vec::new();
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[depth, color_type, 0, 0, 0]);
+        write_chunk(&mut out, b"IHDR", &header).unwrap();
+        write_chunk(&mut out, b"IDAT", &zlib_stored(raw)).unwrap();
+        write_chunk(&mut out, b"IEND", &[]).unwrap();
+        out
+    }
+
+    #[test]
+    fn reads_indexed_and_rgba_images() {
+        let palette = Palette::grayscale();
+        let pixels = [0, 1, 2, 3, 15, 14];
+        let mut data = Vec::new();
+        write_indexed(&mut data, 3, 2, &palette, &pixels).unwrap();
+        let image = read(&data).unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.indices.as_deref(), Some(&pixels[..]));
+        assert_eq!(image.pixels[4], (0xff, 0xff, 0xff));
+
+        let rgba = [1, 2, 3, 255, 4, 5, 6, 0];
+        let mut data = Vec::new();
+        write_rgba(&mut data, 2, 1, &rgba).unwrap();
+        let image = read(&data).unwrap();
+        assert!(image.indices.is_none());
+        assert_eq!(image.pixels, vec![(1, 2, 3), (4, 5, 6)]);
+    }
+
+    #[test]
+    fn reverses_row_filters() {
+        // 8 bit grayscale rows with the sub, up, average and paeth filters
+        let raw = [
+            1, 10, 5, 5, //
+            2, 1, 1, 1, //
+            3, 4, 2, 2, //
+            4, 0, 0, 0,
+        ];
+        let image = read(&png(3, 4, 8, 0, &raw)).unwrap();
+        let gray: Vec<u8> = image.pixels.iter().map(|p| p.0).collect();
+        assert_eq!(gray, vec![10, 15, 20, 11, 16, 21, 9, 14, 19, 9, 14, 19]);
+    }
+
+    #[test]
+    fn unpacks_small_bit_depths() {
+        let image = read(&png(3, 1, 2, 0, &[0, 0b0001_1100])).unwrap();
+        let gray: Vec<u8> = image.pixels.iter().map(|p| p.0).collect();
+        assert_eq!(gray, vec![0, 85, 255]);
+    }
+
+    #[test]
+    fn invalid_images_are_errors() {
+        let valid = png(1, 1, 8, 0, &[0, 0]);
+        assert!(read(&valid[1..]).is_err());
+        assert!(read(&valid[..valid.len() - 20]).is_err());
+        assert!(read(&png(1, 1, 16, 0, &[0, 0, 0])).is_err());
+        assert!(read(&png(2, 2, 8, 0, &[0, 0, 0])).is_err());
+        assert!(read(&png(1, 1, 8, 0, &[5, 0])).is_err());
+        // Indexed image without a palette
+        assert!(read(&png(1, 1, 8, 3, &[0, 0])).is_err());
+        // More image data than the header allows, and sizes that overflow
+        assert!(read(&png(1, 1, 8, 0, &[0; 1000])).is_err());
+        assert!(read(&png(u32::MAX, u32::MAX, 8, 6, &[0, 0])).is_err());
+    }
+}
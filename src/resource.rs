@@ -1,7 +1,12 @@
-use std::fs::File;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::{Cursor, Error, ErrorKind, Result, SeekFrom};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
 
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
 use log::{debug, info, warn};
@@ -9,9 +14,14 @@ use log::{debug, info, warn};
 use crate::bank::Bank;
 use crate::mixer::MixerChunk;
 use crate::parts;
+use crate::png;
 use crate::sfxplayer::{SfxInstrument, SfxModule};
+use crate::video::{Palette, NUM_COLORS};
 
 const MEM_BLOCK_SIZE: usize = 600 * 1024;
+/// Palettes in a palette resource that the scripts can select
+const NUM_PALETTES: usize = 32;
+const PALETTE_SIZE: usize = NUM_COLORS * 2;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum AssetPlatform {
@@ -20,6 +30,153 @@ pub enum AssetPlatform {
     AtariST,
 }
 
+impl AssetPlatform {
+    /// File holding the memlist, either standalone or inside the executable
+    pub fn memlist_file(&self) -> &'static str {
+        match self {
+            AssetPlatform::PC => "Memlist.bin",
+            AssetPlatform::Amiga => "another",
+            AssetPlatform::AtariST => "START.PRG",
+        }
+    }
+
+    /// Preferred file name of a bank on this platform
+    pub fn bank_file(&self, bank_id: u8) -> String {
+        match self {
+            AssetPlatform::PC => format!("Bank{:02x}", bank_id),
+            AssetPlatform::Amiga => format!("bank{:02X}", bank_id),
+            AssetPlatform::AtariST => format!("BANK{:02X}", bank_id),
+        }
+    }
+}
+
+/// Errors from reading the game data
+#[derive(Debug)]
+pub enum ResourceError {
+    /// A data file could not be read or is not in the expected format
+    Io(Error),
+    /// A part id that is not one of the game parts
+    UnknownPart(u16),
+    /// A part number outside of 1 to 10
+    UnknownPartNumber(u8),
+    /// A resource id beyond the end of the memlist
+    UnknownResource(u16),
+    /// An entry unpacked to a different size than the memlist gives
+    SizeMismatch {
+        id: usize,
+        size: usize,
+        expected: usize,
+        bank_id: u8,
+        bank_offset: u32,
+    },
+    /// A music module uses an instrument that is not a loaded sound
+    MissingInstrument(u16),
+    /// The bytecode nests calls deeper than the call stack, at this offset
+    CallStackOverflow(usize),
+    /// The bytecode returns with nothing on the call stack, at this offset
+    CallStackUnderflow(usize),
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceError::Io(e) => write!(f, "{}", e),
+            ResourceError::UnknownPart(part_id) => write!(f, "Unknown part 0x{:04x}", part_id),
+            ResourceError::UnknownPartNumber(part_num) => {
+                write!(f, "Unknown part number: {}", part_num)
+            }
+            ResourceError::UnknownResource(id) => write!(f, "Unknown resource 0x{:02x}", id),
+            // Unpacked banks that don't match the memlist come from mixing
+            // data files of different versions of the game
+            ResourceError::SizeMismatch {
+                id,
+                size,
+                expected,
+                bank_id,
+                bank_offset,
+            } => write!(
+                f,
+                "Entry 0x{:02x} unpacked to {} bytes, expected {} (bank {:02x} offset 0x{:x}). \
+                 Are the data files from the same version of the game?",
+                id, size, expected, bank_id, bank_offset
+            ),
+            ResourceError::MissingInstrument(id) => {
+                write!(f, "Instrument 0x{:02x} is not a loaded sound", id)
+            }
+            ResourceError::CallStackOverflow(pc) => {
+                write!(f, "Call stack overflow at 0x{:04x}", pc)
+            }
+            ResourceError::CallStackUnderflow(pc) => {
+                write!(f, "Return with an empty call stack at 0x{:04x}", pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResourceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ResourceError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for ResourceError {
+    fn from(e: Error) -> ResourceError {
+        ResourceError::Io(e)
+    }
+}
+
+impl From<ResourceError> for Error {
+    fn from(e: ResourceError) -> Error {
+        match e {
+            ResourceError::Io(e) => e,
+            e => Error::new(ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+fn check_unpacked_size(
+    id: usize,
+    entry: &MemEntry,
+    size: usize,
+) -> std::result::Result<(), ResourceError> {
+    if size == entry.size {
+        return Ok(());
+    }
+    Err(ResourceError::SizeMismatch {
+        id,
+        size,
+        expected: entry.size,
+        bank_id: entry.bank_id,
+        bank_offset: entry.bank_offset,
+    })
+}
+
+fn path_error(e: Error, path: &Path, asset_platform: &AssetPlatform) -> Error {
+    Error::new(
+        e.kind(),
+        format!("{} ({:?} assets): {}", path.display(), asset_platform, e),
+    )
+}
+
+impl FromStr for AssetPlatform {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pc" | "dos" => Ok(AssetPlatform::PC),
+            "amiga" => Ok(AssetPlatform::Amiga),
+            "atari" | "atarist" | "st" => Ok(AssetPlatform::AtariST),
+            _ => Err(format!(
+                "Unknown platform: {} (expected pc, amiga or atari)",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum MemEntryState {
     NotNeeded = 0,
@@ -29,16 +186,21 @@ enum MemEntryState {
 }
 
 impl MemEntryState {
-    fn from_u8(val: u8) -> Result<Self> {
+    /// Unknown states of entry `index` are treated as not needed, so memlists
+    /// of other game versions can still be read
+    fn from_u8(val: u8, index: usize) -> Self {
         match val {
-            0 => Ok(MemEntryState::NotNeeded),
-            1 => Ok(MemEntryState::Loaded),
-            2 => Ok(MemEntryState::LoadMe),
-            0xff => Ok(MemEntryState::EndOfMemList),
-            _ => Err(Error::new(
-                ErrorKind::Other,
-                format!("Unknown MemEntryState: {}", val),
-            )),
+            0 => MemEntryState::NotNeeded,
+            1 => MemEntryState::Loaded,
+            2 => MemEntryState::LoadMe,
+            0xff => MemEntryState::EndOfMemList,
+            _ => {
+                warn!(
+                    "Unknown state 0x{:02x} of memlist entry {}, treating it as not needed",
+                    val, index
+                );
+                MemEntryState::NotNeeded
+            }
         }
     }
 }
@@ -55,7 +217,7 @@ pub enum EntryType {
 }
 
 impl EntryType {
-    fn from_u8(val: u8) -> Self {
+    fn from_u8(val: u8, index: usize) -> Self {
         match val {
             0 => EntryType::Sound,
             1 => EntryType::Music,
@@ -63,7 +225,10 @@ impl EntryType {
             3 => EntryType::Palette,
             4 => EntryType::Bytecode,
             5 => EntryType::PolyCinematic,
-            n => EntryType::Unknown(n),
+            n => {
+                warn!("Unknown type 0x{:02x} of memlist entry {}", n, index);
+                EntryType::Unknown(n)
+            }
         }
     }
 }
@@ -83,6 +248,57 @@ pub struct MemEntry {
     size: usize,
 }
 
+impl MemEntry {
+    /// Unpacked size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Size in bytes in the bank file, the same as `size` when the entry is
+    /// not compressed
+    pub fn packed_size(&self) -> usize {
+        self.packed_size
+    }
+}
+
+/// Case-insensitive lookup of bank files in the asset directory
+struct BankFiles {
+    asset_path: PathBuf,
+    files: HashMap<String, PathBuf>,
+}
+
+impl BankFiles {
+    fn scan(asset_path: &Path) -> BankFiles {
+        let mut files = HashMap::new();
+        match fs::read_dir(asset_path) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().to_lowercase();
+                    files.entry(name).or_insert_with(|| entry.path());
+                }
+            }
+            Err(e) => warn!("Could not list {}: {}", asset_path.display(), e),
+        }
+        BankFiles {
+            asset_path: asset_path.to_path_buf(),
+            files,
+        }
+    }
+
+    fn resolve(&self, asset_platform: &AssetPlatform, bank_id: u8) -> PathBuf {
+        let preferred = asset_platform.bank_file(bank_id);
+        let exact = self.asset_path.join(&preferred);
+        if exact.exists() {
+            return exact;
+        }
+        let name = preferred.to_lowercase();
+        [name.clone(), format!("{}.bnk", name)]
+            .iter()
+            .find_map(|candidate| self.files.get(candidate).cloned())
+            .unwrap_or(exact)
+    }
+}
+
 pub struct MemlistReader {
     asset_path: PathBuf,
     asset_platform: AssetPlatform,
@@ -97,15 +313,57 @@ impl MemlistReader {
     }
 
     pub fn detect_platform(asset_path: PathBuf) -> MemlistReader {
-        let asset_platform = if asset_path.join("another").exists() {
-            info!("Detected Amiga binary");
-            AssetPlatform::Amiga
-        } else if asset_path.join("START.PRG").exists() {
-            info!("Detected Atari ST binary");
-            AssetPlatform::AtariST
-        } else {
-            info!("Assuming PC / Memlist.bin version");
-            AssetPlatform::PC
+        let bank_files = BankFiles::scan(&asset_path);
+        let candidates = [
+            AssetPlatform::Amiga,
+            AssetPlatform::AtariST,
+            AssetPlatform::PC,
+        ];
+        let mut present = Vec::new();
+        let mut confirmed = Vec::new();
+        for platform in candidates.iter() {
+            if !asset_path.join(platform.memlist_file()).exists() {
+                continue;
+            }
+            present.push(*platform);
+            let reader = MemlistReader::new(asset_path.clone(), *platform);
+            let entries = reader.read_mem_list().map(|m| m.len()).unwrap_or(0);
+            let bank = bank_files.resolve(platform, 1).exists();
+            info!(
+                "{:?}: found {}, memlist entries: {}, first bank found: {}",
+                platform,
+                platform.memlist_file(),
+                entries,
+                bank
+            );
+            if entries > 0 && bank {
+                confirmed.push(*platform);
+            }
+        }
+
+        let asset_platform = match (confirmed.as_slice(), present.as_slice()) {
+            ([platform], _) => {
+                info!("Detected {:?} assets", platform);
+                *platform
+            }
+            ([platform, ..], _) => {
+                warn!(
+                    "Ambiguous assets, matching {:?}. Using {:?}, override with --platform",
+                    confirmed, platform
+                );
+                *platform
+            }
+            ([], [platform, ..]) => {
+                warn!(
+                    "Could not confirm {:?} assets, override with --platform if this is wrong",
+                    platform
+                );
+                *platform
+            }
+            ([], []) => {
+                info!("Assuming PC / Memlist.bin version");
+                AssetPlatform::PC
+            }
         };
         MemlistReader::new(asset_path, asset_platform)
     }
@@ -130,8 +388,9 @@ impl MemlistReader {
 
     fn read_memlist_from_executable(&self, executable_name: &str) -> Result<Vec<MemEntry>> {
         let path = self.asset_path.join(executable_name);
-        let mut file = File::open(&path)?;
-        let offset = MemlistReader::find_memlist_offset(&mut file)?;
+        let mut file = File::open(&path).map_err(|e| path_error(e, &path, &self.asset_platform))?;
+        let offset = MemlistReader::find_memlist_offset(&mut file)
+            .map_err(|e| path_error(e, &path, &self.asset_platform))?;
         file.seek(SeekFrom::Start(offset))?;
         self.read_entries(&mut file)
     }
@@ -139,9 +398,13 @@ impl MemlistReader {
     fn read_entries<R: Read>(&self, reader: &mut R) -> Result<Vec<MemEntry>> {
         let mut mem_list = Vec::new();
         loop {
+            let state = MemEntryState::from_u8(reader.read_u8()?, mem_list.len());
+            if let MemEntryState::EndOfMemList = state {
+                break;
+            }
             let entry = MemEntry {
-                state: MemEntryState::from_u8(reader.read_u8()?)?,
-                entry_type: EntryType::from_u8(reader.read_u8()?),
+                state,
+                entry_type: EntryType::from_u8(reader.read_u8()?, mem_list.len()),
                 buf_ptr: reader.read_u16::<BigEndian>()? as usize,
                 _unk4: reader.read_u16::<BigEndian>()?,
                 rank_num: reader.read_u8()?,
@@ -152,35 +415,67 @@ impl MemlistReader {
                 _unk10: reader.read_u16::<BigEndian>()?,
                 size: reader.read_u16::<BigEndian>()? as usize,
             };
-            if let MemEntryState::EndOfMemList = entry.state {
-                break;
-            }
             mem_list.push(entry);
         }
         Ok(mem_list)
     }
 
-    pub fn read_memlist(self) -> Result<Resource> {
+    fn read_mem_list(&self) -> Result<Vec<MemEntry>> {
         let mem_list = match self.asset_platform {
             AssetPlatform::PC => {
-                let path = self.asset_path.join("Memlist.bin");
-                let mut file = File::open(&path).map_err(|_| {
-                    Error::new(
-                        ErrorKind::NotFound,
-                        format!("{} does not exist", path.display()),
-                    )
-                })?;
-                self.read_entries(&mut file)?
-            }
-            AssetPlatform::Amiga => self.read_memlist_from_executable("another")?,
-            AssetPlatform::AtariST => self.read_memlist_from_executable("START.PRG")?,
+                let path = self.asset_path.join(self.asset_platform.memlist_file());
+                let mut file =
+                    File::open(&path).map_err(|e| path_error(e, &path, &self.asset_platform))?;
+                self.read_entries(&mut file)
+                    .map_err(|e| path_error(e, &path, &self.asset_platform))?
+            }
+            AssetPlatform::Amiga | AssetPlatform::AtariST => {
+                self.read_memlist_from_executable(self.asset_platform.memlist_file())?
+            }
         };
+        Ok(mem_list)
+    }
+
+    pub fn read_memlist(self) -> std::result::Result<Resource, ResourceError> {
+        let mem_list = self.read_mem_list()?;
         Ok(Resource::new(
             mem_list,
             self.asset_path,
             self.asset_platform,
         ))
     }
+
+    /// Like `read_memlist`, with the error as a message for the user. When
+    /// the files are missing it says where the game data was expected.
+    pub fn open(self) -> std::result::Result<Resource, String> {
+        let hint = format!(
+            "Could not find game data at {} (expected {} / {}…)",
+            self.asset_path.display(),
+            self.asset_platform.memlist_file(),
+            self.asset_platform.bank_file(1)
+        );
+        self.read_memlist().map_err(|e| match e {
+            ResourceError::Io(ref io) if io.kind() == ErrorKind::NotFound => {
+                format!("{}\n{}", hint, e)
+            }
+            e => e.to_string(),
+        })
+    }
+}
+
+/// Usage of the resource memory region. Bytecode, palettes and sounds are
+/// loaded upwards from the start, and must stay below the area reserved for
+/// the background bitmap.
+pub struct MemoryUsage {
+    pub used: usize,
+    pub limit: usize,
+    pub loaded: Vec<(EntryType, usize)>,
+}
+
+impl MemoryUsage {
+    pub fn free(&self) -> usize {
+        self.limit.saturating_sub(self.used)
+    }
 }
 
 pub struct Resource {
@@ -195,9 +490,20 @@ pub struct Resource {
     pub seg_bytecode: usize,
     pub seg_cinematic: usize,
     pub seg_video2: usize,
+    /// Sizes of the segments above, 0 for a part without a video2 segment
+    pub seg_palettes_len: usize,
+    pub seg_bytecode_len: usize,
+    pub seg_cinematic_len: usize,
+    pub seg_video2_len: usize,
     pub copy_vid_ptr: bool,
-    asset_path: PathBuf,
+    bank_files: BankFiles,
     pub asset_platform: AssetPlatform,
+    /// Unpacked data of preloaded entries, by resource id
+    preloaded: HashMap<usize, Vec<u8>>,
+    check_overlaps: bool,
+    background_dir: Option<PathBuf>,
+    /// Image loaded in place of the last background bitmap
+    background_override: Option<png::Image>,
 }
 
 impl Resource {
@@ -218,30 +524,103 @@ impl Resource {
             seg_bytecode: 0,
             seg_cinematic: 0,
             seg_video2: 0,
+            seg_palettes_len: 0,
+            seg_bytecode_len: 0,
+            seg_cinematic_len: 0,
+            seg_video2_len: 0,
             copy_vid_ptr: false,
-            asset_path,
+            bank_files: BankFiles::scan(&asset_path),
             asset_platform,
+            preloaded: HashMap::new(),
+            check_overlaps: false,
+            background_dir: None,
+            background_override: None,
         }
     }
 
-    pub fn setup_part(&mut self, part_id: u16) {
-        debug!("setup_part: {}", part_id);
-        if part_id == self.current_part_id {
-            return;
+    /// Directory of 320x200 PNG images that replace background bitmaps,
+    /// named `background_<resource id>.png` with the id in hex
+    pub fn set_background_dir(&mut self, dir: Option<PathBuf>) {
+        self.background_dir = dir;
+    }
+
+    fn load_background_override(dir: &Path, id: usize) -> Option<png::Image> {
+        let path = dir.join(format!("background_{:02x}.png", id));
+        if !path.exists() {
+            return None;
         }
+        let image = png::load(&path).and_then(|image| {
+            if (image.width, image.height) != (320, 200) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}x{}, expected 320x200", image.width, image.height),
+                ));
+            }
+            Ok(image)
+        });
+        match image {
+            Ok(image) => {
+                info!("Resource: using {} for entry 0x{:02x}", path.display(), id);
+                Some(image)
+            }
+            Err(e) => {
+                warn!("Resource: ignoring {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Checks after each load that the loaded entries do not share memory,
+    /// logging the entries that do
+    pub fn set_check_overlaps(&mut self, check_overlaps: bool) {
+        self.check_overlaps = check_overlaps;
+    }
 
-        if !(parts::GAME_PART_FIRST..=parts::GAME_PART_LAST).contains(&part_id) {
-            panic!("Unknown part: {:x}", part_id);
+    /// Unpack every entry of the memlist up front, so that parts are set up
+    /// from memory instead of reading and unpacking the banks again
+    pub fn preload(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let mut total = 0;
+        for (id, entry) in self.mem_list.iter().enumerate() {
+            if entry.state == MemEntryState::EndOfMemList || entry.bank_id == 0 {
+                continue;
+            }
+            let bank = Resource::read_bank(&self.bank_files, entry, &self.asset_platform)?;
+            if let Err(e) = check_unpacked_size(id, entry, bank.unpacked_size()?) {
+                warn!("Resource: not preloading: {}", e);
+                continue;
+            }
+            let mut data = vec![0; entry.size];
+            bank.read_into(&mut data)?;
+            total += data.len();
+            self.preloaded.insert(id, data);
         }
+        info!(
+            "Preloaded {} entries ({} bytes) in {:?}",
+            self.preloaded.len(),
+            total,
+            start.elapsed()
+        );
+        Ok(())
+    }
 
-        let index = (part_id - parts::GAME_PART_FIRST) as usize;
-        debug!("Part id index: {}", index);
+    pub fn setup_part(&mut self, part_id: u16) -> std::result::Result<(), ResourceError> {
+        debug!("setup_part: {}", part_id);
+        if part_id == self.current_part_id {
+            return Ok(());
+        }
+        let start = Instant::now();
+
+        let part = match parts::Part::for_id(part_id) {
+            Some(part) => part,
+            None => return Err(ResourceError::UnknownPart(part_id)),
+        };
 
-        let palette_index = parts::PARTS[index].palette;
-        let code_index = parts::PARTS[index].code;
+        let palette_index = part.palette;
+        let code_index = part.code;
         debug!("Code index: {}", code_index);
-        let video_cinematic_index = parts::PARTS[index].video1;
-        let video2_index = parts::PARTS[index].video2;
+        let video_cinematic_index = part.video1;
+        let video2_index = part.video2;
 
         self.invalidate_all();
 
@@ -253,23 +632,37 @@ impl Resource {
             self.mem_list[video2_index].state = MemEntryState::LoadMe;
         }
 
-        self.load_marked_as_needed();
+        self.load_marked_as_needed()?;
 
         self.seg_palettes = self.mem_list[palette_index].buf_ptr;
+        self.seg_palettes_len = self.mem_list[palette_index].size;
         self.seg_bytecode = self.mem_list[code_index].buf_ptr;
+        self.seg_bytecode_len = self.mem_list[code_index].size;
         debug!(
             "seg_bytecode: 0x{:04x} value: {:x}",
             self.seg_bytecode, self.memory[self.seg_bytecode]
         );
         self.seg_cinematic = self.mem_list[video_cinematic_index].buf_ptr;
+        self.seg_cinematic_len = self.mem_list[video_cinematic_index].size;
 
-        if let Some(video2_index) = video2_index {
-            self.seg_video2 = self.mem_list[video2_index].buf_ptr;
+        match video2_index {
+            Some(video2_index) => {
+                self.seg_video2 = self.mem_list[video2_index].buf_ptr;
+                self.seg_video2_len = self.mem_list[video2_index].size;
+            }
+            None => self.seg_video2_len = 0,
         }
 
         self.current_part_id = part_id;
 
         self.script_bak_ptr = self.script_cur_ptr;
+        info!("Set up part 0x{:04x} in {:?}", part_id, start.elapsed());
+        Ok(())
+    }
+
+    /// Bytecode of the current part
+    pub fn bytecode(&self) -> &[u8] {
+        &self.memory[self.seg_bytecode..self.seg_bytecode + self.seg_bytecode_len]
     }
 
     pub fn read_byte(&mut self, index: usize) -> u8 {
@@ -287,15 +680,114 @@ impl Resource {
         self.script_cur_ptr = self.script_bak_ptr;
     }
 
-    pub fn load_memory_entry(&mut self, resource_id: u16) {
-        let resource_id = resource_id as usize;
-        let entry = &mut self.mem_list[resource_id];
+    pub fn load_memory_entry(
+        &mut self,
+        resource_id: u16,
+    ) -> std::result::Result<(), ResourceError> {
+        let entry = self
+            .mem_list
+            .get_mut(resource_id as usize)
+            .ok_or(ResourceError::UnknownResource(resource_id))?;
         if entry.state == MemEntryState::NotNeeded {
             entry.state = MemEntryState::LoadMe;
-            self.load_marked_as_needed();
+            self.load_marked_as_needed()?;
+        }
+        Ok(())
+    }
+
+    /// Reads an entry from its bank and unpacks it, without loading it into
+    /// memory
+    pub fn decompress_entry(
+        &self,
+        resource_id: u16,
+    ) -> std::result::Result<Vec<u8>, ResourceError> {
+        let id = resource_id as usize;
+        let entry = self
+            .mem_list
+            .get(id)
+            .ok_or(ResourceError::UnknownResource(resource_id))?;
+        if let Some(data) = self.preloaded.get(&id) {
+            return Ok(data.clone());
+        }
+        if entry.bank_id == 0 {
+            return Err(ResourceError::UnknownResource(resource_id));
+        }
+        let bank = Resource::read_bank(&self.bank_files, entry, &self.asset_platform)?;
+        let data = bank.data()?;
+        check_unpacked_size(id, entry, data.len())?;
+        Ok(data)
+    }
+
+    /// Resources loaded by the scripts since the part was set up, in the
+    /// order they are laid out in memory
+    pub fn loaded_resources(&self) -> Vec<u16> {
+        let mut loaded: Vec<(usize, u16)> = self
+            .mem_list
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                entry.state == MemEntryState::Loaded && entry.buf_ptr >= self.script_bak_ptr
+            })
+            .map(|(index, entry)| (entry.buf_ptr, index as u16))
+            .collect();
+        loaded.sort_unstable();
+        loaded.into_iter().map(|(_, index)| index).collect()
+    }
+
+    /// Loads a music resource together with the sound resources used as its
+    /// instruments, for inspecting modules outside of the game scripts
+    pub fn load_music_entry(&mut self, resource_id: u16) -> std::result::Result<(), ResourceError> {
+        self.load_memory_entry(resource_id)?;
+        let entry = &self.mem_list[resource_id as usize];
+        if entry.state != MemEntryState::Loaded || entry.entry_type != EntryType::Music {
+            return Ok(());
+        }
+        let buf_ptr = entry.buf_ptr;
+        for i in 0..15 {
+            let instrument_id = BigEndian::read_u16(&self.memory[buf_ptr + 2 + i * 4..]);
+            if instrument_id != 0 {
+                self.load_memory_entry(instrument_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let types = [
+            EntryType::Sound,
+            EntryType::Music,
+            EntryType::PolyAnim,
+            EntryType::Palette,
+            EntryType::Bytecode,
+            EntryType::PolyCinematic,
+        ];
+        let loaded = types
+            .iter()
+            .map(|&entry_type| {
+                let count = self
+                    .mem_list
+                    .iter()
+                    .filter(|e| e.entry_type == entry_type && e.state == MemEntryState::Loaded)
+                    .count();
+                (entry_type, count)
+            })
+            .collect();
+        MemoryUsage {
+            used: self.script_cur_ptr,
+            limit: self.vid_bak_ptr,
+            loaded,
         }
     }
 
+    /// Decodes the background bitmap loaded from a PolyAnim entry into one
+    /// palette index per pixel. The PC and Amiga data holds the four bit
+    /// planes one after the other, 8000 bytes each. The Atari ST data is in
+    /// the ST screen layout, where every 16 pixels are stored as four
+    /// big-endian words, one per plane, so the high bytes of the words give
+    /// the first 8 pixels and the low bytes the next 8. Both layouts use the
+    /// last plane as the highest bit, as in the reference decoders, and the
+    /// palettes are stored the same way on all platforms, see
+    /// `Palette::from_bytes`.
     pub fn video_page_data(&self) -> Vec<u8> {
         debug!("video_page_data()");
         let mut buf = Vec::new();
@@ -341,22 +833,76 @@ impl Resource {
         buf
     }
 
+    /// Background to copy to the page after a bitmap was loaded, from the
+    /// replacement image if there is one
+    pub fn background_page_data(&mut self) -> Vec<u8> {
+        match self.background_override.take() {
+            Some(image) => self.indexed_background(&image),
+            None => self.video_page_data(),
+        }
+    }
+
+    /// Converts a replacement image to palette indices. Indexed images with
+    /// up to 16 colors are used as is. Other images are mapped to the part
+    /// palette that matches their colors best, since the palette the
+    /// scripts select for the background is not known yet when it is loaded.
+    fn indexed_background(&self, image: &png::Image) -> Vec<u8> {
+        if let Some(indices) = &image.indices {
+            if indices.iter().all(|&i| (i as usize) < NUM_COLORS) {
+                return indices.clone();
+            }
+        }
+        let mut histogram: HashMap<(u8, u8, u8), u32> = HashMap::new();
+        for &color in &image.pixels {
+            *histogram.entry(color).or_insert(0) += 1;
+        }
+        let palettes = (0..NUM_PALETTES).filter_map(|i| {
+            let start = self.seg_palettes + i * PALETTE_SIZE;
+            self.memory
+                .get(start..start + PALETTE_SIZE)
+                .map(Palette::from_bytes)
+        });
+        let error = |palette: &Palette| {
+            histogram
+                .iter()
+                .map(|(&color, &count)| palette.nearest(color).1 as u64 * count as u64)
+                .sum::<u64>()
+        };
+        let palette = palettes
+            .min_by_key(|palette| error(palette))
+            .unwrap_or_else(Palette::grayscale);
+        image
+            .pixels
+            .iter()
+            .map(|&color| palette.nearest(color).0)
+            .collect()
+    }
+
+    /// The sound `resource_id` ready to play, `None` if it isn't a loaded
+    /// entry or its header gives more samples than the entry holds
     pub fn get_entry_mixer_chunk(&self, resource_id: u16) -> Option<MixerChunk> {
-        let resource_id = resource_id as usize;
-        let entry = &self.mem_list[resource_id];
+        let entry = self.mem_list.get(resource_id as usize)?;
 
         if entry.state != MemEntryState::Loaded {
             return None;
         }
         debug!("sound buf_ptr {}", entry.buf_ptr);
-        let header = &self.memory[entry.buf_ptr..entry.buf_ptr + 8];
-        let data = &self.memory[entry.buf_ptr + 8..];
-        let len = (BigEndian::read_u16(header) * 2) as usize;
-        let loop_len = (BigEndian::read_u16(&header[2..]) * 2) as usize;
+        let sound = self
+            .memory
+            .get(entry.buf_ptr..entry.buf_ptr + entry.size)
+            .filter(|sound| sound.len() >= 8)?;
+        let len = BigEndian::read_u16(sound) as usize * 2;
+        let loop_len = BigEndian::read_u16(&sound[2..]) as usize * 2;
 
         // When looping, buffer length is larger than len
-        let data_len = len + loop_len;
-        Some(MixerChunk::new(&data[0..data_len], len, loop_len))
+        let data = sound[8..].get(..len + loop_len);
+        if data.is_none() {
+            warn!(
+                "Sound 0x{:02x} is shorter than its header says",
+                resource_id
+            );
+        }
+        Some(MixerChunk::new(data?, len, loop_len))
     }
 
     pub fn load_sfx_module(
@@ -364,15 +910,28 @@ impl Resource {
         resource_id: u16,
         delay: &mut u16,
         pos: u8,
-    ) -> Result<Option<SfxModule>> {
+    ) -> std::result::Result<Option<SfxModule>, ResourceError> {
         debug!("load_sfx_module(0x{:x}, {}, {}", resource_id, delay, pos);
-        let resource_id = resource_id as usize;
-        let entry = &self.mem_list[resource_id];
+        let entry = self
+            .mem_list
+            .get(resource_id as usize)
+            .ok_or(ResourceError::UnknownResource(resource_id))?;
 
         if entry.state != MemEntryState::Loaded || entry.entry_type != EntryType::Music {
             return Ok(None);
         }
-        let data = &self.memory[entry.buf_ptr..];
+        // The header holds the delay, the instruments and the order table,
+        // the patterns follow
+        let data = self
+            .memory
+            .get(entry.buf_ptr..entry.buf_ptr + entry.size)
+            .filter(|data| data.len() >= 0xc0)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Music entry 0x{:02x} is too short", resource_id),
+                )
+            })?;
         let cur_order = pos;
         let num_order = BigEndian::read_u16(&data[0x3e..]) as u8;
         debug!(
@@ -386,54 +945,62 @@ impl Resource {
         if *delay == 0 {
             *delay = BigEndian::read_u16(data)
         }
-        let data = &data[0xc0..entry.size];
         let mut samples = Vec::new();
         for i in 0..15 {
-            let buf = &self.memory[entry.buf_ptr + 2 + i * 4..];
-            samples.push(self.prepare_instrument(buf)?);
+            samples.push(self.prepare_instrument(&data[2 + i * 4..])?);
         }
+        let data = &data[0xc0..];
 
         let module = SfxModule::new(data.into(), cur_order, num_order, order_table, samples);
         Ok(Some(module))
     }
 
-    fn prepare_instrument(&self, buf: &[u8]) -> Result<Option<SfxInstrument>> {
+    fn prepare_instrument(
+        &self,
+        buf: &[u8],
+    ) -> std::result::Result<Option<SfxInstrument>, ResourceError> {
         let mut buffer = Cursor::new(&buf);
         let resource_id = buffer.read_u16::<BigEndian>()?;
         if resource_id == 0 {
             return Ok(None);
         }
         let volume = buffer.read_u16::<BigEndian>()?;
-        let entry = &self.mem_list[resource_id as usize];
-        if entry.state != MemEntryState::Loaded || entry.entry_type != EntryType::Sound {
-            panic!("Error loading instrument 0x{:x}", resource_id);
-        }
-        let mut data = self.memory[entry.buf_ptr..entry.buf_ptr + entry.size].to_vec();
+        let entry = match self.mem_list.get(resource_id as usize) {
+            Some(entry)
+                if entry.state == MemEntryState::Loaded && entry.entry_type == EntryType::Sound =>
+            {
+                entry
+            }
+            _ => return Err(ResourceError::MissingInstrument(resource_id)),
+        };
+        let mut data = self
+            .memory
+            .get(entry.buf_ptr..entry.buf_ptr + entry.size)
+            .ok_or(ResourceError::MissingInstrument(resource_id))?
+            .to_vec();
         if data.is_empty() {
             return Ok(None);
         }
         for item in data.iter_mut().take(12).skip(8) {
             *item = 0;
         }
-        Ok(Some(SfxInstrument::new(data, volume)))
+        Ok(Some(SfxInstrument::new(resource_id, data, volume)))
     }
 
     fn read_bank(
-        asset_path: &Path,
+        bank_files: &BankFiles,
         mem_entry: &MemEntry,
         asset_platform: &AssetPlatform,
     ) -> std::io::Result<Bank> {
-        let file_name = match asset_platform {
-            AssetPlatform::PC => asset_path.join(format!("Bank{:02x}", mem_entry.bank_id)),
-            AssetPlatform::Amiga => asset_path.join(format!("bank{:02X}", mem_entry.bank_id)),
-            AssetPlatform::AtariST => asset_path.join(format!("BANK{:02X}", mem_entry.bank_id)),
-        };
+        let file_name = bank_files.resolve(asset_platform, mem_entry.bank_id);
         debug!("Reading bank: {}", file_name.to_string_lossy());
-        let mut file = File::open(file_name)?;
-        file.seek(SeekFrom::Start(mem_entry.bank_offset as u64))?;
+        let error = |e| path_error(e, &file_name, asset_platform);
+        let mut file = File::open(&file_name).map_err(error)?;
+        file.seek(SeekFrom::Start(mem_entry.bank_offset as u64))
+            .map_err(error)?;
 
         let mut data = vec![0; mem_entry.packed_size];
-        file.read_exact(&mut data)?;
+        file.read_exact(&mut data).map_err(error)?;
         let bank = if mem_entry.packed_size == mem_entry.size {
             Bank::Uncompressed(data)
         } else {
@@ -450,14 +1017,18 @@ impl Resource {
         self.script_cur_ptr = 0;
     }
 
-    fn load_marked_as_needed(&mut self) {
-        let to_load: Vec<&mut MemEntry> = self
+    fn load_marked_as_needed(&mut self) -> std::result::Result<(), ResourceError> {
+        // Video data is copied to a page and marked not needed right away,
+        // so its ranges are kept here for the overlap check
+        let mut loaded_video = Vec::new();
+        let to_load: Vec<(usize, &mut MemEntry)> = self
             .mem_list
             .iter_mut()
-            .filter(|e| e.state == MemEntryState::LoadMe)
+            .enumerate()
+            .filter(|(_, e)| e.state == MemEntryState::LoadMe)
             .collect();
 
-        for entry in to_load {
+        for (id, entry) in to_load {
             let load_destination = match entry.entry_type {
                 EntryType::PolyAnim => self.vid_cur_ptr,
                 _ => {
@@ -477,16 +1048,28 @@ impl Resource {
                 continue;
             }
 
-            let bank = Resource::read_bank(&self.asset_path, entry, &self.asset_platform)
-                .expect("Could not read bank");
-            debug!("read_bank() rank_num: {} packed_size: 0x{:x} size: 0x{:x} type={:?} pos={:x} bank_id={:x}", entry.rank_num, entry.packed_size, entry.size, entry.entry_type, entry.bank_offset, entry.bank_id);
+            if let (EntryType::PolyAnim, Some(dir)) = (entry.entry_type, &self.background_dir) {
+                if let Some(image) = Resource::load_background_override(dir, id) {
+                    self.background_override = Some(image);
+                    self.copy_vid_ptr = true;
+                    entry.state = MemEntryState::NotNeeded;
+                    continue;
+                }
+            }
 
             let load_destination_end = load_destination + entry.size;
             let dst = &mut self.memory[load_destination..load_destination_end];
-            let data = bank.data();
-            assert!(data.len() == entry.size);
-            dst.copy_from_slice(&data);
+            if let Some(data) = self.preloaded.get(&id) {
+                dst.copy_from_slice(data);
+            } else {
+                let bank = Resource::read_bank(&self.bank_files, entry, &self.asset_platform)?;
+                debug!("read_bank() rank_num: {} packed_size: 0x{:x} size: 0x{:x} type={:?} pos={:x} bank_id={:x}", entry.rank_num, entry.packed_size, entry.size, entry.entry_type, entry.bank_offset, entry.bank_id);
+
+                check_unpacked_size(id, entry, bank.unpacked_size()?)?;
+                bank.read_into(dst)?;
+            }
             if let EntryType::PolyAnim = entry.entry_type {
+                loaded_video.push((id, load_destination..load_destination_end));
                 self.copy_vid_ptr = true;
                 entry.state = MemEntryState::NotNeeded;
             } else {
@@ -495,5 +1078,165 @@ impl Resource {
                 self.script_cur_ptr += entry.size;
             }
         }
+        if self.check_overlaps {
+            self.report_overlaps(loaded_video);
+        }
+        Ok(())
+    }
+
+    /// Logs loaded entries whose memory overlaps another loaded entry, or
+    /// reaches into the area reserved for video data
+    fn report_overlaps(&self, loaded_video: Vec<(usize, Range<usize>)>) {
+        let mut ranges: Vec<(usize, Range<usize>)> = self
+            .mem_list
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.state == MemEntryState::Loaded)
+            .map(|(id, e)| (id, e.buf_ptr..e.buf_ptr + e.size))
+            .collect();
+        for (id, range) in ranges.iter() {
+            if range.end > self.vid_bak_ptr {
+                warn!(
+                    "Resource: entry 0x{:02x} at 0x{:x}..0x{:x} reaches into video data at 0x{:x}",
+                    id, range.start, range.end, self.vid_bak_ptr
+                );
+            }
+        }
+        ranges.extend(loaded_video);
+        ranges.sort_by_key(|(_, range)| range.start);
+        for (i, (id, range)) in ranges.iter().enumerate() {
+            for (other_id, other) in ranges[i + 1..].iter() {
+                if other.start >= range.end {
+                    break;
+                }
+                warn!(
+                    "Resource: entry 0x{:02x} at 0x{:x}..0x{:x} overlaps entry 0x{:02x} at 0x{:x}..0x{:x}",
+                    id, range.start, range.end, other_id, other.start, other.end
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A loaded entry of `entry_type` holding `data` at the start of memory
+    fn resource_with(entry_type: EntryType, data: &[u8]) -> Resource {
+        let entry = MemEntry {
+            state: MemEntryState::Loaded,
+            entry_type,
+            buf_ptr: 0,
+            _unk4: 0,
+            rank_num: 0,
+            bank_id: 0,
+            bank_offset: 0,
+            _unkc: 0,
+            packed_size: data.len(),
+            _unk10: 0,
+            size: data.len(),
+        };
+        let mut resource = Resource::new(vec![entry], PathBuf::from("missing"), AssetPlatform::PC);
+        resource.memory[..data.len()].copy_from_slice(data);
+        resource
+    }
+
+    #[test]
+    fn bad_sound_ids_and_headers_are_not_played() {
+        // 2 samples, no loop
+        let sound = [0, 1, 0, 0, 0, 0, 0, 0, 0x10, 0x20];
+        let resource = resource_with(EntryType::Sound, &sound);
+        assert!(resource.get_entry_mixer_chunk(0).is_some());
+        assert!(resource.get_entry_mixer_chunk(1).is_none());
+        assert!(resource.get_entry_mixer_chunk(0xffff).is_none());
+
+        let resource = resource_with(EntryType::Sound, &sound[..9]);
+        assert!(resource.get_entry_mixer_chunk(0).is_none());
+        let resource = resource_with(EntryType::Sound, &sound[..4]);
+        assert!(resource.get_entry_mixer_chunk(0).is_none());
+    }
+
+    #[test]
+    fn bad_music_ids_and_short_modules_are_errors() {
+        let mut module = vec![0; 0xc0 + 16];
+        module[0x3f] = 1;
+        let resource = resource_with(EntryType::Music, &module);
+        let mut delay = 0;
+        assert!(resource
+            .load_sfx_module(0, &mut delay, 0)
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            resource.load_sfx_module(0x99, &mut delay, 0),
+            Err(ResourceError::UnknownResource(0x99))
+        ));
+
+        let resource = resource_with(EntryType::Music, &module[..0xbf]);
+        match resource.load_sfx_module(0, &mut delay, 0) {
+            Err(ResourceError::Io(e)) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+            _ => panic!("Expected an error for a truncated module"),
+        }
+    }
+
+    /// Color of each pixel of the test bitmap
+    fn color(x: usize, y: usize) -> u8 {
+        ((x / 3 + y * 5) % NUM_COLORS) as u8
+    }
+
+    fn decode(platform: AssetPlatform, bitmap: &[u8]) -> Vec<u8> {
+        let mut resource = Resource::new(Vec::new(), PathBuf::from("missing"), platform);
+        let start = resource.vid_cur_ptr;
+        resource.memory[start..start + bitmap.len()].copy_from_slice(bitmap);
+        resource.video_page_data()
+    }
+
+    fn expected() -> Vec<u8> {
+        (0..200)
+            .flat_map(|y| (0..320).map(move |x| color(x, y)))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_pc_and_amiga_bit_planes() {
+        // Plane k is 8000 bytes at 8000 * k, 8 pixels per byte from the
+        // most significant bit
+        let mut bitmap = vec![0; 32000];
+        for y in 0..200 {
+            for x in 0..320 {
+                for plane in 0..4 {
+                    if color(x, y) & (1 << plane) != 0 {
+                        bitmap[plane * 8000 + y * 40 + x / 8] |= 0x80 >> (x % 8);
+                    }
+                }
+            }
+        }
+        assert_eq!(decode(AssetPlatform::PC, &bitmap), expected());
+        assert_eq!(decode(AssetPlatform::Amiga, &bitmap), expected());
+    }
+
+    #[test]
+    fn decodes_atari_st_screen_layout() {
+        // Each group of 16 pixels is 4 big-endian words, the word of plane
+        // k at 2 * k, pixels from the most significant bit
+        let mut bitmap = vec![0; 32000];
+        for y in 0..200 {
+            for x in 0..320 {
+                for plane in 0..4 {
+                    if color(x, y) & (1 << plane) != 0 {
+                        let word = y * 160 + x / 16 * 8 + plane * 2;
+                        let bit = 15 - x % 16;
+                        bitmap[word + 1 - bit / 8] |= 1 << (bit % 8);
+                    }
+                }
+            }
+        }
+        assert_eq!(decode(AssetPlatform::AtariST, &bitmap), expected());
+
+        // Plane 0 and plane 3 set for the first pixel, plane 1 for the 9th
+        let mut bitmap = vec![0; 32000];
+        bitmap[..8].copy_from_slice(&[0x80, 0x00, 0x00, 0x80, 0x00, 0x00, 0x80, 0x00]);
+        let pixels = decode(AssetPlatform::AtariST, &bitmap);
+        assert_eq!(pixels[..10], [9, 0, 0, 0, 0, 0, 0, 0, 2, 0]);
     }
 }
@@ -0,0 +1,375 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::resource::AssetPlatform;
+
+/// Settings file read at startup, in the working directory
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Options kept between runs. They are stored as flat `key = value` lines,
+/// a subset of TOML, with the same names as the command line flags.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub asset_path: PathBuf,
+    pub platform: Option<AssetPlatform>,
+    pub scale: u32,
+    pub scanlines: bool,
+    pub crt: bool,
+    pub antialias: bool,
+    pub mirror: bool,
+    pub mirror_input: bool,
+    pub letterbox_color: (u8, u8, u8),
+    pub amiga_filter: bool,
+    pub echo: bool,
+    pub echo_delay: u32,
+    pub echo_feedback: f32,
+    pub audio_buffer: Option<u16>,
+    pub audio_16bit: bool,
+    pub mute: bool,
+    pub window_size: Option<(u32, u32)>,
+    pub window_position: Option<(i32, i32)>,
+    pub fullscreen: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            asset_path: PathBuf::from("data"),
+            platform: None,
+            scale: 1,
+            scanlines: false,
+            crt: false,
+            antialias: false,
+            mirror: false,
+            mirror_input: false,
+            letterbox_color: (0, 0, 0),
+            amiga_filter: false,
+            echo: false,
+            echo_delay: 150,
+            echo_feedback: 0.35,
+            audio_buffer: None,
+            audio_16bit: false,
+            mute: false,
+            window_size: None,
+            window_position: None,
+            fullscreen: false,
+        }
+    }
+}
+
+fn invalid(line: usize, message: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("line {}: {}", line + 1, message),
+    )
+}
+
+/// Reads a double-quoted string with `\\`, `\"` and `\n` escapes
+pub(crate) fn parse_string(value: &str) -> std::result::Result<String, String> {
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or_else(|| format!("Expected a quoted string, got {}", value))?;
+    let mut result = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some(c @ ('\\' | '"')) => result.push(c),
+                _ => return Err(format!("Unsupported escape in {}", value)),
+            },
+            '"' => return Err(format!("Unescaped quote in {}", value)),
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+fn quote(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+fn parse_bool(value: &str) -> std::result::Result<bool, String> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!("Expected true or false, got {}", value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> std::result::Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Expected a number, got {}", value))
+}
+
+/// Removes a comment, keeping `#` inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+pub fn parse_audio_buffer(s: &str) -> std::result::Result<u16, String> {
+    let samples: u16 = s.parse().map_err(|e| format!("{}", e))?;
+    if !samples.is_power_of_two() || !(64..=8192).contains(&samples) {
+        return Err(format!(
+            "Invalid audio buffer size {}, expected a power of two between 64 and 8192",
+            samples
+        ));
+    }
+    Ok(samples)
+}
+
+pub fn parse_echo_feedback(s: &str) -> std::result::Result<f32, String> {
+    let feedback: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=0.95).contains(&feedback) {
+        return Err(format!(
+            "Invalid echo feedback {}, expected a value between 0 and 0.95",
+            feedback
+        ));
+    }
+    Ok(feedback)
+}
+
+pub fn parse_color(s: &str) -> std::result::Result<(u8, u8, u8), String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid color {}, expected RRGGBB in hex", s));
+    }
+    let component = |i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok((component(0), component(2), component(4)))
+}
+
+/// Parses a window size given as `WIDTHxHEIGHT`
+fn parse_window_size(s: &str) -> std::result::Result<(u32, u32), String> {
+    let size = s
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)));
+    match size {
+        Some((w, h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err(format!("Invalid window size {}, expected WIDTHxHEIGHT", s)),
+    }
+}
+
+/// Parses a window position given as `X,Y`
+fn parse_window_position(s: &str) -> std::result::Result<(i32, i32), String> {
+    s.split_once(',')
+        .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)))
+        .ok_or_else(|| format!("Invalid window position {}, expected X,Y", s))
+}
+
+impl Settings {
+    /// Reads the settings in `path`, keeping the defaults for missing keys
+    pub fn load(path: &Path) -> Result<Settings> {
+        let text = fs::read_to_string(path)?;
+        Settings::parse(&text)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Like `load`, with the defaults if there is no file at `path`
+    pub fn load_or_default(path: &Path) -> Result<Settings> {
+        match Settings::load(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Settings::default()),
+            result => result,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_toml())
+    }
+
+    pub fn parse(text: &str) -> Result<Settings> {
+        let mut settings = Settings::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(n, format!("Expected key = value, got {}", line)))?;
+            settings
+                .set(key.trim(), value.trim())
+                .map_err(|e| invalid(n, e))?;
+        }
+        Ok(settings)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> std::result::Result<(), String> {
+        match key {
+            "asset_path" => self.asset_path = PathBuf::from(parse_string(value)?),
+            "platform" => self.platform = Some(parse_string(value)?.parse()?),
+            "scale" => {
+                let scale = parse_number(value)?;
+                if !(1..=4).contains(&scale) {
+                    return Err(format!("Invalid scale {}, expected 1 to 4", scale));
+                }
+                self.scale = scale;
+            }
+            "scanlines" => self.scanlines = parse_bool(value)?,
+            "crt" => self.crt = parse_bool(value)?,
+            "antialias" => self.antialias = parse_bool(value)?,
+            "mirror" => self.mirror = parse_bool(value)?,
+            "mirror_input" => self.mirror_input = parse_bool(value)?,
+            "letterbox_color" => self.letterbox_color = parse_color(&parse_string(value)?)?,
+            "amiga_filter" => self.amiga_filter = parse_bool(value)?,
+            "echo" => self.echo = parse_bool(value)?,
+            "echo_delay" => {
+                let delay = parse_number(value)?;
+                if !(1..=1000).contains(&delay) {
+                    return Err(format!("Invalid echo delay {}, expected 1 to 1000", delay));
+                }
+                self.echo_delay = delay;
+            }
+            "echo_feedback" => self.echo_feedback = parse_echo_feedback(value)?,
+            "audio_buffer" => self.audio_buffer = Some(parse_audio_buffer(value)?),
+            "audio_16bit" => self.audio_16bit = parse_bool(value)?,
+            "mute" => self.mute = parse_bool(value)?,
+            "window_size" => self.window_size = Some(parse_window_size(&parse_string(value)?)?),
+            "window_position" => {
+                self.window_position = Some(parse_window_position(&parse_string(value)?)?)
+            }
+            "fullscreen" => self.fullscreen = parse_bool(value)?,
+            _ => warn!("Ignoring unknown setting {}", key),
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        let path = self.asset_path.to_string_lossy();
+        writeln!(out, "asset_path = {}", quote(&path)).unwrap();
+        if let Some(platform) = &self.platform {
+            let name = match platform {
+                AssetPlatform::PC => "pc",
+                AssetPlatform::Amiga => "amiga",
+                AssetPlatform::AtariST => "atari",
+            };
+            writeln!(out, "platform = {}", quote(name)).unwrap();
+        }
+        writeln!(out, "scale = {}", self.scale).unwrap();
+        writeln!(out, "scanlines = {}", self.scanlines).unwrap();
+        writeln!(out, "crt = {}", self.crt).unwrap();
+        writeln!(out, "antialias = {}", self.antialias).unwrap();
+        writeln!(out, "mirror = {}", self.mirror).unwrap();
+        writeln!(out, "mirror_input = {}", self.mirror_input).unwrap();
+        let (r, g, b) = self.letterbox_color;
+        writeln!(out, "letterbox_color = \"{:02x}{:02x}{:02x}\"", r, g, b).unwrap();
+        writeln!(out, "amiga_filter = {}", self.amiga_filter).unwrap();
+        writeln!(out, "echo = {}", self.echo).unwrap();
+        writeln!(out, "echo_delay = {}", self.echo_delay).unwrap();
+        writeln!(out, "echo_feedback = {}", self.echo_feedback).unwrap();
+        if let Some(samples) = self.audio_buffer {
+            writeln!(out, "audio_buffer = {}", samples).unwrap();
+        }
+        writeln!(out, "audio_16bit = {}", self.audio_16bit).unwrap();
+        writeln!(out, "mute = {}", self.mute).unwrap();
+        if let Some((width, height)) = self.window_size {
+            writeln!(out, "window_size = \"{}x{}\"", width, height).unwrap();
+        }
+        if let Some((x, y)) = self.window_position {
+            writeln!(out, "window_position = \"{},{}\"", x, y).unwrap();
+        }
+        writeln!(out, "fullscreen = {}", self.fullscreen).unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_strings_with_escapes() {
+        assert_eq!(parse_string(r#""data""#).unwrap(), "data");
+        assert_eq!(
+            parse_string(r#""a \"b\" c\\d\ne""#).unwrap(),
+            "a \"b\" c\\d\ne"
+        );
+        assert!(parse_string("data").is_err());
+        assert!(parse_string(r#""a"b""#).is_err());
+        assert!(parse_string(r#""\t""#).is_err());
+        let s = "C:\\games\\\"aw\"\n";
+        assert_eq!(parse_string(&quote(s)).unwrap(), s);
+    }
+
+    #[test]
+    fn parses_settings() {
+        let settings = Settings::parse(
+            "# Comment\n\
+             asset_path = \"my # data\" # trailing comment\n\
+             scale = 3\n\
+             scanlines = true\n\
+             mute = false\n\
+             letterbox_color = \"#10ff80\"\n\
+             window_position = \"-5, 20\"\n\
+             future_option = 1\n",
+        )
+        .unwrap();
+        assert_eq!(settings.asset_path, PathBuf::from("my # data"));
+        assert_eq!(settings.scale, 3);
+        assert!(settings.scanlines);
+        assert!(!settings.mute);
+        assert_eq!(settings.letterbox_color, (0x10, 0xff, 0x80));
+        assert_eq!(settings.window_position, Some((-5, 20)));
+        assert_eq!(settings.crt, Settings::default().crt);
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        for text in &[
+            "scanlines = yes",
+            "scale = 5",
+            "letterbox_color = \"fff\"",
+            "audio_buffer = 100",
+            "asset_path = data",
+            "no value",
+        ] {
+            let error = Settings::parse(text).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{}", text);
+        }
+        let error = Settings::parse("scale = 2\nmute = 1").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"));
+    }
+
+    #[test]
+    fn saved_settings_parse_back() {
+        let settings = Settings {
+            asset_path: PathBuf::from("a \"quoted\" path"),
+            platform: Some(AssetPlatform::Amiga),
+            scale: 2,
+            crt: true,
+            letterbox_color: (1, 2, 3),
+            echo_feedback: 0.5,
+            audio_buffer: Some(512),
+            window_size: Some((640, 400)),
+            window_position: Some((10, -10)),
+            fullscreen: true,
+            ..Settings::default()
+        };
+        assert_eq!(Settings::parse(&settings.to_toml()).unwrap(), settings);
+    }
+}
@@ -1,21 +1,92 @@
-use std::io::{Cursor, Result};
-use std::sync::mpsc::{sync_channel, Receiver};
-use std::sync::{Arc, RwLock};
+use std::fmt;
+use std::io::{Cursor, Error, ErrorKind, Result, Write};
+use std::sync::{Arc, Mutex, RwLock};
 
-use byteorder::{BigEndian, ReadBytesExt};
-use log::{debug, error, trace};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use log::{debug, error, trace, warn};
 use timer::{Guard, Timer};
 
 use crate::mixer::{MixerAudio, MixerChunk};
+use crate::sync;
+
+/// Tempo multipliers selectable at runtime, the delay between events is
+/// divided by the multiplier
+const TEMPO_STEPS: [f32; 7] = [0.5, 0.625, 0.75, 1.0, 1.25, 1.5, 2.0];
+const TEMPO_NORMAL: usize = 3;
 
 pub struct SfxInstrument {
+    resource_id: u16,
     data: Vec<u8>,
     volume: u16,
 }
 
 impl SfxInstrument {
-    pub fn new(data: Vec<u8>, volume: u16) -> SfxInstrument {
-        SfxInstrument { data, volume }
+    pub fn new(resource_id: u16, data: Vec<u8>, volume: u16) -> SfxInstrument {
+        SfxInstrument {
+            resource_id,
+            data,
+            volume,
+        }
+    }
+}
+
+/// A decoded pattern cell, `note2` holds the sample index in the high
+/// nibble followed by the effect and its parameter
+#[derive(Debug, PartialEq)]
+pub enum SfxNote {
+    Empty,
+    Stop,
+    Mark(u16),
+    Note {
+        note1: u16,
+        note2: u16,
+        sample_index: usize,
+        effect: u8,
+        param: u8,
+    },
+}
+
+impl SfxNote {
+    pub fn decode(note1: u16, note2: u16) -> SfxNote {
+        match note1 {
+            0xfffd => SfxNote::Mark(note2),
+            0xfffe => SfxNote::Stop,
+            _ => {
+                let sample_index = ((note2 & 0xf000) >> 12) as usize;
+                if sample_index == 0 {
+                    SfxNote::Empty
+                } else {
+                    SfxNote::Note {
+                        note1,
+                        note2,
+                        sample_index,
+                        effect: ((note2 & 0x0f00) >> 8) as u8,
+                        param: (note2 & 0xff) as u8,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for SfxNote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SfxNote::Empty => write!(f, "---"),
+            SfxNote::Stop => write!(f, "stop"),
+            SfxNote::Mark(value) => write!(f, "mark 0x{:04x}", value),
+            SfxNote::Note {
+                note1,
+                sample_index,
+                effect,
+                param,
+                ..
+            } => write!(
+                f,
+                "{:04x} s{:02} e{:x} {:02x}",
+                note1, sample_index, effect, param
+            ),
+        }
     }
 }
 
@@ -26,6 +97,8 @@ pub struct SfxModule {
     num_order: u8,
     order_table: [u8; 0x80],
     samples: Vec<Option<SfxInstrument>>,
+    /// Set once the last order has been played
+    finished: bool,
 }
 
 impl SfxModule {
@@ -36,6 +109,14 @@ impl SfxModule {
         order_table: [u8; 0x80],
         samples: Vec<Option<SfxInstrument>>,
     ) -> SfxModule {
+        if num_order as usize > order_table.len() {
+            warn!(
+                "Music module has {} orders, only {} fit in the order table",
+                num_order,
+                order_table.len()
+            );
+        }
+        let num_order = num_order.min(order_table.len() as u8);
         SfxModule {
             data,
             cur_pos: 0,
@@ -43,7 +124,86 @@ impl SfxModule {
             num_order,
             order_table,
             samples,
+            finished: false,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Range in `data` of the row at the current position, with the four
+    /// channel cells. `None` if the order or the row is past the module data.
+    fn row_range(&self) -> Option<std::ops::Range<usize>> {
+        if self.cur_order >= self.num_order {
+            return None;
+        }
+        let pattern = *self.order_table.get(self.cur_order as usize)? as usize;
+        let start = pattern * 1024 + self.cur_pos;
+        let end = start + 4 * 4;
+        if end > self.data.len() {
+            return None;
+        }
+        Some(start..end)
+    }
+
+    /// Writes the order table, instruments and the decoded pattern of each
+    /// order as text
+    pub fn write_structure<W: Write>(&self, out: &mut W) -> Result<()> {
+        writeln!(out, "num_order: {}", self.num_order)?;
+        let orders: Vec<String> = self.order_table[..self.num_order as usize]
+            .iter()
+            .map(|o| format!("{:02x}", o))
+            .collect();
+        writeln!(out, "order_table: {}", orders.join(" "))?;
+        writeln!(out, "instruments:")?;
+        for (i, sample) in self.samples.iter().enumerate() {
+            match sample {
+                Some(sample) => writeln!(
+                    out,
+                    "  s{:02}: resource 0x{:02x} volume {} length {}",
+                    i + 1,
+                    sample.resource_id,
+                    sample.volume,
+                    sample.data.len()
+                )?,
+                None => writeln!(out, "  s{:02}: -", i + 1)?,
+            }
+        }
+        for order in 0..self.num_order as usize {
+            let pattern = self.order_table[order] as usize;
+            writeln!(out, "order {:02x} pattern {:02x}", order, pattern)?;
+            for row in 0..1024 / 16 {
+                let mut line = format!("  {:02}", row);
+                for ch in 0..4 {
+                    let start = pattern * 1024 + row * 16 + ch * 4;
+                    let cell = match self.data.get(start..start + 4) {
+                        Some(cell) => {
+                            let note1 = BigEndian::read_u16(cell);
+                            let note2 = BigEndian::read_u16(&cell[2..]);
+                            SfxNote::decode(note1, note2).to_string()
+                        }
+                        None => "<truncated>".to_string(),
+                    };
+                    line.push_str(&format!(" | {:<14}", cell));
+                }
+                writeln!(out, "{}", line.trim_end())?;
+            }
         }
+        Ok(())
+    }
+
+    /// Continue playback from the start of `order`, wrapping around at the
+    /// ends of the order table
+    pub fn seek(&mut self, order: i16) {
+        let num_order = self.num_order.max(1) as i16;
+        self.cur_order = order.rem_euclid(num_order) as u8;
+        self.cur_pos = 0;
+        self.finished = false;
+        debug!(
+            "Music order {}/{} (pattern {})",
+            self.cur_order, self.num_order, self.order_table[self.cur_order as usize]
+        );
     }
 }
 
@@ -92,10 +252,16 @@ impl SfxPattern {
             }
         }
         let sample_start = 8;
+        let sample_buffer = sample.data.get(sample_start..).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Instrument 0x{:x} is too short", sample.resource_id),
+            )
+        })?;
         Ok(SfxPattern {
             note1,
             note2,
-            sample_buffer: sample.data[sample_start..].to_vec(),
+            sample_buffer: sample_buffer.to_vec(),
             sample_len,
             loop_pos,
             loop_len,
@@ -104,20 +270,79 @@ impl SfxPattern {
     }
 }
 
+/// The module currently being played, kept so the timer can be rescheduled
+struct Playback {
+    sfx_module: Arc<RwLock<SfxModule>>,
+    mixer: MixerAudio,
+    /// Last mark reached by the music, until it is taken by the VM
+    mark: Arc<Mutex<Option<i16>>>,
+}
+
+/// Plays music modules. By default a timer thread steps through the module,
+/// when frame synced the VM steps it instead by calling `advance` once per
+/// frame, so the music stays in step with the game clock.
 pub struct SfxPlayer {
     delay: i64,
+    tempo_step: usize,
     sfx_module: Option<SfxModule>,
+    playback: Option<Playback>,
     timer: Timer,
     timer_guard: Option<Guard>,
+    frame_synced: bool,
+    elapsed: i64,
+}
+
+impl Default for SfxPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SfxPlayer {
     pub fn new() -> SfxPlayer {
         SfxPlayer {
             delay: 0,
+            tempo_step: TEMPO_NORMAL,
             sfx_module: None,
+            playback: None,
             timer: Timer::new(),
             timer_guard: None,
+            frame_synced: false,
+            elapsed: 0,
+        }
+    }
+
+    pub fn set_frame_synced(&mut self, frame_synced: bool) {
+        self.frame_synced = frame_synced;
+        // A module already playing switches between the timer and `advance`
+        if frame_synced {
+            self.timer_guard.take();
+        } else {
+            self.resume();
+        }
+    }
+
+    pub fn tempo(&self) -> f32 {
+        TEMPO_STEPS[self.tempo_step]
+    }
+
+    pub fn faster(&mut self) {
+        if self.tempo_step + 1 < TEMPO_STEPS.len() {
+            self.set_tempo_step(self.tempo_step + 1);
+        }
+    }
+
+    pub fn slower(&mut self) {
+        if self.tempo_step > 0 {
+            self.set_tempo_step(self.tempo_step - 1);
+        }
+    }
+
+    fn set_tempo_step(&mut self, step: usize) {
+        self.tempo_step = step;
+        debug!("Music tempo {}x", self.tempo());
+        if self.timer_guard.is_some() {
+            self.schedule();
         }
     }
 
@@ -131,36 +356,141 @@ impl SfxPlayer {
         self.sfx_module = Some(module);
     }
 
-    pub fn start(&mut self, mixer: MixerAudio) -> Receiver<i16> {
-        let (tx, rx) = sync_channel::<i16>(0);
+    pub fn next_order(&mut self) {
+        self.seek_relative(1);
+    }
+
+    pub fn previous_order(&mut self) {
+        self.seek_relative(-1);
+    }
+
+    fn seek_relative(&mut self, step: i16) {
+        if let Some(playback) = &self.playback {
+            let mut sfx_module = sync::write(&playback.sfx_module);
+            let order = sfx_module.cur_order as i16 + step;
+            sfx_module.seek(order);
+        }
+    }
+
+    pub fn start(&mut self, mixer: MixerAudio) {
         if let Some(sfx_module) = self.sfx_module.take() {
-            let sfx_module = Arc::new(RwLock::new(sfx_module));
+            self.playback = Some(Playback {
+                sfx_module: Arc::new(RwLock::new(sfx_module)),
+                mixer,
+                mark: Arc::new(Mutex::new(None)),
+            });
+            self.elapsed = 0;
+            if !self.frame_synced {
+                self.schedule();
+            }
+        }
+    }
+
+    /// Delay between two rows of the module at the current tempo
+    fn row_delay(&self) -> i64 {
+        (self.delay as f32 / self.tempo()).round() as i64
+    }
+
+    /// (Re)starts the timer driving the playing module at the current delay
+    /// and tempo
+    fn schedule(&mut self) {
+        if let Some(playback) = &self.playback {
+            let sfx_module = playback.sfx_module.clone();
+            let mixer = playback.mixer.clone();
+            let mark = playback.mark.clone();
             self.timer_guard.replace(self.timer.schedule_repeating(
-                chrono::Duration::milliseconds(self.delay),
+                chrono::Duration::milliseconds(self.row_delay()),
                 move || {
                     if let Some(variable) =
                         SfxPlayer::handle_events(sfx_module.clone(), mixer.clone())
                     {
-                        tx.send(variable).unwrap();
+                        *sync::lock(&mark) = Some(variable);
                     }
                 },
             ));
         }
-        rx
+    }
+
+    /// Plays the rows of the module due in the last `ms` milliseconds, when
+    /// frame synced
+    pub fn advance(&mut self, ms: u64) {
+        self.release_finished_timer();
+        let playback = match (&self.playback, self.frame_synced) {
+            (Some(playback), true) => playback,
+            _ => return,
+        };
+        let row_delay = self.row_delay().max(1);
+        self.elapsed += ms as i64;
+        while self.elapsed >= row_delay {
+            self.elapsed -= row_delay;
+            if sync::read(&playback.sfx_module).is_finished() {
+                self.elapsed = 0;
+                break;
+            }
+            let sfx_module = playback.sfx_module.clone();
+            if let Some(variable) = SfxPlayer::handle_events(sfx_module, playback.mixer.clone()) {
+                *sync::lock(&playback.mark) = Some(variable);
+            }
+        }
+    }
+
+    /// Cancels the timer once the module has ended. The playback is kept so
+    /// a mark reached on the last row can still be taken.
+    fn release_finished_timer(&mut self) {
+        if let (Some(playback), Some(_)) = (&self.playback, &self.timer_guard) {
+            if sync::read(&playback.sfx_module).is_finished() {
+                debug!("Music module finished, stopping timer");
+                self.timer_guard.take();
+            }
+        }
+    }
+
+    /// The last mark reached by the music since the previous call
+    pub fn take_mark(&self) -> Option<i16> {
+        let playback = self.playback.as_ref()?;
+        let mut mark = sync::lock(&playback.mark);
+        mark.take()
     }
 
     pub fn stop(&mut self) {
         self.timer_guard.take();
+        self.playback = None;
+    }
+
+    /// Stops the timer playing the module, keeping the position
+    pub fn pause(&mut self) {
+        self.timer_guard.take();
+    }
+
+    /// Restarts the timer stopped by `pause`
+    pub fn resume(&mut self) {
+        if !self.frame_synced && self.timer_guard.is_none() {
+            self.schedule();
+            self.release_finished_timer();
+        }
     }
 
     pub fn handle_events(sfx_module: Arc<RwLock<SfxModule>>, mixer: MixerAudio) -> Option<i16> {
         let mut variable_value = None;
 
-        let mut sfx_module = sfx_module.write().expect("Expected non-poisoned RwLock");
-        let order = sfx_module.order_table[sfx_module.cur_order as usize] as usize;
-        let mut mixer_guard = mixer.0.write().expect("Expected non-poisoned RwLock");
+        let mut sfx_module = sync::write(&sfx_module);
+        if sfx_module.finished {
+            return None;
+        }
+        let row = match sfx_module.row_range() {
+            Some(row) => row,
+            None => {
+                error!(
+                    "Music order {} at 0x{:x} is outside the module data, stopping music",
+                    sfx_module.cur_order, sfx_module.cur_pos
+                );
+                sfx_module.finished = true;
+                return None;
+            }
+        };
+        let mut mixer_guard = sync::write(&mixer.0);
         for ch in 0..4 {
-            let start = sfx_module.cur_pos + order * 1024 + ch * 4;
+            let start = row.start + ch * 4;
             trace!("Start: {}", start);
             let pattern_data = Cursor::new(&sfx_module.data[start..start + 4]);
             if let Ok(result) = SfxPlayer::handle_pattern(&sfx_module, ch as u8, pattern_data) {
@@ -183,19 +513,23 @@ impl SfxPlayer {
             }
         }
 
-        let order = sfx_module.order_table[sfx_module.cur_order as usize] as usize;
         sfx_module.cur_pos += 4 * 4;
         debug!(
-            "handle_events() order = 0x{:x} cur_pos = 0x{:x}",
-            order, sfx_module.cur_pos
+            "handle_events() order = {} cur_pos = 0x{:x}",
+            sfx_module.cur_order, sfx_module.cur_pos
         );
         if sfx_module.cur_pos >= 1024 {
             sfx_module.cur_pos = 0;
             let order = sfx_module.cur_order + 1;
-            if order == sfx_module.num_order {
-                //STOP PLAYING
+            if order >= sfx_module.num_order {
+                // Modules do not loop, the original stops the music and
+                // silences all channels at the end of the order table
+                debug!("End of music module");
+                sfx_module.finished = true;
+                mixer_guard.stop_all();
+            } else {
+                sfx_module.cur_order = order;
             }
-            sfx_module.cur_order = order;
         }
         variable_value
     }
@@ -208,25 +542,88 @@ impl SfxPlayer {
         let note1 = pattern_data.read_u16::<BigEndian>()?;
         let note2 = pattern_data.read_u16::<BigEndian>()?;
         trace!("Note1: {}, Note2: {}", note1, note2);
-        if note1 != 0xfffd {
-            if note1 == 0xfffe {
+        match SfxNote::decode(note1, note2) {
+            SfxNote::Stop => {
                 trace!("Stop channel {}", channel);
-                return Ok(Some(PatternResult::StopChannel(channel)));
+                Ok(Some(PatternResult::StopChannel(channel)))
             }
-            let sample_index = ((note2 & 0xf000) >> 12) as usize;
-            if sample_index != 0 {
+            SfxNote::Mark(value) => Ok(Some(PatternResult::MarkVariable(value))),
+            SfxNote::Note { sample_index, .. } => {
                 trace!("Have sample index");
-                if let Some(sample) = sfx_module.samples[sample_index - 1].as_ref() {
-                    trace!("Sample len: {}", sample.data.len());
-                    return Ok(Some(PatternResult::Pattern(
-                        channel,
-                        SfxPattern::from_notes(note1, note2, sample)?,
-                    )));
+                match sfx_module
+                    .samples
+                    .get(sample_index - 1)
+                    .and_then(Option::as_ref)
+                {
+                    Some(sample) => {
+                        trace!("Sample len: {}", sample.data.len());
+                        Ok(Some(PatternResult::Pattern(
+                            channel,
+                            SfxPattern::from_notes(note1, note2, sample)?,
+                        )))
+                    }
+                    None => Ok(None),
                 }
             }
-        } else {
-            return Ok(Some(PatternResult::MarkVariable(note2)));
+            SfxNote::Empty => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mixer::Mixer;
+
+    fn play(module: SfxModule) -> (Arc<RwLock<SfxModule>>, MixerAudio) {
+        let mixer = MixerAudio(Arc::new(RwLock::new(Mixer::new())));
+        (Arc::new(RwLock::new(module)), mixer)
+    }
+
+    #[test]
+    fn truncated_module_stops_the_music() {
+        #[rustfmt::skip]
+        let data = vec![
+            0xff, 0xfd, 0x00, 0x42, // mark 0x42
+            0x01, 0x00, 0x20, 0x00, // instrument 2, not loaded
+            0x01, 0x00, 0x50, 0x00, // instrument 5, past the instruments
+            0x01, 0x00, 0x10, 0x00, // instrument 1, shorter than its header
+            0x01, 0x00, 0x10, 0x00, 0x00, 0x00,
+        ];
+        let samples = vec![Some(SfxInstrument::new(0x5a, vec![0; 4], 0x20)), None];
+        let mut order_table = [0; 0x80];
+        order_table[1] = 3;
+        let (module, mixer) = play(SfxModule::new(data, 0, 2, order_table, samples));
+
+        assert_eq!(
+            SfxPlayer::handle_events(module.clone(), mixer.clone()),
+            Some(0x42)
+        );
+        assert!(!sync::read(&module).is_finished());
+        // The second row is cut short
+        assert_eq!(
+            SfxPlayer::handle_events(module.clone(), mixer.clone()),
+            None
+        );
+        assert!(sync::read(&module).is_finished());
+        assert_eq!(SfxPlayer::handle_events(module, mixer), None);
+    }
+
+    #[test]
+    fn orders_past_the_data_stop_the_music() {
+        let mut order_table = [0; 0x80];
+        order_table[1] = 1;
+        let module = SfxModule::new(vec![0; 1024], 0, 0xff, order_table, Vec::new());
+        let (module, mixer) = play(module);
+        for _ in 0..1024 / 16 {
+            assert_eq!(
+                SfxPlayer::handle_events(module.clone(), mixer.clone()),
+                None
+            );
         }
-        Ok(None)
+        assert_eq!(sync::read(&module).cur_order, 1);
+        assert!(!sync::read(&module).is_finished());
+        SfxPlayer::handle_events(module.clone(), mixer);
+        assert!(sync::read(&module).is_finished());
     }
 }
@@ -0,0 +1,649 @@
+use std::fmt;
+use std::fs;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::lz;
+use crate::parts;
+use crate::video::{Color, Palette, MAX_SCALE, NUM_COLORS};
+
+const MAGIC: &[u8; 4] = b"AWSV";
+/// Version 2 adds the time of saving and a thumbnail after the part id,
+/// version 3 the call stack, loaded resources and video pages after the
+/// threads
+const VERSION: u8 = 3;
+
+/// Compressed save states wrap the serialized state in a header of their
+/// own with the size of the state, followed by the data compressed with
+/// `lz::compress`
+const COMPRESSED_MAGIC: &[u8; 4] = b"AWSZ";
+const COMPRESSED_VERSION: u8 = 1;
+
+pub const NUM_VARIABLES: usize = 256;
+pub const NUM_THREADS: usize = 64;
+
+pub const VM_VARIABLE_RANDOM_SEED: usize = 0x3c;
+pub const VM_VARIABLE_LAST_KEYCHAR: usize = 0xda;
+pub const VM_VARIABLE_HERO_POS_UP_DOWN: usize = 0xe5;
+pub const VM_VARIABLE_MUS_MARK: usize = 0xf4;
+pub const VM_VARIABLE_SCROLL_Y: usize = 0xf9;
+pub const VM_VARIABLE_HERO_ACTION: usize = 0xfa;
+pub const VM_VARIABLE_HERO_POS_JUMP_DOWN: usize = 0xfb;
+pub const VM_VARIABLE_HERO_POS_LEFT_RIGHT: usize = 0xfc;
+pub const VM_VARIABLE_HERO_POS_MASK: usize = 0xfd;
+pub const VM_VARIABLE_HERO_ACTION_POS_MASK: usize = 0xfe;
+pub const VM_VARIABLE_PAUSE_SLICES: usize = 0xff;
+
+/// Name of the variable at `index` if the engine gives it a meaning
+pub fn variable_name(index: usize) -> Option<&'static str> {
+    match index {
+        VM_VARIABLE_RANDOM_SEED => Some("RANDOM_SEED"),
+        VM_VARIABLE_LAST_KEYCHAR => Some("LAST_KEYCHAR"),
+        VM_VARIABLE_HERO_POS_UP_DOWN => Some("HERO_POS_UP_DOWN"),
+        VM_VARIABLE_MUS_MARK => Some("MUS_MARK"),
+        VM_VARIABLE_SCROLL_Y => Some("SCROLL_Y"),
+        VM_VARIABLE_HERO_ACTION => Some("HERO_ACTION"),
+        VM_VARIABLE_HERO_POS_JUMP_DOWN => Some("HERO_POS_JUMP_DOWN"),
+        VM_VARIABLE_HERO_POS_LEFT_RIGHT => Some("HERO_POS_LEFT_RIGHT"),
+        VM_VARIABLE_HERO_POS_MASK => Some("HERO_POS_MASK"),
+        VM_VARIABLE_HERO_ACTION_POS_MASK => Some("HERO_ACTION_POS_MASK"),
+        VM_VARIABLE_PAUSE_SLICES => Some("PAUSE_SLICES"),
+        _ => None,
+    }
+}
+
+pub const THUMBNAIL_WIDTH: usize = 80;
+pub const THUMBNAIL_HEIGHT: usize = 50;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadState {
+    pub pc: u16,
+    pub requested_pc_offset: Option<u16>,
+    pub is_channel_active_current: bool,
+    pub is_channel_active_requested: bool,
+}
+
+impl fmt::Display for ThreadState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.pc == 0xffff {
+            write!(f, "inactive")?;
+        } else {
+            write!(f, "pc 0x{:04x}", self.pc)?;
+        }
+        if let Some(offset) = self.requested_pc_offset {
+            write!(f, ", jump to 0x{:04x} requested", offset)?;
+        }
+        if self.is_channel_active_current {
+            write!(f, ", paused")?;
+        }
+        if self.is_channel_active_requested != self.is_channel_active_current {
+            let requested = if self.is_channel_active_requested {
+                "pause"
+            } else {
+                "resume"
+            };
+            write!(f, ", {} requested", requested)?;
+        }
+        Ok(())
+    }
+}
+
+fn same_thread(a: &ThreadState, b: &ThreadState) -> bool {
+    a.pc == b.pc
+        && a.requested_pc_offset == b.requested_pc_offset
+        && a.is_channel_active_current == b.is_channel_active_current
+        && a.is_channel_active_requested == b.is_channel_active_requested
+}
+
+fn palette_colors(palette: &Palette) -> [(u8, u8, u8); NUM_COLORS] {
+    let mut colors = [(0, 0, 0); NUM_COLORS];
+    for (color, entry) in colors.iter_mut().zip(palette.entries.iter()) {
+        *color = (entry.r, entry.g, entry.b);
+    }
+    colors
+}
+
+fn colors_palette(colors: &[(u8, u8, u8); NUM_COLORS]) -> Palette {
+    let mut palette = Palette::grayscale();
+    for (entry, &(r, g, b)) in palette.entries.iter_mut().zip(colors.iter()) {
+        *entry = Color { r, g, b, a: 0xff };
+    }
+    palette
+}
+
+fn read_colors<R: Read>(reader: &mut R) -> Result<[(u8, u8, u8); NUM_COLORS]> {
+    let mut colors = [(0, 0, 0); NUM_COLORS];
+    for color in colors.iter_mut() {
+        *color = (reader.read_u8()?, reader.read_u8()?, reader.read_u8()?);
+    }
+    Ok(colors)
+}
+
+fn write_colors<W: Write>(writer: &mut W, colors: &[(u8, u8, u8); NUM_COLORS]) -> Result<()> {
+    for &(r, g, b) in colors.iter() {
+        writer.write_all(&[r, g, b])?;
+    }
+    Ok(())
+}
+
+fn read_palette<R: Read>(reader: &mut R) -> Result<Option<Palette>> {
+    if reader.read_u8()? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(colors_palette(&read_colors(reader)?)))
+}
+
+fn write_palette<W: Write>(writer: &mut W, palette: Option<&Palette>) -> Result<()> {
+    match palette {
+        Some(palette) => {
+            writer.write_u8(1)?;
+            write_colors(writer, &palette_colors(palette))
+        }
+        None => writer.write_u8(0),
+    }
+}
+
+/// Small copy of the frame displayed when saving, for previewing saves
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    /// RGB colors of the palette indices in `pixels`
+    pub colors: [(u8, u8, u8); NUM_COLORS],
+    pub pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+    /// Scales down a `width` x `height` page by sampling every few pixels
+    pub fn from_page(width: usize, height: usize, data: &[u8], palette: &Palette) -> Thumbnail {
+        let mut pixels = Vec::with_capacity(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT);
+        for y in 0..THUMBNAIL_HEIGHT {
+            let row = y * height / THUMBNAIL_HEIGHT * width;
+            for x in 0..THUMBNAIL_WIDTH {
+                pixels.push(data[row + x * width / THUMBNAIL_WIDTH]);
+            }
+        }
+        Thumbnail {
+            width: THUMBNAIL_WIDTH,
+            height: THUMBNAIL_HEIGHT,
+            colors: palette_colors(palette),
+            pixels,
+        }
+    }
+
+    pub fn palette(&self) -> Palette {
+        colors_palette(&self.colors)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Thumbnail> {
+        let width = reader.read_u16::<BigEndian>()? as usize;
+        let height = reader.read_u16::<BigEndian>()? as usize;
+        if width != THUMBNAIL_WIDTH || height != THUMBNAIL_HEIGHT {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid thumbnail size {}x{} in save state", width, height),
+            ));
+        }
+        let colors = read_colors(reader)?;
+        let mut pixels = vec![0; width * height];
+        reader.read_exact(&mut pixels)?;
+        if pixels.iter().any(|&p| p as usize >= NUM_COLORS) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid color in save state thumbnail",
+            ));
+        }
+        Ok(Thumbnail {
+            width,
+            height,
+            colors,
+            pixels,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.width as u16)?;
+        writer.write_u16::<BigEndian>(self.height as u16)?;
+        write_colors(writer, &self.colors)?;
+        writer.write_all(&self.pixels)
+    }
+}
+
+/// The four video pages, which of them are drawn to and displayed, and the
+/// palettes
+#[derive(Clone, Debug)]
+pub struct VideoState {
+    pub width: usize,
+    pub height: usize,
+    pub pages: [Vec<u8>; 4],
+    /// Indices of the page drawn to, the displayed page and the back buffer
+    pub page_ptrs: [usize; 3],
+    pub palette: Option<Palette>,
+    /// Palette set by the scripts, applied on the next blit
+    pub palette_requested: Option<Palette>,
+}
+
+impl VideoState {
+    fn read<R: Read>(reader: &mut R) -> Result<VideoState> {
+        let width = reader.read_u16::<BigEndian>()? as usize;
+        let height = reader.read_u16::<BigEndian>()? as usize;
+        // Pages are only ever saved at a multiple of 320x200
+        let scale = width / 320;
+        if !(1..=MAX_SCALE as usize).contains(&scale)
+            || width != 320 * scale
+            || height != 200 * scale
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid page size {}x{} in save state", width, height),
+            ));
+        }
+        let mut page_ptrs = [0; 3];
+        for ptr in page_ptrs.iter_mut() {
+            *ptr = reader.read_u8()? as usize;
+        }
+        if page_ptrs.iter().any(|&ptr| ptr >= 4) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Invalid page in save state",
+            ));
+        }
+        let palette = read_palette(reader)?;
+        let palette_requested = read_palette(reader)?;
+        let mut pages = [vec![], vec![], vec![], vec![]];
+        for page in pages.iter_mut() {
+            *page = vec![0; width * height];
+            reader.read_exact(page)?;
+        }
+        Ok(VideoState {
+            width,
+            height,
+            pages,
+            page_ptrs,
+            palette,
+            palette_requested,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.width as u16)?;
+        writer.write_u16::<BigEndian>(self.height as u16)?;
+        for &ptr in self.page_ptrs.iter() {
+            writer.write_u8(ptr as u8)?;
+        }
+        write_palette(writer, self.palette.as_ref())?;
+        write_palette(writer, self.palette_requested.as_ref())?;
+        for page in self.pages.iter() {
+            writer.write_all(page)?;
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot of the VM state that is needed to resume a game
+#[derive(Clone, Debug)]
+pub struct SaveState {
+    pub part_id: u16,
+    /// Seconds since the Unix epoch when the state was saved, if known
+    pub timestamp: Option<i64>,
+    pub thumbnail: Option<Thumbnail>,
+    pub variables: [i16; NUM_VARIABLES],
+    pub threads: [ThreadState; NUM_THREADS],
+    /// Part requested by the scripts, set up at the start of the next frame
+    pub requested_next_part: Option<u16>,
+    /// Return offsets of the calls in progress, empty between frames
+    pub call_stack: Vec<u16>,
+    /// Resources loaded by the scripts on top of those of the part, in the
+    /// order they are laid out in memory
+    pub loaded_resources: Vec<u16>,
+    /// Offsets of the palette, bytecode, cinematic and second video segments
+    /// in the resource memory, to check that the part is set up alike
+    pub segments: Option<[u32; 4]>,
+    pub video: Option<VideoState>,
+}
+
+impl SaveState {
+    /// Reads a save state, compressed or not
+    pub fn load(path: &Path) -> Result<SaveState> {
+        SaveState::from_bytes(&fs::read(path)?)
+    }
+
+    /// Writes the save state compressed
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes(true)?)
+    }
+
+    /// Writes the save state as is, for inspecting the file
+    pub fn save_uncompressed(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes(false)?)
+    }
+
+    pub fn to_bytes(&self, compress: bool) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.write(&mut data)?;
+        if !compress {
+            return Ok(data);
+        }
+        let mut out = Vec::new();
+        out.write_all(COMPRESSED_MAGIC)?;
+        out.write_u8(COMPRESSED_VERSION)?;
+        out.write_u32::<BigEndian>(data.len() as u32)?;
+        out.extend_from_slice(&lz::compress(&data));
+        Ok(out)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<SaveState> {
+        if !data.starts_with(COMPRESSED_MAGIC) {
+            return SaveState::read(&mut Cursor::new(data));
+        }
+        let mut reader = Cursor::new(&data[COMPRESSED_MAGIC.len()..]);
+        let version = reader.read_u8()?;
+        if version != COMPRESSED_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported compressed save state version {}", version),
+            ));
+        }
+        let size = reader.read_u32::<BigEndian>()? as usize;
+        let compressed = &reader.get_ref()[reader.position() as usize..];
+        let data = lz::decompress(compressed, size)?;
+        SaveState::read(&mut Cursor::new(data))
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<SaveState> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a save state"));
+        }
+        let version = reader.read_u8()?;
+        if version == 0 || version > VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported save state version {}", version),
+            ));
+        }
+
+        let part_id = reader.read_u16::<BigEndian>()?;
+        if parts::part_number(part_id).is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid part 0x{:04x} in save state", part_id),
+            ));
+        }
+
+        let (mut timestamp, mut thumbnail) = (None, None);
+        if version >= 2 {
+            timestamp = Some(reader.read_i64::<BigEndian>()?).filter(|&t| t != 0);
+            if reader.read_u8()? != 0 {
+                thumbnail = Some(Thumbnail::read(reader)?);
+            }
+        }
+
+        let mut variables = [0; NUM_VARIABLES];
+        reader.read_i16_into::<BigEndian>(&mut variables)?;
+
+        let mut threads = [ThreadState {
+            pc: 0,
+            requested_pc_offset: None,
+            is_channel_active_current: false,
+            is_channel_active_requested: false,
+        }; NUM_THREADS];
+        for thread in threads.iter_mut() {
+            thread.pc = reader.read_u16::<BigEndian>()?;
+            let flags = reader.read_u8()?;
+            let requested_pc_offset = reader.read_u16::<BigEndian>()?;
+            thread.requested_pc_offset = if flags & 1 != 0 {
+                Some(requested_pc_offset)
+            } else {
+                None
+            };
+            thread.is_channel_active_current = flags & 2 != 0;
+            thread.is_channel_active_requested = flags & 4 != 0;
+        }
+
+        let mut call_stack = Vec::new();
+        let mut loaded_resources = Vec::new();
+        let (mut requested_next_part, mut segments, mut video) = (None, None, None);
+        if version >= 3 {
+            requested_next_part = Some(reader.read_u16::<BigEndian>()?).filter(|&p| p != 0);
+            if let Some(part_id) = requested_next_part {
+                if parts::part_name(part_id).is_none() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid requested part 0x{:04x} in save state", part_id),
+                    ));
+                }
+            }
+            for _ in 0..reader.read_u8()? {
+                call_stack.push(reader.read_u16::<BigEndian>()?);
+            }
+            for _ in 0..reader.read_u16::<BigEndian>()? {
+                loaded_resources.push(reader.read_u16::<BigEndian>()?);
+            }
+            if reader.read_u8()? != 0 {
+                let mut offsets = [0; 4];
+                reader.read_u32_into::<BigEndian>(&mut offsets)?;
+                segments = Some(offsets);
+            }
+            if reader.read_u8()? != 0 {
+                video = Some(VideoState::read(reader)?);
+            }
+        }
+
+        Ok(SaveState {
+            part_id,
+            timestamp,
+            thumbnail,
+            variables,
+            threads,
+            requested_next_part,
+            call_stack,
+            loaded_resources,
+            segments,
+            video,
+        })
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_u8(VERSION)?;
+        writer.write_u16::<BigEndian>(self.part_id)?;
+        writer.write_i64::<BigEndian>(self.timestamp.unwrap_or(0))?;
+        match &self.thumbnail {
+            Some(thumbnail) => {
+                writer.write_u8(1)?;
+                thumbnail.write(writer)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        for &variable in self.variables.iter() {
+            writer.write_i16::<BigEndian>(variable)?;
+        }
+        for thread in self.threads.iter() {
+            let mut flags = 0;
+            if thread.requested_pc_offset.is_some() {
+                flags |= 1;
+            }
+            if thread.is_channel_active_current {
+                flags |= 2;
+            }
+            if thread.is_channel_active_requested {
+                flags |= 4;
+            }
+            writer.write_u16::<BigEndian>(thread.pc)?;
+            writer.write_u8(flags)?;
+            writer.write_u16::<BigEndian>(thread.requested_pc_offset.unwrap_or(0))?;
+        }
+        writer.write_u16::<BigEndian>(self.requested_next_part.unwrap_or(0))?;
+        writer.write_u8(self.call_stack.len() as u8)?;
+        for &offset in self.call_stack.iter() {
+            writer.write_u16::<BigEndian>(offset)?;
+        }
+        writer.write_u16::<BigEndian>(self.loaded_resources.len() as u16)?;
+        for &resource_id in self.loaded_resources.iter() {
+            writer.write_u16::<BigEndian>(resource_id)?;
+        }
+        match &self.segments {
+            Some(offsets) => {
+                writer.write_u8(1)?;
+                for &offset in offsets.iter() {
+                    writer.write_u32::<BigEndian>(offset)?;
+                }
+            }
+            None => writer.write_u8(0)?,
+        }
+        match &self.video {
+            Some(video) => {
+                writer.write_u8(1)?;
+                video.write(writer)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        Ok(())
+    }
+
+    /// Writes the part, variables and threads that differ from `other` as
+    /// `-` lines for this state and `+` lines for `other`. Returns the
+    /// number of differences.
+    pub fn write_diff<W: Write>(&self, other: &SaveState, out: &mut W) -> Result<usize> {
+        let mut differences = 0;
+        if self.part_id != other.part_id {
+            writeln!(out, "@@ part @@")?;
+            writeln!(out, "-0x{:04x}", self.part_id)?;
+            writeln!(out, "+0x{:04x}", other.part_id)?;
+            differences += 1;
+        }
+
+        let variables: Vec<usize> = (0..NUM_VARIABLES)
+            .filter(|&i| self.variables[i] != other.variables[i])
+            .collect();
+        if !variables.is_empty() {
+            writeln!(out, "@@ variables @@")?;
+        }
+        for &i in &variables {
+            let name = variable_name(i)
+                .map(|name| format!(" {}", name))
+                .unwrap_or_default();
+            writeln!(out, "-0x{:02x}{} = {}", i, name, self.variables[i])?;
+            writeln!(out, "+0x{:02x}{} = {}", i, name, other.variables[i])?;
+        }
+        differences += variables.len();
+
+        let threads: Vec<usize> = (0..NUM_THREADS)
+            .filter(|&i| !same_thread(&self.threads[i], &other.threads[i]))
+            .collect();
+        if !threads.is_empty() {
+            writeln!(out, "@@ threads @@")?;
+        }
+        for &i in &threads {
+            writeln!(out, "-{:02}: {}", i, self.threads[i])?;
+            writeln!(out, "+{:02}: {}", i, other.threads[i])?;
+        }
+        differences += threads.len();
+        Ok(differences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video_state() -> VideoState {
+        VideoState {
+            width: 320,
+            height: 200,
+            pages: [
+                vec![0; 320 * 200],
+                vec![1; 320 * 200],
+                vec![2; 320 * 200],
+                vec![3; 320 * 200],
+            ],
+            page_ptrs: [2, 2, 1],
+            palette: None,
+            palette_requested: None,
+        }
+    }
+
+    fn save_state() -> SaveState {
+        SaveState {
+            part_id: parts::GAME_PART2,
+            timestamp: None,
+            thumbnail: None,
+            variables: [0; NUM_VARIABLES],
+            threads: [ThreadState {
+                pc: 0xffff,
+                requested_pc_offset: None,
+                is_channel_active_current: false,
+                is_channel_active_requested: false,
+            }; NUM_THREADS],
+            requested_next_part: Some(parts::GAME_PART3),
+            call_stack: vec![],
+            loaded_resources: vec![],
+            segments: None,
+            video: Some(video_state()),
+        }
+    }
+
+    /// The video state with the page size in its header replaced
+    fn video_bytes(width: u16, height: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        video_state().write(&mut data).unwrap();
+        data[0..2].copy_from_slice(&width.to_be_bytes());
+        data[2..4].copy_from_slice(&height.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn video_state_reads_scaled_pages_only() {
+        assert!(VideoState::read(&mut Cursor::new(video_bytes(320, 200))).is_ok());
+        for &(width, height) in &[(0, 0), (320, 0), (0, 200), (321, 200), (640, 200)] {
+            let result = VideoState::read(&mut Cursor::new(video_bytes(width, height)));
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        // Rejected before allocating four pages of this size
+        let result = VideoState::read(&mut Cursor::new(video_bytes(0xffff, 0xffff)));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn thumbnail_reads_its_own_size_only() {
+        let page = vec![5; 320 * 200];
+        let thumbnail = Thumbnail::from_page(320, 200, &page, &Palette::grayscale());
+        let mut data = Vec::new();
+        thumbnail.write(&mut data).unwrap();
+        let read = Thumbnail::read(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(read.pixels, vec![5; THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT]);
+
+        for &(width, height) in &[(0, 0), (40, 100), (0xffff, 0xffff)] {
+            let mut data = data.clone();
+            data[0..2].copy_from_slice(&(width as u16).to_be_bytes());
+            data[2..4].copy_from_slice(&(height as u16).to_be_bytes());
+            let result = Thumbnail::read(&mut Cursor::new(&data));
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn save_state_roundtrips_compressed_and_not() {
+        let state = save_state();
+        for &compress in &[false, true] {
+            let read = SaveState::from_bytes(&state.to_bytes(compress).unwrap()).unwrap();
+            assert_eq!(read.part_id, state.part_id);
+            assert_eq!(read.requested_next_part, state.requested_next_part);
+            let video = read.video.unwrap();
+            assert_eq!(video.page_ptrs, [2, 2, 1]);
+            assert_eq!(video.pages[3], vec![3; 320 * 200]);
+        }
+    }
+
+    #[test]
+    fn unknown_requested_part_is_rejected() {
+        let mut state = save_state();
+        state.requested_next_part = Some(0x1234);
+        let result = SaveState::from_bytes(&state.to_bytes(false).unwrap());
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
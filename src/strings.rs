@@ -1,5 +1,10 @@
 use lazy_static::lazy_static;
 use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::settings::parse_string;
 
 lazy_static! {
     pub static ref STRINGS_TABLE_ENG: HashMap<u16, &'static str> =
@@ -146,3 +151,59 @@ lazy_static! {
         (0x193, "AU BOULOT !!!\n"),
     ].iter().cloned().collect();
 }
+
+/// Strings read from a file, used instead of the English ones with the
+/// same id. Each line holds an id and a quoted string, `0x190 = "Bonsoir"`,
+/// where `\n` starts a new line.
+pub struct StringTable {
+    pub name: String,
+    strings: HashMap<u16, String>,
+}
+
+fn invalid(line: usize, message: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("line {}: {}", line + 1, message),
+    )
+}
+
+fn parse_id(id: &str) -> std::result::Result<u16, String> {
+    let digits = id
+        .strip_prefix("0x")
+        .ok_or_else(|| format!("Expected a hexadecimal id, got {}", id))?;
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid string id {}", id))
+}
+
+impl StringTable {
+    /// Reads a table named after the file
+    pub fn load(path: &Path) -> Result<StringTable> {
+        let text = fs::read_to_string(path)?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        StringTable::parse(name, &text)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(name: String, text: &str) -> Result<StringTable> {
+        let mut strings = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(n, format!("Expected id = \"text\", got {}", line)))?;
+            let id = parse_id(id.trim()).map_err(|e| invalid(n, e))?;
+            let value = parse_string(value.trim()).map_err(|e| invalid(n, e))?;
+            strings.insert(id, value);
+        }
+        Ok(StringTable { name, strings })
+    }
+
+    pub fn get(&self, id: u16) -> Option<&str> {
+        self.strings.get(&id).map(String::as_str)
+    }
+}
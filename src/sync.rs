@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LockResult, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use log::error;
+
+static REPORTED_POISON: AtomicBool = AtomicBool::new(false);
+
+/// Takes the guard even if a thread panicked while holding the lock. The
+/// audio state stays usable after a panic in the audio thread, so the game
+/// keeps running rather than failing on the next lock.
+fn recover<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(|poisoned| {
+        if !REPORTED_POISON.swap(true, Ordering::Relaxed) {
+            error!("A thread panicked while holding a lock, continuing with its state");
+        }
+        poisoned.into_inner()
+    })
+}
+
+pub fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    recover(lock.read())
+}
+
+pub fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    recover(lock.write())
+}
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    recover(mutex.lock())
+}
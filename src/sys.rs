@@ -1,28 +1,82 @@
-use log::debug;
+use log::{debug, warn};
 use std::sync::{Arc, RwLock};
-use std::{thread, time};
 
-use sdl2::audio::{AudioDevice, AudioSpecDesired};
+use sdl2::audio::{AudioDevice, AudioSpec, AudioSpecDesired};
 use sdl2::pixels::{Color, Palette, PixelFormatEnum};
-use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Texture, TextureCreator, WindowCanvas};
 use sdl2::surface::Surface;
-use sdl2::video::WindowContext;
+use sdl2::video::{FullscreenType, WindowContext};
 
+use crate::clock::Clock;
+use crate::input::UserInput;
 use crate::mixer;
+use crate::player::PlayerInput;
+use crate::system::{System, WindowGeometry};
 use crate::video;
 
+/// The open audio device, with the output format it was opened with
+enum AudioOutput {
+    Bits8(AudioDevice<mixer::MixerAudio>),
+    Bits16(AudioDevice<mixer::MixerAudio16>),
+}
+
 pub struct SDLSys {
     sdl_context: sdl2::Sdl,
+    input: UserInput,
     surface: Surface<'static>,
     canvas: WindowCanvas,
-    audio_device: Option<AudioDevice<mixer::MixerAudio>>,
-    timestamp: time::Instant,
+    audio_device: Option<AudioOutput>,
+    audio_samples: Option<u16>,
+    audio_16bit: bool,
+    audio: bool,
+    clock: Clock,
     width: usize,
     height: usize,
     texture_creator: TextureCreator<WindowContext>,
     scanlines: bool,
     scanline_overlay_size: (u32, u32),
     scanline_texture: Option<Texture>,
+    crt: bool,
+    crt_overlay_size: (u32, u32),
+    crt_texture: Option<Texture>,
+    mirror: bool,
+    output_size: (u32, u32),
+    viewport: Rect,
+    letterbox_color: Color,
+    precise_sleep: bool,
+    colors: Vec<Color>,
+}
+
+/// Whether a window at `position` overlaps one of the displays enough to be
+/// grabbed by its title bar
+fn is_on_screen(video_subsystem: &sdl2::VideoSubsystem, (x, y): (i32, i32), width: u32) -> bool {
+    let title_bar = Rect::new(x, y, width, 32);
+    let displays = video_subsystem.num_video_displays().unwrap_or(0);
+    (0..displays).any(|i| {
+        video_subsystem
+            .display_bounds(i)
+            .map(|bounds| bounds.has_intersection(title_bar))
+            .unwrap_or(false)
+    })
+}
+
+/// Largest rectangle with the aspect ratio of `width` x `height` that fits
+/// centered in `output_width` x `output_height`
+fn fit_viewport((output_width, output_height): (u32, u32), width: usize, height: usize) -> Rect {
+    let (output_width, output_height) = (output_width as u64, output_height as u64);
+    let (width, height) = (width as u64, height as u64);
+    let (w, h) = if output_width * height > output_height * width {
+        (output_height * width / height, output_height)
+    } else {
+        (output_width, output_width * height / width)
+    };
+    Rect::new(
+        ((output_width - w) / 2) as i32,
+        ((output_height - h) / 2) as i32,
+        w.max(1) as u32,
+        h.max(1) as u32,
+    )
 }
 
 fn create_scanline_overlay(display_width: u32, display_height: u32) -> Surface<'static> {
@@ -45,52 +99,199 @@ fn create_scanline_overlay(display_width: u32, display_height: u32) -> Surface<'
     surface
 }
 
+fn create_crt_overlay(display_width: u32, display_height: u32, width: usize) -> Surface<'static> {
+    let mut surface =
+        Surface::new(display_width, display_height, PixelFormatEnum::RGBA8888).unwrap();
+
+    // Only tint subpixels when each game pixel covers at least three columns
+    let grille = display_width as usize / width >= 3;
+    let grille_alpha = 20.0;
+    let vignette_alpha = 110.0;
+    let cx = display_width as f32 / 2.0;
+    let cy = display_height as f32 / 2.0;
+    surface.with_lock_mut(|p| {
+        for j in 0..display_height {
+            let dy = (j as f32 - cy) / cy;
+            for i in 0..display_width {
+                let dx = (i as f32 - cx) / cx;
+                let vignette = ((dx * dx + dy * dy) / 2.0).powi(2) * vignette_alpha;
+                let (tint, tint_alpha) = if grille {
+                    let tint = match i % 3 {
+                        0 => (255.0, 0.0, 0.0),
+                        1 => (0.0, 255.0, 0.0),
+                        _ => (0.0, 0.0, 255.0),
+                    };
+                    (tint, grille_alpha)
+                } else {
+                    ((0.0, 0.0, 0.0), 0.0)
+                };
+                let alpha = (vignette + tint_alpha).min(255.0);
+                let offset = ((j * display_width + i) * 4) as usize;
+                if alpha > 0.0 {
+                    let k = tint_alpha / alpha;
+                    p[offset] = alpha as u8;
+                    p[offset + 1] = (tint.2 * k) as u8;
+                    p[offset + 2] = (tint.1 * k) as u8;
+                    p[offset + 3] = (tint.0 * k) as u8;
+                }
+            }
+        }
+    });
+    surface
+}
+
 impl SDLSys {
-    pub fn new(sdl_context: sdl2::Sdl, width: usize, height: usize, scanlines: bool) -> SDLSys {
+    pub fn new(
+        sdl_context: sdl2::Sdl,
+        width: usize,
+        height: usize,
+        scanlines: bool,
+        crt: bool,
+        geometry: &WindowGeometry,
+    ) -> SDLSys {
         let video_subsystem = sdl_context.video().unwrap();
 
-        let window = video_subsystem
-            .window("Another world", 1280, 800)
-            .position_centered()
-            .resizable()
-            .build()
-            .unwrap();
+        let (window_width, window_height) = geometry.size;
+        let mut builder = video_subsystem.window("Another world", window_width, window_height);
+        match geometry.position {
+            Some(position) if is_on_screen(&video_subsystem, position, window_width) => {
+                builder.position(position.0, position.1)
+            }
+            Some(position) => {
+                warn!("Window position {:?} is off-screen, centering", position);
+                builder.position_centered()
+            }
+            None => builder.position_centered(),
+        };
+        if geometry.fullscreen {
+            builder.fullscreen_desktop();
+        }
+        let window = builder.resizable().build().unwrap();
+        // Letters for the password screen are read from text input events
+        video_subsystem.text_input().start();
 
-        let mut canvas = window.into_canvas().build().expect("Expected canvas");
-        canvas
-            .set_logical_size(width as u32, height as u32)
-            .expect("Expected logical size");
+        let canvas = window.into_canvas().build().expect("Expected canvas");
+        let output_size = canvas.output_size().expect("Expected output size");
+        let viewport = fit_viewport(output_size, width, height);
 
         let texture_creator = canvas.texture_creator();
+        let input = UserInput::new(sdl_context.event_pump().unwrap());
 
-        SDLSys {
+        let mut sys = SDLSys {
             sdl_context,
+            input,
             surface: Surface::new(width as u32, height as u32, PixelFormatEnum::Index8).unwrap(),
             canvas,
             audio_device: None,
-            timestamp: time::Instant::now(),
+            audio_samples: None,
+            audio_16bit: false,
+            audio: true,
+            clock: Clock::real(),
             width,
             height,
             texture_creator,
             scanlines,
             scanline_overlay_size: (0, 0),
             scanline_texture: None,
+            crt,
+            crt_overlay_size: (0, 0),
+            crt_texture: None,
+            mirror: false,
+            output_size,
+            viewport,
+            letterbox_color: Color::RGB(0, 0, 0),
+            precise_sleep: false,
+            colors: Vec::new(),
+        };
+        let viewport = sys.window_viewport();
+        sys.input.set_viewport(viewport);
+        sys
+    }
+
+    /// Recomputes where the game is drawn after the window has been resized.
+    /// Does nothing if the output size hasn't changed.
+    fn update_viewport(&mut self) {
+        let output_size = self.canvas.output_size().expect("Expected output size");
+        if output_size == self.output_size {
+            return;
         }
+        debug!("update_viewport({:?})", output_size);
+        self.output_size = output_size;
+        self.viewport = fit_viewport(output_size, self.width, self.height);
     }
 
-    pub fn set_palette(&mut self, palette: &video::Palette) {
-        debug!("set_palette()");
-        let colors: Vec<Color> = palette
-            .entries
-            .iter()
-            .map(|c| Color::RGBA(c.r, c.g, c.b, c.a))
-            .collect();
-        let sdl_palette = Palette::with_colors(&colors).unwrap();
+    /// Where the game is drawn in window coordinates as (x, y, width, height),
+    /// for translating mouse positions. These differ from the output pixels
+    /// on high DPI displays.
+    pub fn window_viewport(&self) -> (i32, i32, u32, u32) {
+        let viewport = fit_viewport(self.canvas.window().size(), self.width, self.height);
+        (
+            viewport.x(),
+            viewport.y(),
+            viewport.width(),
+            viewport.height(),
+        )
+    }
 
-        self.surface.set_palette(&sdl_palette).unwrap();
+    /// Color of the margins around the game when the window doesn't have its
+    /// aspect ratio
+    pub fn set_letterbox_color(&mut self, r: u8, g: u8, b: u8) {
+        self.letterbox_color = Color::RGB(r, g, b);
+    }
+
+    /// Flip the displayed image horizontally. Everything is flipped alike,
+    /// including text and overlays, since only the final image is touched.
+    pub fn set_mirror(&mut self, mirror: bool) {
+        self.mirror = mirror;
+        self.input.set_mirror(mirror);
+    }
+
+    /// Swap left and right
+    pub fn set_mirror_input(&mut self, mirror_input: bool) {
+        self.input.set_mirror_input(mirror_input);
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Spin for the end of each sleep for tighter frame pacing, at the cost
+    /// of CPU time
+    pub fn set_precise_sleep(&mut self, precise_sleep: bool) {
+        self.precise_sleep = precise_sleep;
+    }
+
+    /// Audio buffer size in sample frames, `None` lets SDL decide. Takes
+    /// effect when audio is started.
+    pub fn set_audio_buffer(&mut self, samples: Option<u16>) {
+        self.audio_samples = samples;
+    }
+
+    /// Output 16 bit samples instead of the original 8 bit ones. Only has an
+    /// effect when audio is started.
+    pub fn set_audio_16bit(&mut self, audio_16bit: bool) {
+        self.audio_16bit = audio_16bit;
+    }
+
+    /// Disable audio to run without opening an audio device. Must be set
+    /// before audio is started.
+    pub fn set_audio(&mut self, audio: bool) {
+        self.audio = audio;
+    }
+}
+
+impl System for SDLSys {
+    fn process_events(&mut self) -> PlayerInput {
+        let input = self.input.process_events();
+        if input.resized {
+            self.update_viewport();
+            let viewport = self.window_viewport();
+            self.input.set_viewport(viewport);
+        }
+        input
     }
 
-    pub fn update_display(&mut self, page: &video::Page) {
+    fn update_display(&mut self, page: &video::Page) {
         debug!("update_display()");
         let pitch = self.surface.pitch() as usize;
         let width = self.width;
@@ -103,16 +304,35 @@ impl SDLSys {
                     .clone_from_slice(&page.data[page_offset..(width + page_offset)]);
             }
         });
-        let texture = self
+        let mut texture = self
             .texture_creator
             .create_texture_from_surface(&*self.surface)
             .unwrap();
 
+        let viewport = self.viewport;
+        let viewport_size = (viewport.width(), viewport.height());
+        self.canvas.set_draw_color(self.letterbox_color);
         self.canvas.clear();
-        self.canvas.copy(&texture, None, None).unwrap();
+        self.canvas
+            .copy_ex(&texture, None, viewport, 0.0, None, self.mirror, false)
+            .unwrap();
 
-        if self.scanlines && self.scanline_overlay_size != self.canvas.output_size().unwrap() {
-            let (display_width, display_height) = self.canvas.output_size().unwrap();
+        if self.crt {
+            // Horizontal bleed: blend the frame shifted by one pixel over itself
+            texture.set_blend_mode(BlendMode::Blend);
+            texture.set_alpha_mod(64);
+            let pixel_width = (viewport.width() as usize / self.width).max(1) as i32;
+            let mut bleed = viewport;
+            bleed.offset(pixel_width, 0);
+            self.canvas.set_clip_rect(viewport);
+            self.canvas
+                .copy_ex(&texture, None, bleed, 0.0, None, self.mirror, false)
+                .unwrap();
+            self.canvas.set_clip_rect(None);
+        }
+
+        if self.scanlines && self.scanline_overlay_size != viewport_size {
+            let (display_width, display_height) = viewport_size;
             let scanline_overlay = create_scanline_overlay(display_width, display_height);
             let overlay = self
                 .texture_creator
@@ -122,40 +342,165 @@ impl SDLSys {
             self.scanline_overlay_size = (display_width, display_height);
         }
 
-        if let Some(scanline_texture) = &self.scanline_texture {
-            self.canvas.copy(scanline_texture, None, None).unwrap();
+        if let (true, Some(scanline_texture)) = (self.scanlines, &self.scanline_texture) {
+            self.canvas.copy(scanline_texture, None, viewport).unwrap();
+        }
+
+        if self.crt && self.crt_overlay_size != viewport_size {
+            let (display_width, display_height) = viewport_size;
+            let crt_overlay = create_crt_overlay(display_width, display_height, self.width);
+            let overlay = self
+                .texture_creator
+                .create_texture_from_surface(&*crt_overlay)
+                .unwrap();
+            self.crt_texture = Some(overlay);
+            self.crt_overlay_size = (display_width, display_height);
+        }
+
+        if let (true, Some(crt_texture)) = (self.crt, &self.crt_texture) {
+            self.canvas.copy(crt_texture, None, viewport).unwrap();
         }
 
         self.canvas.present();
     }
 
-    pub fn sleep(&self, ms: u64) {
-        let duration = time::Duration::from_millis(ms);
-        thread::sleep(duration);
+    fn set_palette(&mut self, palette: &video::Palette) {
+        debug!("set_palette()");
+        let colors: Vec<Color> = palette
+            .entries
+            .iter()
+            .map(|c| Color::RGBA(c.r, c.g, c.b, c.a))
+            .collect();
+        let sdl_palette = Palette::with_colors(&colors).unwrap();
+
+        self.surface.set_palette(&sdl_palette).unwrap();
+        self.colors = colors;
+    }
+
+    /// Changes the size of the pages being displayed, keeping the palette
+    fn set_size(&mut self, width: usize, height: usize) {
+        debug!("set_size({}, {})", width, height);
+        self.width = width;
+        self.height = height;
+        self.surface = Surface::new(width as u32, height as u32, PixelFormatEnum::Index8).unwrap();
+        if !self.colors.is_empty() {
+            let sdl_palette = Palette::with_colors(&self.colors).unwrap();
+            self.surface.set_palette(&sdl_palette).unwrap();
+        }
+        self.viewport = fit_viewport(self.output_size, width, height);
+        let window_viewport = self.window_viewport();
+        self.input.set_viewport(window_viewport);
+        // The CRT grille depends on the number of pixels
+        self.crt_overlay_size = (0, 0);
+        self.crt_texture = None;
+    }
+
+    fn get_timestamp(&self) -> u64 {
+        self.clock.timestamp()
     }
 
-    pub fn get_timestamp(&self) -> u64 {
-        (self.timestamp.elapsed().as_millis() & u64::MAX as u128) as u64
+    fn sleep(&mut self, ms: u64) {
+        if self.precise_sleep {
+            self.clock.sleep_precise(ms);
+        } else {
+            self.clock.sleep(ms);
+        }
     }
 
-    pub fn start_audio(&mut self, audio: Arc<RwLock<mixer::Mixer>>) {
+    fn start_audio(&mut self, audio: Arc<RwLock<mixer::Mixer>>) {
+        if !self.audio {
+            debug!("Audio disabled");
+            return;
+        }
         debug!("Starting audio");
         let audio_subsystem = self.sdl_context.audio().unwrap();
 
         let desired_spec = AudioSpecDesired {
             freq: Some(mixer::SOUND_SAMPLE_RATE as i32),
             channels: Some(1),
-            samples: None,
+            samples: self.audio_samples,
         };
 
-        let device = audio_subsystem
-            .open_playback(None, &desired_spec, |spec| {
-                debug!("Actual spec: {:?}", spec);
-                mixer::MixerAudio(audio)
-            })
-            .unwrap();
+        let check_spec = |spec: &AudioSpec| {
+            debug!("Actual spec: {:?}", spec);
+            if let Some(samples) = desired_spec.samples {
+                if samples != spec.samples {
+                    warn!(
+                        "Requested an audio buffer of {} samples, got {}",
+                        samples, spec.samples
+                    );
+                }
+            }
+        };
 
-        device.resume();
+        let device = if self.audio_16bit {
+            let device = audio_subsystem
+                .open_playback(None, &desired_spec, |spec| {
+                    check_spec(&spec);
+                    mixer::MixerAudio16(audio)
+                })
+                .unwrap();
+            device.resume();
+            AudioOutput::Bits16(device)
+        } else {
+            let device = audio_subsystem
+                .open_playback(None, &desired_spec, |spec| {
+                    check_spec(&spec);
+                    mixer::MixerAudio(audio)
+                })
+                .unwrap();
+            device.resume();
+            AudioOutput::Bits8(device)
+        };
         self.audio_device = Some(device);
     }
+
+    /// Pauses and closes the audio device, so playback ends on a buffer
+    /// boundary instead of when the device happens to be dropped
+    fn stop_audio(&mut self) {
+        match self.audio_device.take() {
+            Some(AudioOutput::Bits8(device)) => device.pause(),
+            Some(AudioOutput::Bits16(device)) => device.pause(),
+            None => return,
+        }
+        debug!("Stopped audio");
+    }
+
+    fn audio(&self) -> bool {
+        self.audio
+    }
+
+    fn scanlines(&self) -> bool {
+        self.scanlines
+    }
+
+    fn set_scanlines(&mut self, scanlines: bool) {
+        debug!("set_scanlines({})", scanlines);
+        self.scanlines = scanlines;
+        // Regenerate the overlay for the current output size when re-enabled
+        self.scanline_overlay_size = (0, 0);
+        self.scanline_texture = None;
+    }
+
+    fn crt(&self) -> bool {
+        self.crt
+    }
+
+    fn set_crt(&mut self, crt: bool) {
+        debug!("set_crt({})", crt);
+        self.crt = crt;
+        self.crt_overlay_size = (0, 0);
+        self.crt_texture = None;
+    }
+
+    /// Current window geometry. While fullscreen, the size and position are
+    /// those of the display.
+    fn window_geometry(&self) -> Option<WindowGeometry> {
+        let window = self.canvas.window();
+        Some(WindowGeometry {
+            size: window.size(),
+            position: Some(window.position()),
+            fullscreen: window.fullscreen_state() != FullscreenType::Off,
+        })
+    }
 }
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+
+use log::debug;
+
+use crate::clock::Clock;
+use crate::mixer::Mixer;
+use crate::player::PlayerInput;
+use crate::video::{Page, Palette};
+
+/// Size, position and fullscreen state of the window, kept between runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowGeometry {
+    pub size: (u32, u32),
+    /// Top left corner, the window is centered if `None`
+    pub position: Option<(i32, i32)>,
+    pub fullscreen: bool,
+}
+
+impl Default for WindowGeometry {
+    fn default() -> WindowGeometry {
+        WindowGeometry {
+            size: (1280, 800),
+            position: None,
+            fullscreen: false,
+        }
+    }
+}
+
+/// What the interpreter needs from the platform: input, display, time and
+/// audio output. `SDLSys` plays in a window, `HeadlessSys` runs without
+/// any.
+pub trait System {
+    /// Handles pending events and returns the current input
+    fn process_events(&mut self) -> PlayerInput;
+
+    /// Presents a page with the palette given to `set_palette`
+    fn update_display(&mut self, page: &Page);
+
+    fn set_palette(&mut self, palette: &Palette);
+
+    /// Changes the size of the pages being displayed, keeping the palette
+    fn set_size(&mut self, width: usize, height: usize);
+
+    /// Milliseconds since the system was created
+    fn get_timestamp(&self) -> u64;
+
+    fn sleep(&mut self, ms: u64);
+
+    /// Starts playing the output of `mixer`
+    fn start_audio(&mut self, mixer: Arc<RwLock<Mixer>>);
+
+    /// Stops playing, so playback ends on a buffer boundary instead of when
+    /// the system happens to be dropped
+    fn stop_audio(&mut self);
+
+    /// Whether sounds are played at all
+    fn audio(&self) -> bool;
+
+    fn scanlines(&self) -> bool {
+        false
+    }
+
+    fn set_scanlines(&mut self, _scanlines: bool) {}
+
+    fn crt(&self) -> bool {
+        false
+    }
+
+    fn set_crt(&mut self, _crt: bool) {}
+
+    /// Geometry of the window to restore on the next run, if there is one
+    fn window_geometry(&self) -> Option<WindowGeometry> {
+        None
+    }
+}
+
+/// System without a window or audio device. Displayed pages are kept in a
+/// framebuffer, the input is queued by the caller and time only passes by
+/// sleeping, so runs are deterministic.
+pub struct HeadlessSys {
+    clock: Clock,
+    width: usize,
+    height: usize,
+    palette: Option<Palette>,
+    framebuffer: Vec<u8>,
+    frames_displayed: u64,
+    inputs: VecDeque<PlayerInput>,
+}
+
+impl HeadlessSys {
+    pub fn new(width: usize, height: usize) -> HeadlessSys {
+        HeadlessSys {
+            clock: Clock::virtual_clock(),
+            width,
+            height,
+            palette: None,
+            framebuffer: vec![0; width * height],
+            frames_displayed: 0,
+            inputs: VecDeque::new(),
+        }
+    }
+
+    /// Queue `input` to be returned by one call to `process_events`. Once
+    /// the queue is empty no key is pressed.
+    pub fn push_input(&mut self, input: PlayerInput) {
+        self.inputs.push_back(input);
+    }
+
+    /// Last displayed page, as palette indices
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Last displayed page as RGBA, with the palette in use then
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        self.palette
+            .unwrap_or_else(Palette::grayscale)
+            .to_rgba(&self.framebuffer)
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn palette(&self) -> Option<&Palette> {
+        self.palette.as_ref()
+    }
+
+    pub fn frames_displayed(&self) -> u64 {
+        self.frames_displayed
+    }
+}
+
+impl System for HeadlessSys {
+    fn process_events(&mut self) -> PlayerInput {
+        self.inputs.pop_front().unwrap_or_default()
+    }
+
+    fn update_display(&mut self, page: &Page) {
+        self.framebuffer
+            .copy_from_slice(&page.data[..self.width * self.height]);
+        self.frames_displayed += 1;
+    }
+
+    fn set_palette(&mut self, palette: &Palette) {
+        self.palette = Some(*palette);
+    }
+
+    fn set_size(&mut self, width: usize, height: usize) {
+        debug!("set_size({}, {})", width, height);
+        self.width = width;
+        self.height = height;
+        self.framebuffer = vec![0; width * height];
+    }
+
+    fn get_timestamp(&self) -> u64 {
+        self.clock.timestamp()
+    }
+
+    fn sleep(&mut self, ms: u64) {
+        self.clock.sleep(ms);
+    }
+
+    fn start_audio(&mut self, _mixer: Arc<RwLock<Mixer>>) {
+        debug!("No audio when headless");
+    }
+
+    fn stop_audio(&mut self) {}
+
+    fn audio(&self) -> bool {
+        false
+    }
+}
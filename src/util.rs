@@ -1,12 +1,85 @@
-pub fn resize(buffer: &[u8], factor: u32) -> Vec<u8> {
+/// Scales an indexed `width` x `height` image up by `factor`, repeating
+/// each pixel
+pub fn scale_nearest(buffer: &[u8], width: usize, height: usize, factor: u32) -> Vec<u8> {
     let factor = factor as usize;
-    let width = 320 * factor;
-    let height = 200 * factor;
-    let mut result = vec![0; width * height];
-    for j in 0..height {
-        for i in 0..width {
-            result[j * width + i] = buffer[j / factor * 320 + i / factor];
+    let mut result = Vec::with_capacity(width * height * factor * factor);
+    for row in buffer.chunks(width).take(height) {
+        let scaled: Vec<u8> = row
+            .iter()
+            .flat_map(|&p| std::iter::repeat_n(p, factor))
+            .collect();
+        for _ in 0..factor {
+            result.extend_from_slice(&scaled);
         }
     }
     result
 }
+
+/// Scales a `width` x `height` RGBA image to `new_width` x `new_height`,
+/// blending the four source pixels around the center of each new pixel
+pub fn scale_bilinear(
+    rgba: &[u8],
+    (width, height): (usize, usize),
+    (new_width, new_height): (usize, usize),
+) -> Vec<u8> {
+    // Source coordinate of the center of each new pixel, as the two pixels
+    // around it and the weight of the second one
+    let sample = |i: usize, size: usize, new_size: usize| {
+        let x = ((i as f32 + 0.5) * size as f32 / new_size as f32 - 0.5).max(0.0);
+        let x0 = (x as usize).min(size - 1);
+        let x1 = (x0 + 1).min(size - 1);
+        (x0, x1, x - x0 as f32)
+    };
+    let columns: Vec<_> = (0..new_width)
+        .map(|i| sample(i, width, new_width))
+        .collect();
+    let mut result = Vec::with_capacity(new_width * new_height * 4);
+    for j in 0..new_height {
+        let (y0, y1, fy) = sample(j, height, new_height);
+        for &(x0, x1, fx) in &columns {
+            for c in 0..4 {
+                let pixel = |x: usize, y: usize| rgba[(y * width + x) * 4 + c] as f32;
+                let top = pixel(x0, y0) * (1.0 - fx) + pixel(x1, y0) * fx;
+                let bottom = pixel(x0, y1) * (1.0 - fx) + pixel(x1, y1) * fx;
+                result.push((top * (1.0 - fy) + bottom * fy).round() as u8);
+            }
+        }
+    }
+    result
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 as used by PNG and zip
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continues a CRC-32 from a previous value, starting from 0
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c = CRC32_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
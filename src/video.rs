@@ -1,17 +1,24 @@
-use log::{debug, warn};
+use log::{debug, error, warn};
 use std::cmp;
 use std::io::{Cursor, Result};
+use std::mem;
+use std::thread;
 
 use byteorder::{BigEndian, ReadBytesExt};
 
-use crate::font::FONT;
-use crate::strings::STRINGS_TABLE_ENG;
-use crate::sys::SDLSys;
+use crate::overlay::{draw_glyph, Overlay, OVERLAY_HEIGHT, OVERLAY_WIDTH};
+use crate::state::VideoState;
+use crate::strings::{StringTable, STRINGS_TABLE_ENG};
+use crate::system::System;
 
 const MAX_POINTS: usize = 50;
-const NUM_COLORS: usize = 16;
+pub const NUM_COLORS: usize = 16;
+/// Largest multiple of the original 320x200 resolution rendered at
+pub const MAX_SCALE: u32 = 4;
+/// Polygons covering fewer pixels are filled faster than threads are started
+const PARALLEL_MIN_PIXELS: usize = 128 * 1024;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
@@ -19,11 +26,23 @@ pub struct Color {
     pub a: u8,
 }
 
+impl Color {
+    /// Perceived brightness, between 0 and 255
+    pub fn luma(&self) -> u8 {
+        ((self.r as u32 * 299 + self.g as u32 * 587 + self.b as u32 * 114) / 1000) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub struct Palette {
     pub entries: [Color; NUM_COLORS],
 }
 
 impl Palette {
+    /// Reads 16 colors stored as big-endian `0x0RGB` words with 4 bits per
+    /// component, the format of the palette resources on all supported
+    /// platforms. Components are widened to 6 bits like the VGA DAC, then
+    /// shifted to 8 bits.
     pub fn from_bytes(buffer: &[u8]) -> Palette {
         let mut entries = [Color {
             r: 0,
@@ -42,9 +61,72 @@ impl Palette {
         }
         Palette { entries }
     }
+
+    /// Converts indexed `pixels` to RGBA, indices outside of the palette
+    /// take the last color
+    pub fn to_rgba(&self, pixels: &[u8]) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(pixels.len() * 4);
+        for &p in pixels {
+            let c = &self.entries[(p as usize).min(NUM_COLORS - 1)];
+            rgba.extend_from_slice(&[c.r, c.g, c.b, c.a]);
+        }
+        rgba
+    }
+
+    /// Gray ramp, for when no palette has been set yet
+    pub fn grayscale() -> Palette {
+        let mut entries = [Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0xff,
+        }; NUM_COLORS];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let v = (i * 0x11) as u8;
+            *entry = Color {
+                r: v,
+                g: v,
+                b: v,
+                a: 0xff,
+            };
+        }
+        Palette { entries }
+    }
+
+    /// Indices of the darkest and the brightest color
+    pub fn darkest_and_brightest(&self) -> (u8, u8) {
+        let mut darkest = 0;
+        let mut brightest = 0;
+        for (i, c) in self.entries.iter().enumerate() {
+            if c.luma() < self.entries[darkest].luma() {
+                darkest = i;
+            }
+            if c.luma() > self.entries[brightest].luma() {
+                brightest = i;
+            }
+        }
+        (darkest as u8, brightest as u8)
+    }
+
+    /// Index of the color closest to `(r, g, b)`, and the squared distance
+    /// to it
+    pub fn nearest(&self, (r, g, b): (u8, u8, u8)) -> (u8, u32) {
+        let distance = |c: &Color| {
+            let dr = c.r as i32 - r as i32;
+            let dg = c.g as i32 - g as i32;
+            let db = c.b as i32 - b as i32;
+            (dr * dr + dg * dg + db * db) as u32
+        };
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i as u8, distance(c)))
+            .min_by_key(|&(_, d)| d)
+            .unwrap()
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Point {
     pub x: i32,
     pub y: i32,
@@ -73,6 +155,34 @@ impl Polygon {
         Ok(Polygon { bbw, bbh, points })
     }
 
+    /// Bounding box width and height
+    pub fn bounding_box(&self) -> (u32, u32) {
+        (self.bbw, self.bbh)
+    }
+
+    /// Vertices relative to the top left corner of the bounding box
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    /// A degenerate polygon which is drawn as a single point
+    pub fn is_point(&self) -> bool {
+        self.bbw == 0 && self.bbh == 1 && self.num_points() == 4
+    }
+
+    /// Vertices in page coordinates when the polygon is centered on `point`
+    pub fn vertices_at(&self, point: Point) -> Vec<Point> {
+        let x = point.x - self.bbw as i32 / 2;
+        let y = point.y - self.bbh as i32 / 2;
+        self.points
+            .iter()
+            .map(|p| Point {
+                x: x + p.x,
+                y: y + p.y,
+            })
+            .collect()
+    }
+
     fn num_points(&self) -> usize {
         self.points.len()
     }
@@ -101,6 +211,16 @@ fn calc_step(p1: &Point, p2: &Point) -> (i32, u16) {
 pub struct Video {
     pages: [Page; 4],
     pub palette_requested: Option<Palette>,
+    /// Palette last applied to the display
+    palette: Option<Palette>,
+    antialias: bool,
+    reported_invalid_page: bool,
+    render_threads: usize,
+    page_grid: bool,
+    string_tables: Vec<StringTable>,
+    /// Index in `string_tables` of the active language, `None` for English
+    language: Option<usize>,
+    show_missing_strings: bool,
     cur_page_ptr1: usize,
     cur_page_ptr2: usize,
     cur_page_ptr3: usize,
@@ -115,6 +235,14 @@ impl Video {
         Video {
             pages: [page.clone(), page.clone(), page.clone(), page],
             palette_requested: None,
+            palette: None,
+            antialias: false,
+            reported_invalid_page: false,
+            render_threads: 1,
+            page_grid: false,
+            string_tables: Vec::new(),
+            language: None,
+            show_missing_strings: false,
             cur_page_ptr1: 2,
             cur_page_ptr2: 2,
             cur_page_ptr3: 1,
@@ -123,30 +251,238 @@ impl Video {
         }
     }
 
-    pub fn update_display(&mut self, sys: &mut SDLSys, page_id: u8) {
+    /// Changes the page size, scaling the current contents of the pages
+    pub fn resize(&mut self, width: usize, height: usize) {
+        for page in self.pages.iter_mut() {
+            let mut data = vec![0; width * height];
+            for (y, row) in data.chunks_mut(width).enumerate() {
+                let src = &page.data[y * self.height / height * self.width..];
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    *pixel = src[x * self.width / width];
+                }
+            }
+            page.data = data;
+        }
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Copy of the pages, page pointers and palettes for a save state
+    pub fn save_state(&self) -> VideoState {
+        VideoState {
+            width: self.width,
+            height: self.height,
+            pages: self.pages.clone().map(|page| page.data),
+            page_ptrs: [self.cur_page_ptr1, self.cur_page_ptr2, self.cur_page_ptr3],
+            palette: self.palette,
+            palette_requested: self.palette_requested,
+        }
+    }
+
+    /// Restores the pages saved with `save_state`, scaled to the current
+    /// page size if they were saved at another one
+    pub fn load_state(&mut self, state: &VideoState) {
+        let (width, height) = (self.width, self.height);
+        for (page, data) in self.pages.iter_mut().zip(state.pages.iter()) {
+            page.data = data.clone();
+        }
+        self.width = state.width;
+        self.height = state.height;
+        if (width, height) != (state.width, state.height) {
+            self.resize(width, height);
+        }
+        let [ptr1, ptr2, ptr3] = state.page_ptrs;
+        self.cur_page_ptr1 = ptr1;
+        self.cur_page_ptr2 = ptr2;
+        self.cur_page_ptr3 = ptr3;
+        self.palette = state.palette;
+        self.palette_requested = state.palette_requested;
+    }
+
+    /// One of the four video pages, by index
+    pub fn page(&self, index: usize) -> &Page {
+        &self.pages[index]
+    }
+
+    /// Page `page_id` as RGBA with the palette of the last displayed frame,
+    /// width * height * 4 bytes. Ids are those of the scripts, 0xfe is the
+    /// displayed page and 0xff the one being drawn.
+    pub fn render_rgba(&self, page_id: u8) -> Vec<u8> {
+        let index = self.page_index(page_id).unwrap_or(0);
+        self.palette
+            .unwrap_or_else(Palette::grayscale)
+            .to_rgba(&self.pages[index].data[..self.width * self.height])
+    }
+
+    /// Index of the page currently displayed
+    pub fn displayed_page(&self) -> usize {
+        self.cur_page_ptr2
+    }
+
+    /// The palette used for the next displayed frame
+    pub fn palette(&self) -> Option<&Palette> {
+        self.palette_requested.as_ref().or(self.palette.as_ref())
+    }
+
+    /// Adds a language to switch to, and returns its index
+    pub fn add_string_table(&mut self, table: StringTable) -> usize {
+        self.string_tables.push(table);
+        self.string_tables.len() - 1
+    }
+
+    /// Selects the strings drawn by the scripts, `None` for English
+    pub fn set_language(&mut self, language: Option<usize>) {
+        self.language = language.filter(|&i| i < self.string_tables.len());
+    }
+
+    /// Switches to the next language, wrapping around to English
+    pub fn next_language(&mut self) {
+        self.language = match self.language {
+            None if !self.string_tables.is_empty() => Some(0),
+            Some(i) if i + 1 < self.string_tables.len() => Some(i + 1),
+            _ => None,
+        };
+    }
+
+    /// Draw a placeholder for strings that are missing, from the active
+    /// language or at all, instead of falling back to English or nothing
+    pub fn set_show_missing_strings(&mut self, show_missing_strings: bool) {
+        self.show_missing_strings = show_missing_strings;
+    }
+
+    pub fn language_name(&self) -> &str {
+        match self.language {
+            Some(i) => &self.string_tables[i].name,
+            None => "English",
+        }
+    }
+
+    /// Blend the left and right edges of solid polygons with the background.
+    /// Off by default since it changes the original rasterization.
+    pub fn set_antialias(&mut self, antialias: bool) {
+        self.antialias = antialias;
+    }
+
+    /// Fill large solid polygons using all available cores
+    pub fn set_parallel_render(&mut self, parallel: bool) {
+        self.render_threads = if parallel {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            1
+        };
+    }
+
+    /// Show all four pages shrunk into a 2x2 grid instead of the displayed
+    /// page, labeled with the page pointers referencing them
+    pub fn set_page_grid(&mut self, page_grid: bool) {
+        self.page_grid = page_grid;
+    }
+
+    pub fn page_grid(&self) -> bool {
+        self.page_grid
+    }
+
+    pub fn update_display<S: System>(&mut self, sys: &mut S, page_id: u8, overlay: &Overlay) {
         debug!("update_display({})", page_id);
+        self.flip(sys, page_id);
+        if overlay.is_empty() && !self.page_grid {
+            sys.update_display(&self.pages[self.cur_page_ptr2]);
+        } else {
+            self.redisplay(sys, overlay);
+        }
+    }
+
+    /// Selects the page to display and applies a requested palette, without
+    /// presenting anything. Used when frames are skipped.
+    pub fn flip<S: System>(&mut self, sys: &mut S, page_id: u8) {
         if page_id != 0xfe {
             if page_id == 0xff {
                 std::mem::swap(&mut self.cur_page_ptr3, &mut self.cur_page_ptr2);
             } else {
-                self.cur_page_ptr2 = self.get_page_id(page_id);
+                self.cur_page_ptr2 = self.get_page_id(page_id, "blit_frame_buffer");
             }
         }
 
         if let Some(palette) = self.palette_requested.take() {
             sys.set_palette(&palette);
+            self.palette = Some(palette);
+        }
+    }
+
+    /// Presents the currently displayed page again with the overlay on top
+    pub fn redisplay<S: System>(&mut self, sys: &mut S, overlay: &Overlay) {
+        sys.update_display(&self.compose(overlay));
+    }
+
+    /// The displayed page as it is presented, or the page grid, with the
+    /// overlay on top
+    pub fn compose(&self, overlay: &Overlay) -> Page {
+        let mut page = if self.page_grid {
+            self.compose_page_grid()
+        } else {
+            self.pages[self.cur_page_ptr2].clone()
+        };
+        overlay.draw(&mut page, self.width);
+        page
+    }
+
+    fn compose_page_grid(&self) -> Page {
+        let mut grid = Page::new(self.width * self.height);
+        let (cell_width, cell_height) = (self.width / 2, self.height / 2);
+        for (index, page) in self.pages.iter().enumerate() {
+            let left = index % 2 * cell_width;
+            let top = index / 2 * cell_height;
+            for y in 0..cell_height {
+                let src = &page.data[y * 2 * self.width..];
+                let dst = &mut grid.data[(top + y) * self.width + left..];
+                for x in 0..cell_width {
+                    dst[x] = src[x * 2];
+                }
+            }
+        }
+
+        let (background, foreground) = self.overlay_colors();
+        let mut labels = Overlay::new();
+        labels.rect(OVERLAY_WIDTH / 2, 0, 1, OVERLAY_HEIGHT, foreground);
+        labels.rect(0, OVERLAY_HEIGHT / 2, OVERLAY_WIDTH, 1, foreground);
+        for index in 0..self.pages.len() {
+            let mut label = format!("{}", index);
+            for (name, ptr) in &[
+                ("ptr1", self.cur_page_ptr1),
+                ("ptr2", self.cur_page_ptr2),
+                ("ptr3", self.cur_page_ptr3),
+            ] {
+                if *ptr == index {
+                    label.push(' ');
+                    label.push_str(name);
+                }
+            }
+            let x = index as u16 % 2 * (OVERLAY_WIDTH / 2) + 1;
+            let y = index as u16 / 2 * (OVERLAY_HEIGHT / 2) + 1;
+            labels.rect(x, y, label.len() as u16 * 8 + 2, 10, background);
+            labels.text(x + 1, y + 1, foreground, &label);
+        }
+        labels.draw(&mut grid, self.width);
+        grid
+    }
+
+    /// Background and foreground colors for overlays, picked from the active
+    /// palette so that they stay readable regardless of the scene
+    pub fn overlay_colors(&self) -> (u8, u8) {
+        match &self.palette {
+            Some(palette) => palette.darkest_and_brightest(),
+            None => (0x00, 0x0f),
         }
-        sys.update_display(&self.pages[self.cur_page_ptr2]);
     }
 
     pub fn change_page_ptr1(&mut self, page_id: u8) {
         debug!("change_page_ptr1({})", page_id);
-        self.cur_page_ptr1 = self.get_page_id(page_id);
+        self.cur_page_ptr1 = self.get_page_id(page_id, "select_video_page");
     }
 
     pub fn fill_video_page(&mut self, page_id: u8, color: u8) {
         debug!("fill_page({}, {})", page_id, color);
-        let page_id = self.get_page_id(page_id);
+        let page_id = self.get_page_id(page_id, "fill_video_page");
         let page = &mut self.pages[page_id];
 
         for b in page.data.iter_mut() {
@@ -168,12 +504,12 @@ impl Video {
             if src_page_id < 0xfe {
                 src_page_id &= 0xbf;
             }
-            let src_page = self.get_page(src_page_id);
-            let q = self.get_page_id(dst_page_id);
-            self.pages[q] = src_page.clone();
+            let p = self.get_page_id(src_page_id, "copy_video_page");
+            let q = self.get_page_id(dst_page_id, "copy_video_page");
+            self.pages[q] = self.pages[p].clone();
         } else {
-            let src_page = self.get_page(src_page_id & 3).clone();
-            let q = self.get_page_id(dst_page_id);
+            let src_page = self.pages[(src_page_id & 3) as usize].clone();
+            let q = self.get_page_id(dst_page_id, "copy_video_page");
             let mut src_i = 0;
             let mut dst_i = 0;
             if vscroll >= -(height - 1) && vscroll < height {
@@ -204,10 +540,24 @@ impl Video {
 
     pub fn draw_string_id(&mut self, color: u8, x: u16, y: u16, string_id: u16, scale: u32) {
         debug!("DrawString(0x{:04x}, {}, {}, {})", string_id, x, y, color);
-        if let Some(entry) = STRINGS_TABLE_ENG.get(&string_id) {
+        let translated = self
+            .language
+            .and_then(|i| self.string_tables[i].get(string_id))
+            .map(str::to_string);
+        let untranslated = self.language.is_some() && translated.is_none();
+        if let Some(entry) = translated {
+            self.draw_string(color, x, y, &entry, scale);
+        } else if self.show_missing_strings && untranslated {
+            let placeholder = format!("[UNTRANSLATED 0x{:03X}]", string_id);
+            self.draw_string(color, x, y, &placeholder, scale);
+        } else if let Some(entry) = STRINGS_TABLE_ENG.get(&string_id) {
             self.draw_string(color, x, y, entry, scale);
         } else {
             warn!("String with id 0x{:03x} not found", string_id);
+            if self.show_missing_strings {
+                let placeholder = format!("[MISSING 0x{:03X}]", string_id);
+                self.draw_string(color, x, y, &placeholder, scale);
+            }
         }
     }
 
@@ -226,8 +576,8 @@ impl Video {
         }
     }
 
+    /// Read a polygon or a polygon hierarchy without drawing it
     pub fn read_polygons(
-        &mut self,
         buffer: &mut Cursor<&[u8]>,
         color: u8,
         zoom: u32,
@@ -245,7 +595,7 @@ impl Video {
         } else {
             i &= 0x3f;
             if i == 2 {
-                polygons.append(&mut self.read_polygon_hierarchy(buffer, zoom, point)?);
+                polygons.append(&mut Video::read_polygon_hierarchy(buffer, zoom, point)?);
             } else {
                 warn!("read_polygons: i != 2 ({})", i);
             }
@@ -254,7 +604,6 @@ impl Video {
     }
 
     fn read_polygon_hierarchy(
-        &mut self,
         buffer: &mut Cursor<&[u8]>,
         zoom: u32,
         point: Point,
@@ -289,7 +638,7 @@ impl Video {
             let bak_pos = buffer.position();
             buffer.set_position(offset as u64 * 2);
 
-            polygons.append(&mut self.read_polygons(buffer, color, zoom, po)?);
+            polygons.append(&mut Video::read_polygons(buffer, color, zoom, po)?);
 
             buffer.set_position(bak_pos);
         }
@@ -303,14 +652,14 @@ impl Video {
         zoom: u32,
         point: Point,
     ) -> Result<()> {
-        for (polygon, color, point) in self.read_polygons(buffer, color, zoom, point)? {
+        for (polygon, color, point) in Video::read_polygons(buffer, color, zoom, point)? {
             self.fill_polygon(polygon, color, point);
         }
         Ok(())
     }
 
     fn fill_polygon(&mut self, polygon: Polygon, color: u8, point: Point) {
-        if polygon.bbw == 0 && polygon.bbh == 1 && polygon.num_points() == 4 {
+        if polygon.is_point() {
             self.draw_point(color, point);
             return;
         }
@@ -338,8 +687,13 @@ impl Video {
         let mut cpt1 = (x1 as u64) << 16;
         let mut cpt2 = (x2 as u64) << 16;
 
+        // Spans of solid polygons are collected and filled on several threads
+        // when parallel rendering is enabled
+        let mut spans = Vec::new();
+        let collect_spans = self.render_threads > 1 && color <= 0x0f && !self.antialias;
+
         let mut num_points = polygon.num_points();
-        loop {
+        'edges: loop {
             num_points -= 2;
             if num_points == 0 {
                 break;
@@ -362,6 +716,8 @@ impl Video {
                         x1 = (cpt1 >> 16) as i32;
                         x2 = (cpt2 >> 16) as i32;
                         if x1 < width && x2 >= 0 {
+                            let left_edge = x1 >= 0 && x1 < x2;
+                            let right_edge = x2 < width && x1 < x2;
                             if x1 < 0 {
                                 x1 = 0;
                             }
@@ -369,6 +725,16 @@ impl Video {
                                 x2 = width - 1;
                             }
                             match color {
+                                0..=0x0f if collect_spans => spans.push((hliney, x1, x2)),
+                                0..=0x0f if self.antialias => {
+                                    // Coverage of the edge pixels from the
+                                    // fractional part of the edge positions
+                                    let left = (0xffff - (cpt1 & 0xffff) as u32, left_edge);
+                                    let right = ((cpt2 & 0xffff) as u32, right_edge);
+                                    self.draw_line_n_antialiased(
+                                        x1, x2, color, hliney, left, right,
+                                    );
+                                }
                                 0..=0x0f => self.draw_line_n(x1, x2, color, hliney),
                                 0x11..=0xff => self.draw_line_p(x1, x2, color, hliney),
                                 0x10 => self.draw_line_blend(x1, x2, color, hliney),
@@ -379,54 +745,125 @@ impl Video {
                     cpt2 = (cpt2 as i64 + step2 as i64) as u64;
                     hliney += 1;
                     if hliney >= height {
-                        return;
+                        break 'edges;
                     }
                 }
             }
         }
+        if !spans.is_empty() {
+            self.fill_spans(&spans, color);
+        }
     }
 
-    fn draw_line_n(&mut self, x1: i32, x2: i32, color: u8, hliney: i32) {
-        debug!("draw_line_n({}, {}, {})", x1, x2, color);
-        let xmax = cmp::max(x1, x2);
-        let xmin = cmp::min(x1, x2);
-        let mut offset = (hliney * self.width as i32 + xmin) as usize;
+    /// Fills horizontal spans, one per row in increasing row order. Large
+    /// fills are split into bands of rows which are filled in parallel.
+    fn fill_spans(&mut self, spans: &[(i32, i32, i32)], color: u8) {
+        let pixels: usize = spans
+            .iter()
+            .map(|&(_, x1, x2)| (x1 - x2).unsigned_abs() as usize + 1)
+            .sum();
+        if pixels < PARALLEL_MIN_PIXELS {
+            for &(y, x1, x2) in spans {
+                self.draw_line_n(x1, x2, color, y);
+            }
+            return;
+        }
 
-        let mut w = (xmax - xmin + 1) as u16;
+        let width = self.width;
+        let spans_per_thread = spans.len().div_ceil(self.render_threads);
+        let mut rest = &mut self.pages[self.cur_page_ptr1].data[..];
+        let mut first_row = 0;
+        thread::scope(|scope| {
+            for band in spans.chunks(spans_per_thread) {
+                let end_row = band[band.len() - 1].0 as usize + 1;
+                let (rows, tail) = mem::take(&mut rest).split_at_mut((end_row - first_row) * width);
+                rest = tail;
+                let band_first_row = first_row;
+                first_row = end_row;
+                scope.spawn(move || {
+                    for &(y, x1, x2) in band {
+                        let offset = (y as usize - band_first_row) * width;
+                        let (xmin, xmax) = (x1.min(x2) as usize, x1.max(x2) as usize);
+                        rows[offset + xmin..=offset + xmax].fill(color);
+                    }
+                });
+            }
+        });
+    }
 
-        while w > 0 {
-            self.pages[self.cur_page_ptr1].data[offset] = color;
-            offset += 1;
-            w -= 1;
+    fn draw_line_n_antialiased(
+        &mut self,
+        x1: i32,
+        x2: i32,
+        color: u8,
+        hliney: i32,
+        (left_coverage, left_edge): (u32, bool),
+        (right_coverage, right_edge): (u32, bool),
+    ) {
+        let offset = (hliney * self.width as i32) as usize;
+        let left = offset + x1 as usize;
+        let right = offset + x2 as usize;
+        let left_bg = self.pages[self.cur_page_ptr1].data[left];
+        let right_bg = self.pages[self.cur_page_ptr1].data[right];
+        self.draw_line_n(x1, x2, color, hliney);
+        if left_edge {
+            self.blend_pixel(left, color, left_bg, left_coverage);
+        }
+        if right_edge {
+            self.blend_pixel(right, color, right_bg, right_coverage);
         }
     }
 
+    /// Writes the palette color closest to `color` and `background` mixed
+    /// by `coverage`, which is a 16 bit fraction of `color`
+    fn blend_pixel(&mut self, offset: usize, color: u8, background: u8, coverage: u32) {
+        let palette = match self.palette() {
+            Some(palette) => palette,
+            None => return,
+        };
+        if background as usize >= NUM_COLORS {
+            return;
+        }
+        let fg = palette.entries[color as usize];
+        let bg = palette.entries[background as usize];
+        let mix =
+            |a: u8, b: u8| ((a as u32 * coverage + b as u32 * (0xffff - coverage)) / 0xffff) as u8;
+        let (nearest, _) = palette.nearest((mix(fg.r, bg.r), mix(fg.g, bg.g), mix(fg.b, bg.b)));
+        self.pages[self.cur_page_ptr1].data[offset] = nearest;
+    }
+
+    /// Offsets of the first and last pixel of a span in a page
+    fn span(&self, x1: i32, x2: i32, hliney: i32) -> (usize, usize) {
+        let offset = (hliney * self.width as i32) as usize;
+        (
+            offset + cmp::min(x1, x2) as usize,
+            offset + cmp::max(x1, x2) as usize,
+        )
+    }
+
+    fn draw_line_n(&mut self, x1: i32, x2: i32, color: u8, hliney: i32) {
+        debug!("draw_line_n({}, {}, {})", x1, x2, color);
+        let (start, end) = self.span(x1, x2, hliney);
+        self.pages[self.cur_page_ptr1].data[start..=end].fill(color);
+    }
+
+    /// Copies the span from the background in page 0
     fn draw_line_p(&mut self, x1: i32, x2: i32, color: u8, hliney: i32) {
         debug!("draw_line_p({}, {}, {})", x1, x2, color);
-        let xmax = cmp::max(x1, x2);
-        let xmin = cmp::min(x1, x2);
-        let mut offset = (hliney * self.width as i32 + xmin) as usize;
-
-        let mut w = (xmax - xmin + 1) as u16;
-        while w > 0 {
-            self.pages[self.cur_page_ptr1].data[offset] = self.pages[0].data[offset];
-            offset += 1;
-            w -= 1;
+        if self.cur_page_ptr1 == 0 {
+            return;
         }
+        let (start, end) = self.span(x1, x2, hliney);
+        let (background, pages) = self.pages.split_at_mut(1);
+        let page = &mut pages[self.cur_page_ptr1 - 1];
+        page.data[start..=end].copy_from_slice(&background[0].data[start..=end]);
     }
 
     fn draw_line_blend(&mut self, x1: i32, x2: i32, color: u8, hliney: i32) {
         debug!("draw_line_blend({}, {}, {})", x1, x2, color);
-        let xmax = cmp::max(x1, x2);
-        let xmin = cmp::min(x1, x2);
-        let mut offset = (hliney * self.width as i32 + xmin) as usize;
-
-        let mut w = (xmax - xmin + 1) as u16;
-        while w > 0 {
-            let p = self.pages[self.cur_page_ptr1].data[offset];
-            self.pages[self.cur_page_ptr1].data[offset] = (p & 0x77) | 0x08;
-            offset += 1;
-            w -= 1;
+        let (start, end) = self.span(x1, x2, hliney);
+        for p in self.pages[self.cur_page_ptr1].data[start..=end].iter_mut() {
+            *p = (*p & 0x77) | 0x08;
         }
     }
 
@@ -453,43 +890,90 @@ impl Video {
         scale: u32,
     ) {
         if x <= 39 && y <= 192 {
-            let offset = (character as u8 - b' ') as usize * 8;
-
-            let font_char = &FONT[offset..offset + 8];
-
-            let x = x as usize;
-            let y = y as usize;
             let scale = scale as usize;
-            let mut p = x * 8 * scale + y * scale * self.width;
-
-            let buffer = &mut self.pages[page_off].data;
-
-            for j in 0..8 * scale {
-                for i in 0..8 * scale {
-                    let ch = font_char[j / scale] << (i / scale);
-                    if ch & 0x80 > 0 {
-                        buffer[p + i] = color;
-                    }
-                }
-                p += self.width;
-            }
+            draw_glyph(
+                &mut self.pages[page_off].data,
+                self.width,
+                character,
+                x as usize * 8 * scale,
+                y as usize * scale,
+                color,
+                scale,
+            );
         }
     }
 
-    fn get_page_id(&self, page_id: u8) -> usize {
-        let page_id = page_id as usize;
+    /// Page index of a page id from the scripts, `None` if the id is invalid
+    fn page_index(&self, page_id: u8) -> Option<usize> {
         match page_id {
-            0..=3 => page_id,
-            0xff => self.cur_page_ptr3,
-            0xfe => self.cur_page_ptr2,
-            _ => {
-                warn!("get_page() id != [0, 1, 2, 3, 0xfe, 0xff]");
+            0..=3 => Some(page_id as usize),
+            0xff => Some(self.cur_page_ptr3),
+            0xfe => Some(self.cur_page_ptr2),
+            _ => None,
+        }
+    }
+
+    /// Resolves a page id from the scripts to a page index. Invalid ids fall
+    /// back to page 0 like the original, and are logged once since they point
+    /// to a bug in the script or the interpreter.
+    fn get_page_id(&mut self, page_id: u8, operation: &str) -> usize {
+        match self.page_index(page_id) {
+            Some(index) => index,
+            None => {
+                if self.reported_invalid_page {
+                    debug!("{}: invalid page id 0x{:02x}", operation, page_id);
+                } else {
+                    error!(
+                        "{}: invalid page id 0x{:02x}, using page 0 instead",
+                        operation, page_id
+                    );
+                    self.reported_invalid_page = true;
+                }
                 0
             }
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_words_are_widened_like_the_vga_dac() {
+        // 0x0RGB words, the top nibble is unused
+        let mut colors = [0; 32];
+        colors[..6].copy_from_slice(&[0x0f, 0xff, 0x01, 0x23, 0xf8, 0x00]);
+        let palette = Palette::from_bytes(&colors);
+        let rgb = |i: usize| {
+            let c = palette.entries[i];
+            (c.r, c.g, c.b, c.a)
+        };
+        assert_eq!(rgb(0), (252, 252, 252, 255));
+        assert_eq!(rgb(1), (16, 32, 48, 255));
+        assert_eq!(rgb(2), (136, 0, 0, 255));
+        assert_eq!(rgb(15), (0, 0, 0, 255));
+    }
 
-    fn get_page(&self, page_id: u8) -> &Page {
-        &self.pages[self.get_page_id(page_id)]
+    #[test]
+    fn renders_pages_with_the_displayed_palette() {
+        let mut video = Video::new(320, 200);
+        video.fill_video_page(1, 5);
+        // Without a palette the colors are a gray ramp
+        assert_eq!(video.render_rgba(1)[..4], [0x55, 0x55, 0x55, 0xff]);
+
+        let mut colors = [0; 32];
+        colors[10] = 0x0f;
+        colors[11] = 0x80;
+        video.palette = Some(Palette::from_bytes(&colors));
+        let rgba = video.render_rgba(1);
+        assert_eq!(rgba.len(), 320 * 200 * 4);
+        assert!(rgba.chunks(4).all(|c| c == [252, 136, 0, 255]));
+        assert_eq!(video.render_rgba(0)[..4], [0, 0, 0, 255]);
+
+        // 0xff is the page being drawn, page 1 at startup, and invalid ids
+        // show page 0 like the scripts
+        assert_eq!(video.render_rgba(0xff), rgba);
+        assert_eq!(video.render_rgba(0x42), video.render_rgba(0));
     }
 }
@@ -1,41 +1,47 @@
-use log::{debug, trace, warn};
+use log::{debug, error, info, trace, warn};
 use rand::random;
 use std::cmp;
-use std::io::{Cursor, Result};
-use std::sync::mpsc::Receiver;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::thread;
 
-use crate::input::UserInput;
+use crate::capture;
+use crate::capture::{CaptureOutput, FrameDump};
+use crate::debugger::{self, Command};
+use crate::demo::{Demo, DemoRecorder};
+use crate::disasm::Instruction;
 use crate::mixer;
 use crate::mixer::{Mixer, MixerAudio, MixerChunk};
 use crate::opcode::Opcode;
+use crate::overlay::Overlay;
 use crate::parts;
-use crate::player::PlayerDirection;
-use crate::resource::Resource;
+use crate::player::{PlayerDirection, PlayerInput};
+use crate::resource::{EntryType, Resource, ResourceError};
+use crate::settings::Settings;
 use crate::sfxplayer::SfxPlayer;
-use crate::sys::SDLSys;
+use crate::state::{
+    variable_name, SaveState, ThreadState, Thumbnail, NUM_THREADS, NUM_VARIABLES,
+    VM_VARIABLE_HERO_ACTION, VM_VARIABLE_HERO_ACTION_POS_MASK, VM_VARIABLE_HERO_POS_JUMP_DOWN,
+    VM_VARIABLE_HERO_POS_LEFT_RIGHT, VM_VARIABLE_HERO_POS_MASK, VM_VARIABLE_HERO_POS_UP_DOWN,
+    VM_VARIABLE_LAST_KEYCHAR, VM_VARIABLE_MUS_MARK, VM_VARIABLE_PAUSE_SLICES,
+    VM_VARIABLE_RANDOM_SEED, VM_VARIABLE_SCROLL_Y,
+};
+use crate::sync;
+use crate::system::System;
 use crate::util;
-use crate::video::{Palette, Point, Video};
+use crate::video::{Palette, Point, Video, MAX_SCALE};
 
-const NUM_VARIABLES: usize = 256;
-const NUM_THREADS: usize = 64;
 const SET_INACTIVE_THREAD: usize = 0xfffe;
 const INACTIVE_THREAD: usize = 0xffff;
 const COLOR_BLACK: u8 = 0xff;
 const DEFAULT_ZOOM: u32 = 0x40;
 const STACK_SIZE: usize = 0xff;
-
-const VM_VARIABLE_RANDOM_SEED: usize = 0x3c;
-const VM_VARIABLE_LAST_KEYCHAR: usize = 0xda;
-const VM_VARIABLE_HERO_POS_UP_DOWN: usize = 0xe5;
-const VM_VARIABLE_MUS_MARK: usize = 0xf4;
-const VM_VARIABLE_SCROLL_Y: usize = 0xf9;
-const VM_VARIABLE_HERO_ACTION: usize = 0xfa;
-const VM_VARIABLE_HERO_POS_JUMP_DOWN: usize = 0xfb;
-const VM_VARIABLE_HERO_POS_LEFT_RIGHT: usize = 0xfc;
-const VM_VARIABLE_HERO_POS_MASK: usize = 0xfd;
-const VM_VARIABLE_HERO_ACTION_POS_MASK: usize = 0xfe;
-const VM_VARIABLE_PAUSE_SLICES: usize = 0xff;
+const CHECKSUM_FLUSH_FRAMES: u64 = 50;
+/// Rate of the time base the scripts pause in, each slice is 20 ms
+pub const ORIGINAL_FRAME_RATE: u32 = 50;
 
 #[derive(Copy, Clone)]
 struct Thread {
@@ -56,12 +62,48 @@ impl Thread {
     }
 }
 
+/// Hero related variables, as set from the player input each frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeroState {
+    /// -1 left, 1 right, 0 neither
+    pub pos_left_right: i16,
+    /// -1 up, 1 down, 0 neither
+    pub pos_up_down: i16,
+    /// 1 while the action button is held
+    pub action: i16,
+    /// Vertical offset applied when copying pages
+    pub scroll_y: i16,
+}
+
 pub enum VideoBufferSeg {
     Cinematic,
     Video2,
 }
 
-pub struct VirtualMachine {
+/// How the time between frames is kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacingMode {
+    /// Each blit sleeps until the pause requested by the scripts has passed
+    /// on the system clock, like the original
+    Realtime,
+    /// Blits never sleep and count as exactly the requested pause, so the
+    /// caller decides how fast the game runs, one frame per `Engine::step`
+    Unpaced,
+}
+
+fn entry_type_abbrev(entry_type: EntryType) -> &'static str {
+    match entry_type {
+        EntryType::Sound => "SND",
+        EntryType::Music => "MUS",
+        EntryType::PolyAnim => "BMP",
+        EntryType::Palette => "PAL",
+        EntryType::Bytecode => "COD",
+        EntryType::PolyCinematic => "VID",
+        EntryType::Unknown(_) => "???",
+    }
+}
+
+pub struct VirtualMachine<S: System> {
     variables: [i16; NUM_VARIABLES],
     threads: [Thread; NUM_THREADS],
     mixer: Arc<RwLock<Mixer>>,
@@ -74,24 +116,61 @@ pub struct VirtualMachine {
     goto_next_thread: bool,
     video_buffer_seg: VideoBufferSeg,
     script_stack_calls: [usize; STACK_SIZE],
-    sys: SDLSys,
-    user_input: UserInput,
+    sys: S,
     last_timestamp: u64,
-    variable_receiver: Option<Receiver<i16>>,
+    music_timestamp: u64,
+    pacing: PacingMode,
+    /// Pause slices per second when paced in real time
+    frame_rate: u32,
+    /// Music stepped by the frames even when paced in real time
+    frame_synced_music: bool,
+    /// Blits don't sleep while the fast-forward key is held
+    fast_forward: bool,
+    paused: bool,
+    /// Milliseconds of game time, the sum of the pauses of blitted frames
+    /// at the original frame rate. Frame synced music is played by it.
+    game_time: u64,
     scale: u32,
+    skip_protection: bool,
+    autosave_path: Option<PathBuf>,
+    compress_saves: bool,
+    save_dir: PathBuf,
+    pending_code: VecDeque<Option<char>>,
+    vu_meter: bool,
+    memory_usage: bool,
+    palette_overlay: bool,
+    checksum_log: Option<BufWriter<File>>,
+    demo: Option<Demo>,
+    demo_recorder: Option<DemoRecorder>,
+    injected_input: VecDeque<PlayerInput>,
+    frame_number: u64,
+    capture_output: CaptureOutput,
+    frame_dump: Option<FrameDump>,
+    max_frameskip: u32,
+    skipped_frames: u32,
+    settings: Option<(Settings, PathBuf)>,
+    /// Game data that failed to load while running, which ends the game
+    error: Option<ResourceError>,
+    debug: bool,
+    /// Stop in the debug console before the next instruction
+    debug_break: bool,
 }
 
-impl VirtualMachine {
+impl<S: System> VirtualMachine<S> {
+    /// Creates the interpreter. The scripts are seeded with `seed`, or a
+    /// random value if it is `None`.
     pub fn new(
         resource: Resource,
         video: Video,
-        mut sys: SDLSys,
-        user_input: UserInput,
+        mut sys: S,
         scale: u32,
-    ) -> VirtualMachine {
+        seed: Option<i16>,
+    ) -> VirtualMachine<S> {
         let mut variables = [0; NUM_VARIABLES];
         variables[0x54] = 0x81;
-        variables[VM_VARIABLE_RANDOM_SEED] = random::<i16>();
+        let seed = seed.unwrap_or_else(random::<i16>);
+        info!("Random seed {}", seed);
+        variables[VM_VARIABLE_RANDOM_SEED] = seed;
         let mixer = Arc::new(RwLock::new(Mixer::new()));
         sys.start_audio(mixer.clone());
         VirtualMachine {
@@ -108,33 +187,325 @@ impl VirtualMachine {
             video_buffer_seg: VideoBufferSeg::Cinematic,
             script_stack_calls: [0; STACK_SIZE],
             sys,
-            user_input,
             last_timestamp: 0,
-            variable_receiver: None,
+            music_timestamp: 0,
+            pacing: PacingMode::Realtime,
+            frame_rate: ORIGINAL_FRAME_RATE,
+            frame_synced_music: false,
+            fast_forward: false,
+            paused: false,
+            game_time: 0,
             scale,
+            skip_protection: false,
+            autosave_path: None,
+            compress_saves: true,
+            save_dir: PathBuf::from("."),
+            pending_code: VecDeque::new(),
+            vu_meter: false,
+            memory_usage: false,
+            palette_overlay: false,
+            checksum_log: None,
+            demo: None,
+            demo_recorder: None,
+            injected_input: VecDeque::new(),
+            capture_output: CaptureOutput::default(),
+            frame_dump: None,
+            settings: None,
+            error: None,
+            max_frameskip: 0,
+            skipped_frames: 0,
+            frame_number: 0,
+            debug: false,
+            debug_break: false,
         }
     }
 
+    pub fn mixer(&self) -> &Arc<RwLock<Mixer>> {
+        &self.mixer
+    }
+
+    pub fn sys(&self) -> &S {
+        &self.sys
+    }
+
+    pub fn sys_mut(&mut self) -> &mut S {
+        &mut self.sys
+    }
+
+    pub fn variable(&self, var: usize) -> i16 {
+        self.variables[var]
+    }
+
     pub fn set_variable(&mut self, var: usize, value: i16) {
         self.variables[var] = value;
     }
 
-    pub fn init_for_part(&mut self, part_id: u16) {
+    /// Stops the music and the sounds and closes the audio device
+    pub fn stop_audio(&mut self) {
+        self.player.stop();
+        sync::write(&self.mixer).stop_all();
+        self.sys.stop_audio();
+    }
+
+    /// Renders at `scale` times the original resolution from the next frame
+    /// on. The current page contents are scaled along.
+    pub fn set_scale(&mut self, scale: u32) {
+        if scale == self.scale {
+            return;
+        }
+        debug!("set_scale({})", scale);
+        let (width, height) = (320 * scale as usize, 200 * scale as usize);
+        self.video.resize(width, height);
+        self.sys.set_size(width, height);
+        self.scale = scale;
+    }
+
+    /// Step the music from the game clock instead of a timer thread, which
+    /// makes the music marks seen by the scripts reproducible
+    pub fn set_frame_synced_music(&mut self, frame_synced: bool) {
+        self.frame_synced_music = frame_synced;
+        self.update_music_sync();
+    }
+
+    /// Unpaced also steps the music once per frame from the game time,
+    /// since a timer thread would keep playing it in real time
+    pub fn set_pacing(&mut self, pacing: PacingMode) {
+        self.pacing = pacing;
+        self.update_music_sync();
+        // The timestamps of the two modes come from different clocks
+        self.restart_frame_timing();
+    }
+
+    fn update_music_sync(&mut self) {
+        let frame_synced = self.frame_synced_music || self.pacing == PacingMode::Unpaced;
+        self.player.set_frame_synced(frame_synced);
+        if self.paused {
+            self.player.pause();
+        }
+    }
+
+    /// Runs the pause slices of the scripts at `frame_rate` per second
+    /// instead of `ORIGINAL_FRAME_RATE`, 100 runs the game twice as fast.
+    /// 0 runs unpaced.
+    pub fn set_frame_rate(&mut self, frame_rate: u32) {
+        if frame_rate == 0 {
+            self.set_pacing(PacingMode::Unpaced);
+            return;
+        }
+        self.frame_rate = frame_rate;
+        if self.pacing == PacingMode::Unpaced {
+            self.set_pacing(PacingMode::Realtime);
+        }
+        // Measure the next frame from now, so the time spent before the
+        // change doesn't count against the new pause
+        self.restart_frame_timing();
+    }
+
+    /// Measures the next frame and the music played from now on
+    fn restart_frame_timing(&mut self) {
+        self.last_timestamp = self.timestamp();
+        self.music_timestamp = self.game_time;
+    }
+
+    /// Milliseconds since the start, from the system clock or from the game
+    /// time when unpaced
+    fn timestamp(&self) -> u64 {
+        match self.pacing {
+            PacingMode::Realtime => self.sys.get_timestamp(),
+            PacingMode::Unpaced => self.game_time,
+        }
+    }
+
+    pub fn hero_state(&self) -> HeroState {
+        HeroState {
+            pos_left_right: self.variables[VM_VARIABLE_HERO_POS_LEFT_RIGHT],
+            pos_up_down: self.variables[VM_VARIABLE_HERO_POS_UP_DOWN],
+            action: self.variables[VM_VARIABLE_HERO_ACTION],
+            scroll_y: self.variables[VM_VARIABLE_SCROLL_Y],
+        }
+    }
+
+    /// Go straight to the introduction whenever the protection screens are
+    /// requested. The password screen is only entered when the player asks
+    /// for it with the code key, so it is left alone.
+    pub fn set_skip_protection(&mut self, skip_protection: bool) {
+        self.skip_protection = skip_protection;
+    }
+
+    /// Write a save state to `path` every time a part is started
+    pub fn set_autosave_path(&mut self, path: Option<PathBuf>) {
+        self.autosave_path = path;
+    }
+
+    /// Compress written save states, on by default. Uncompressed ones are
+    /// easier to inspect.
+    pub fn set_compress_saves(&mut self, compress_saves: bool) {
+        self.compress_saves = compress_saves;
+    }
+
+    /// Directory of the save<slot>.aws files written with F5 and read with F9
+    pub fn set_save_dir(&mut self, save_dir: PathBuf) {
+        self.save_dir = save_dir;
+    }
+
+    fn slot_path(&self, slot: i8) -> PathBuf {
+        self.save_dir.join(format!("save{}.aws", slot))
+    }
+
+    /// Saves the game to `slot`, errors are logged
+    pub fn save_slot(&self, slot: i8) {
+        let path = self.slot_path(slot);
+        let state = self.save_state();
+        let result = if self.compress_saves {
+            state.save(&path)
+        } else {
+            state.save_uncompressed(&path)
+        };
+        match result {
+            Ok(()) => info!("Saved slot {} to {}", slot, path.display()),
+            Err(e) => error!("Could not save {}: {}", path.display(), e),
+        }
+    }
+
+    /// Resumes the game saved in `slot`. A missing or unreadable save is
+    /// logged and the game goes on.
+    pub fn load_slot(&mut self, slot: i8) {
+        let path = self.slot_path(slot);
+        match SaveState::load(&path) {
+            Ok(state) => {
+                let result = self.load_state(&state);
+                self.check_resource(result);
+                info!("Loaded slot {} from {}", slot, path.display());
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                warn!("Nothing saved in slot {} ({})", slot, path.display())
+            }
+            Err(e) => warn!("Could not load {}: {}", path.display(), e),
+        }
+    }
+
+    /// Log a CRC-32 of the displayed page for every blitted frame to `path`,
+    /// one `frame_number: checksum` line per frame
+    pub fn set_checksum_log(&mut self, path: &Path) -> Result<()> {
+        self.checksum_log = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    /// Play the game input from `demo` instead of the keyboard, starting with
+    /// its random seed. The keyboard takes over at the end of the demo.
+    pub fn set_demo(&mut self, demo: Demo) {
+        info!("Random seed {} from the demo", demo.seed);
+        self.variables[VM_VARIABLE_RANDOM_SEED] = demo.seed;
+        self.demo = Some(demo);
+    }
+
+    /// Write the game input of every frame to `recorder`
+    pub fn set_demo_recorder(&mut self, recorder: DemoRecorder) {
+        self.demo_recorder = Some(recorder);
+    }
+
+    /// Directory and file names used for screenshots and page dumps
+    pub fn set_capture_output(&mut self, capture_output: CaptureOutput) {
+        self.capture_output = capture_output;
+    }
+
+    /// Skip presenting up to `max_frameskip` frames in a row when the game
+    /// falls behind, to keep up on slow machines
+    pub fn set_max_frameskip(&mut self, max_frameskip: u32) {
+        self.max_frameskip = max_frameskip;
+    }
+
+    /// Write every displayed frame to a numbered PNG file
+    pub fn set_frame_dump(&mut self, frame_dump: Option<FrameDump>) {
+        self.frame_dump = frame_dump;
+    }
+
+    /// Allow stopping the scripts in the debug console with the ` key
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Write `settings` to `path` when they are changed while playing
+    pub fn set_settings(&mut self, settings: Settings, path: PathBuf) {
+        self.settings = Some((settings, path));
+    }
+
+    fn save_settings(&mut self) {
+        if let Some((settings, path)) = self.settings.as_mut() {
+            settings.scanlines = self.sys.scanlines();
+            settings.crt = self.sys.crt();
+            settings.scale = self.scale;
+            settings.mute = sync::read(&self.mixer).is_muted();
+            // The size of a fullscreen window is the display's, keep the one
+            // to return to
+            if let Some(window) = self.sys.window_geometry() {
+                if !window.fullscreen {
+                    settings.window_size = Some(window.size);
+                    settings.window_position = window.position;
+                }
+                settings.fullscreen = window.fullscreen;
+            }
+            if let Err(e) = settings.save(path) {
+                error!("Could not save {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Frames displayed since the start
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number
+    }
+
+    pub fn current_part(&self) -> u16 {
+        self.resource.current_part_id
+    }
+
+    /// Flushes the checksum log, waits for dumped frames to be written and
+    /// saves the settings with the window geometry
+    pub fn finish_output(&mut self) {
+        self.save_settings();
+        if let Some(mut log) = self.checksum_log.take() {
+            if let Err(e) = log.flush() {
+                warn!("Could not write checksum log: {}", e);
+            }
+        }
+        if let Some(mut frame_dump) = self.frame_dump.take() {
+            frame_dump.finish();
+        }
+        if let Some(recorder) = self.demo_recorder.take() {
+            let frames = recorder.frames();
+            match recorder.finish() {
+                Ok(()) => info!("Recorded {} frames", frames),
+                Err(e) => warn!("Could not write demo: {}", e),
+            }
+        }
+    }
+
+    pub fn init_for_part(&mut self, part_id: u16) -> std::result::Result<(), ResourceError> {
+        self.setup_part(part_id)?;
+        self.autosave();
+        Ok(())
+    }
+
+    fn setup_part(&mut self, part_id: u16) -> std::result::Result<(), ResourceError> {
+        let part_id = if self.skip_protection && part_id == parts::GAME_PART1 {
+            debug!("Skipping protection screens");
+            parts::GAME_PART2
+        } else {
+            part_id
+        };
         debug!("init_for_part: {}", part_id);
         self.player.stop();
-        self.mixer
-            .write()
-            .expect("Expected non-poisoned RwLock")
-            .stop_all();
+        sync::write(&self.mixer).stop_all();
 
         self.variables[0xe4] = 0x14;
 
-        self.resource.setup_part(part_id);
+        self.resource.setup_part(part_id)?;
         if self.resource.copy_vid_ptr {
-            let mut video_page_data = self.resource.video_page_data();
+            let mut video_page_data = self.resource.background_page_data();
             debug!("init_for_part copy_vid_ptr: {}", video_page_data.len());
             if self.scale != 1 {
-                video_page_data = util::resize(&video_page_data, self.scale);
+                video_page_data = util::scale_nearest(&video_page_data, 320, 200, self.scale);
             }
             self.video.copy_page_buffer(&video_page_data);
             self.resource.copy_vid_ptr = false;
@@ -149,13 +520,150 @@ impl VirtualMachine {
         }
 
         self.threads[0].pc = 0;
+        Ok(())
+    }
+
+    /// Threads are freshly initialized at the start of a part, which makes
+    /// it a stable checkpoint. The file is written on a separate thread to
+    /// not delay the frame.
+    fn autosave(&self) {
+        if let Some(path) = &self.autosave_path {
+            let path = path.clone();
+            let state = self.save_state();
+            let compress = self.compress_saves;
+            thread::spawn(move || {
+                let result = if compress {
+                    state.save(&path)
+                } else {
+                    state.save_uncompressed(&path)
+                };
+                if let Err(e) = result {
+                    warn!("Could not write autosave {}: {}", path.display(), e);
+                }
+            });
+        }
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        let mut threads = [ThreadState {
+            pc: 0,
+            requested_pc_offset: None,
+            is_channel_active_current: false,
+            is_channel_active_requested: false,
+        }; NUM_THREADS];
+        for (state, thread) in threads.iter_mut().zip(self.threads.iter()) {
+            *state = ThreadState {
+                pc: thread.pc as u16,
+                requested_pc_offset: thread.requested_pc_offset.map(|pc| pc as u16),
+                is_channel_active_current: thread.is_channel_active_current,
+                is_channel_active_requested: thread.is_channel_active_requested,
+            };
+        }
+        let page = self.video.page(self.video.displayed_page());
+        let thumbnail = self.video.palette().map(|palette| {
+            Thumbnail::from_page(self.video.width, self.video.height, &page.data, palette)
+        });
+        SaveState {
+            part_id: self.resource.current_part_id,
+            timestamp: Some(chrono::Utc::now().timestamp()),
+            thumbnail,
+            variables: self.variables,
+            threads,
+            requested_next_part: self.requested_next_part,
+            call_stack: self.script_stack_calls[..self.stack_ptr]
+                .iter()
+                .map(|&offset| offset as u16)
+                .collect(),
+            loaded_resources: self.resource.loaded_resources(),
+            segments: Some(self.segments()),
+            video: Some(self.video.save_state()),
+        }
+    }
+
+    /// Offsets of the palette, bytecode, cinematic and second video segments
+    fn segments(&self) -> [u32; 4] {
+        [
+            self.resource.seg_palettes as u32,
+            self.resource.seg_bytecode as u32,
+            self.resource.seg_cinematic as u32,
+            self.resource.seg_video2 as u32,
+        ]
+    }
+
+    pub fn load_state(&mut self, state: &SaveState) -> std::result::Result<(), ResourceError> {
+        debug!("load_state: part {}", state.part_id);
+        // Force setup_part to reload the part
+        self.resource.current_part_id = 0;
+        self.requested_next_part = None;
+        self.setup_part(state.part_id)?;
+        self.variables = state.variables;
+        for (thread, state) in self.threads.iter_mut().zip(state.threads.iter()) {
+            thread.pc = state.pc as usize;
+            thread.requested_pc_offset = state.requested_pc_offset.map(|pc| pc as usize);
+            thread.is_channel_active_current = state.is_channel_active_current;
+            thread.is_channel_active_requested = state.is_channel_active_requested;
+        }
+        self.requested_next_part = state.requested_next_part;
+        self.stack_ptr = state.call_stack.len().min(STACK_SIZE);
+        for (call, &offset) in self.script_stack_calls.iter_mut().zip(&state.call_stack) {
+            *call = offset as usize;
+        }
+        for &resource_id in &state.loaded_resources {
+            self.resource.load_memory_entry(resource_id)?;
+        }
+        // Backgrounds loaded by the scripts are already in the saved pages
+        self.resource.copy_vid_ptr = false;
+        if let Some(segments) = state.segments {
+            if segments != self.segments() {
+                warn!(
+                    "Part set up differently than when saved, segments {:x?} instead of {:x?}",
+                    self.segments(),
+                    segments
+                );
+            }
+        }
+        if let Some(video) = &state.video {
+            self.video.load_state(video);
+            if let Some(palette) = self.video.palette() {
+                self.sys.set_palette(palette);
+            }
+            self.video.redisplay(&mut self.sys, &Overlay::new());
+        }
+        Ok(())
+    }
+
+    /// Error from loading game data while running, after which the game
+    /// can't go on
+    pub fn take_error(&mut self) -> Option<ResourceError> {
+        self.error.take()
+    }
+
+    /// Keeps the first error from loading game data for `take_error`
+    fn check_resource<T>(&mut self, result: std::result::Result<T, ResourceError>) {
+        if let Err(e) = result {
+            error!("{}", e);
+            self.error.get_or_insert(e);
+        }
+    }
+
+    /// Switches to another part at the start of the next frame, like the
+    /// scripts do
+    pub fn request_part(&mut self, part: u16) {
+        match parts::part_name(part) {
+            Some(name) => {
+                info!("Switching to part {}", name);
+                self.requested_next_part = Some(part);
+            }
+            None => warn!("Ignoring request for unknown part 0x{:04x}", part),
+        }
     }
 
     pub fn check_thread_requests(&mut self) {
         // Check if a part switch has been requested
         if let Some(part) = self.requested_next_part {
             trace!("New part requested: {}", part);
-            self.init_for_part(part);
+            let result = self.init_for_part(part);
+            self.check_resource(result);
             self.requested_next_part = None;
         }
 
@@ -176,8 +684,48 @@ impl VirtualMachine {
         }
     }
 
-    pub fn update_player_input(&mut self) -> bool {
-        let input = self.user_input.process_events();
+    /// Returns the next queued input if there is one, otherwise the input
+    /// from the keyboard. Events are processed either way so the window stays
+    /// responsive, and closing it still quits.
+    pub fn poll_input(&mut self) -> PlayerInput {
+        let polled = self.sys.process_events();
+        if polled.quit {
+            return polled;
+        }
+        self.injected_input.pop_front().unwrap_or(polled)
+    }
+
+    /// Queue `input` to be used for one frame instead of the keyboard. Queued
+    /// inputs are consumed in order, one per call to `poll_input`.
+    pub fn push_input(&mut self, input: PlayerInput) {
+        self.injected_input.push_back(input);
+    }
+
+    /// Type `code` on the password screen once it is shown, one key per
+    /// frame with a released key in between, followed by the action button
+    pub fn set_code(&mut self, code: &str) {
+        self.pending_code.clear();
+        for c in code.chars() {
+            self.pending_code.push_back(Some(c));
+            self.pending_code.push_back(Some('\0'));
+        }
+        self.pending_code.push_back(None);
+    }
+
+    pub fn update_player_input(&mut self, input: &PlayerInput) {
+        let mut input = *input;
+        // Demos and typed codes only advance with the scripts
+        if !self.paused {
+            self.play_demo_frame(&mut input);
+            if self.resource.current_part_id == parts::GAME_PART10 {
+                match self.pending_code.pop_front() {
+                    Some(Some(c)) => input.last_char = c,
+                    Some(None) => input.button = true,
+                    None => {}
+                }
+            }
+            self.record_demo_frame(&input);
+        }
 
         if self.resource.current_part_id == 0x3e89 {
             let c = input.last_char;
@@ -186,8 +734,89 @@ impl VirtualMachine {
             }
         }
 
-        if input.quit {
-            return false;
+        if input.toggle_scanlines {
+            let scanlines = !self.sys.scanlines();
+            self.sys.set_scanlines(scanlines);
+            self.save_settings();
+        }
+
+        if input.toggle_crt {
+            let crt = !self.sys.crt();
+            self.sys.set_crt(crt);
+            self.save_settings();
+        }
+
+        // The music keeps playing in real time, so it falls behind while
+        // fast-forwarding and the scripts wait for its marks afterwards
+        self.fast_forward = input.fast_forward;
+
+        if input.toggle_vu_meter {
+            self.vu_meter = !self.vu_meter;
+        }
+
+        if input.toggle_mute {
+            let mut mixer = sync::write(&self.mixer);
+            let muted = !mixer.is_muted();
+            mixer.set_muted(muted);
+            drop(mixer);
+            info!("Audio {}", if muted { "muted" } else { "unmuted" });
+            self.save_settings();
+        }
+
+        if input.next_language {
+            self.video.next_language();
+            info!("Language: {}", self.video.language_name());
+        }
+
+        if input.toggle_memory_usage {
+            self.memory_usage = !self.memory_usage;
+        }
+
+        if input.toggle_palette {
+            self.palette_overlay = !self.palette_overlay;
+        }
+
+        if input.next_scale {
+            self.set_scale(self.scale % MAX_SCALE + 1);
+            self.save_settings();
+            let overlay = self.debug_overlay();
+            self.video.redisplay(&mut self.sys, &overlay);
+        }
+
+        if input.toggle_page_grid {
+            let page_grid = self.video.page_grid();
+            self.video.set_page_grid(!page_grid);
+        }
+
+        if input.screenshot {
+            self.screenshot();
+        }
+
+        if input.dump_pages {
+            self.dump_pages();
+        }
+
+        if input.debug_break && self.debug {
+            println!("Stopped, type h for help");
+            self.debug_break = true;
+        }
+
+        if input.music_slower {
+            self.player.slower();
+        }
+        if input.music_faster {
+            self.player.faster();
+        }
+        if input.music_previous_order {
+            self.player.previous_order();
+        }
+        if input.music_next_order {
+            self.player.next_order();
+        }
+
+        if input.restart_part {
+            let result = self.restart_part();
+            self.check_resource(result);
         }
 
         if input.code
@@ -228,10 +857,79 @@ impl VirtualMachine {
             0
         };
         self.variables[VM_VARIABLE_HERO_ACTION_POS_MASK] = m;
-        true
+    }
+
+    /// Restarts the current part from the beginning, reloading its resources
+    pub fn restart_part(&mut self) -> std::result::Result<(), ResourceError> {
+        let part = self.resource.current_part_id;
+        debug!("restart_part: {}", part);
+        // Force setup_part to reload the part
+        self.resource.current_part_id = 0;
+        self.requested_next_part = None;
+        self.init_for_part(part)
+    }
+
+    /// Presents the last displayed frame with the overlay on top, used while
+    /// the scripts are not running
+    pub fn redisplay(&mut self, overlay: &Overlay) {
+        self.video.redisplay(&mut self.sys, overlay);
+    }
+
+    pub fn overlay_colors(&self) -> (u8, u8) {
+        self.video.overlay_colors()
+    }
+
+    pub fn sleep(&mut self, ms: u64) {
+        self.sys.sleep(ms);
+    }
+
+    /// Silences the sounds and holds the music while the scripts are not
+    /// run. The time spent paused is not counted as game time.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        debug!("set_paused({})", paused);
+        self.paused = paused;
+        sync::write(&self.mixer).set_paused(paused);
+        if paused {
+            self.player.pause();
+        } else {
+            self.player.resume();
+            self.restart_frame_timing();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Runs the scripts for a single frame while paused, the sound staying
+    /// silenced
+    pub fn step_frame(&mut self, input: &PlayerInput) {
+        debug!("step_frame({})", self.frame_number);
+        let paused = self.paused;
+        self.paused = false;
+        // The time spent paused would make the frame late and skipped
+        self.restart_frame_timing();
+        self.check_thread_requests();
+        self.update_player_input(input);
+        self.host_frame();
+        self.paused = paused;
     }
 
     pub fn host_frame(&mut self) {
+        // Frame synced music follows the game time whatever the pacing, so
+        // demos and other synced runs play it the same way every time
+        self.player.advance(self.game_time - self.music_timestamp);
+        self.music_timestamp = self.game_time;
+        // Music marks are only picked up here, so every thread of a frame
+        // sees the same value
+        if let Some(value) = self.player.take_mark() {
+            debug!("Music mark: {}", value);
+            self.variables[VM_VARIABLE_MUS_MARK] = value;
+        }
+
         for thread_id in 0..self.threads.len() {
             if self.threads[thread_id].is_channel_active_current {
                 trace!("Skip thread {}", thread_id);
@@ -247,7 +945,7 @@ impl VirtualMachine {
 
                 trace!("host_frame() thread_id=0x{:02x} n=0x{:02x}", thread_id, n);
 
-                self.execute_thread();
+                self.execute_thread(thread_id);
 
                 // Save pc since it will be modified on the next iteration
                 self.threads[thread_id].pc = self.script_ptr - self.resource.seg_bytecode;
@@ -275,48 +973,118 @@ impl VirtualMachine {
         result
     }
 
-    fn execute_thread(&mut self) {
+    fn execute_thread(&mut self, thread_id: usize) {
         while !self.goto_next_thread {
-            if let Some(rx) = &self.variable_receiver {
-                if let Ok(value) = rx.try_recv() {
-                    warn!("Got variable value from sfxplayer: {}", value);
-                    self.variables[VM_VARIABLE_MUS_MARK] = value;
+            trace!("pc: 0x{:x} Decoding opcode", self.script_ptr);
+            let pc = self.script_ptr - self.resource.seg_bytecode;
+            if self.debug_break {
+                self.debug_console(thread_id, pc);
+            }
+            self.execute_instruction();
+        }
+    }
+
+    /// Fetches and runs the instruction at `script_ptr`
+    fn execute_instruction(&mut self) {
+        let opcode = Opcode::decode(self.fetch_byte());
+
+        match opcode {
+            Opcode::MovConst => self.op_mov_const(),
+            Opcode::Mov => self.op_mov(),
+            Opcode::Add => self.op_add(),
+            Opcode::AddConst => self.op_add_const(),
+            Opcode::Call => self.op_call(),
+            Opcode::Ret => self.op_ret(),
+            Opcode::PauseThread => self.op_pause_thread(),
+            Opcode::Jmp => self.op_jmp(),
+            Opcode::SetSetVect => self.op_set_set_vect(),
+            Opcode::Jnz => self.op_jnz(),
+            Opcode::CondJmp => self.op_cond_jmp(),
+            Opcode::SetPalette => self.op_set_palette(),
+            Opcode::ResetThread => self.op_reset_thread(),
+            Opcode::SelectVideoPage => self.op_select_video_page(),
+            Opcode::FillVideoPage => self.op_fill_video_page(),
+            Opcode::CopyVideoPage => self.op_copy_video_page(),
+            Opcode::BlitFrameBuffer => self.op_blit_frame_buffer(),
+            Opcode::KillThread => self.op_kill_thread(),
+            Opcode::DrawString => self.op_draw_string(),
+            Opcode::Sub => self.op_sub(),
+            Opcode::And => self.op_and(),
+            Opcode::Or => self.op_or(),
+            Opcode::Shl => self.op_shl(),
+            Opcode::Shr => self.op_shr(),
+            Opcode::PlaySound => self.op_play_sound(),
+            Opcode::UpdateMemList => self.op_update_memlist(),
+            Opcode::PlayMusic => self.op_play_music(),
+            Opcode::DrawPolySprite(val) => self.op_draw_poly_sprite(val),
+            Opcode::DrawPolyBackground(val) => self.op_draw_poly_background(val),
+        }
+    }
+
+    /// Reads debug console commands from standard input until the scripts
+    /// are continued or stepped. The game stays frozen meanwhile.
+    fn debug_console(&mut self, thread_id: usize, pc: usize) {
+        let code = self.resource.bytecode();
+        match Instruction::decode(code, pc) {
+            Some(instruction) => {
+                println!("thread 0x{:02x} 0x{:04x}: {}", thread_id, pc, instruction)
+            }
+            None => println!(
+                "thread 0x{:02x} 0x{:04x}: illegal instruction",
+                thread_id, pc
+            ),
+        }
+        loop {
+            match debugger::read_command() {
+                Some(Command::Print(var)) => self.print_variable(var),
+                Some(Command::Set(var, value)) => {
+                    self.variables[var] = value;
+                    self.print_variable(var);
+                }
+                Some(Command::Step) => break,
+                Some(Command::Continue) | None => {
+                    self.debug_break = false;
+                    break;
                 }
+                Some(Command::Threads) => self.print_threads(thread_id, pc),
+                Some(Command::Help) => println!("{}", debugger::HELP),
             }
-            trace!("pc: 0x{:x} Decoding opcode", self.script_ptr);
-            let opcode = Opcode::decode(self.fetch_byte());
-
-            match opcode {
-                Opcode::MovConst => self.op_mov_const(),
-                Opcode::Mov => self.op_mov(),
-                Opcode::Add => self.op_add(),
-                Opcode::AddConst => self.op_add_const(),
-                Opcode::Call => self.op_call(),
-                Opcode::Ret => self.op_ret(),
-                Opcode::PauseThread => self.op_pause_thread(),
-                Opcode::Jmp => self.op_jmp(),
-                Opcode::SetSetVect => self.op_set_set_vect(),
-                Opcode::Jnz => self.op_jnz(),
-                Opcode::CondJmp => self.op_cond_jmp(),
-                Opcode::SetPalette => self.op_set_palette(),
-                Opcode::ResetThread => self.op_reset_thread(),
-                Opcode::SelectVideoPage => self.op_select_video_page(),
-                Opcode::FillVideoPage => self.op_fill_video_page(),
-                Opcode::CopyVideoPage => self.op_copy_video_page(),
-                Opcode::BlitFrameBuffer => self.op_blit_frame_buffer(),
-                Opcode::KillThread => self.op_kill_thread(),
-                Opcode::DrawString => self.op_draw_string(),
-                Opcode::Sub => self.op_sub(),
-                Opcode::And => self.op_and(),
-                Opcode::Or => self.op_or(),
-                Opcode::Shl => self.op_shl(),
-                Opcode::Shr => self.op_shr(),
-                Opcode::PlaySound => self.op_play_sound(),
-                Opcode::UpdateMemList => self.op_update_memlist(),
-                Opcode::PlayMusic => self.op_play_music(),
-                Opcode::DrawPolySprite(val) => self.op_draw_poly_sprite(val),
-                Opcode::DrawPolyBackground(val) => self.op_draw_poly_background(val),
+        }
+        // The time spent in the console doesn't count as a late frame or
+        // as played music
+        self.restart_frame_timing();
+    }
+
+    fn print_variable(&self, var: usize) {
+        let value = self.variables[var];
+        let name = variable_name(var)
+            .map(|name| format!(" ({})", name))
+            .unwrap_or_default();
+        println!("0x{:02x}{} = {} (0x{:04x})", var, name, value, value as u16);
+    }
+
+    /// Lists the threads with a program counter, `current` running at `pc`
+    fn print_threads(&self, current: usize, pc: usize) {
+        for (thread_id, thread) in self.threads.iter().enumerate() {
+            let thread_pc = if thread_id == current { pc } else { thread.pc };
+            if thread_pc == INACTIVE_THREAD {
+                continue;
             }
+            let marker = if thread_id == current { '*' } else { ' ' };
+            let paused = if thread.is_channel_active_current {
+                " paused"
+            } else {
+                ""
+            };
+            let requested = match thread.requested_pc_offset {
+                Some(SET_INACTIVE_THREAD) => " (kill requested)".to_string(),
+                Some(offset) => format!(" (jump to 0x{:04x} requested)", offset),
+                None => String::new(),
+            };
+            println!(
+                "{}0x{:02x}: 0x{:04x}{}{}",
+                marker, thread_id, thread_pc, paused, requested
+            );
         }
     }
 
@@ -356,10 +1124,12 @@ impl VirtualMachine {
         let offset = self.fetch_word();
 
         trace!("call(0x{:x})", offset);
-        self.script_stack_calls[self.stack_ptr] = self.script_ptr - self.resource.seg_bytecode;
         if self.stack_ptr == STACK_SIZE {
-            panic!("Stack overflow");
+            let pc = self.script_ptr - self.resource.seg_bytecode - 3;
+            self.stop_thread(ResourceError::CallStackOverflow(pc));
+            return;
         }
+        self.script_stack_calls[self.stack_ptr] = self.script_ptr - self.resource.seg_bytecode;
         self.stack_ptr += 1;
         self.script_ptr = self.resource.seg_bytecode + offset as usize;
     }
@@ -367,7 +1137,9 @@ impl VirtualMachine {
     fn op_ret(&mut self) {
         trace!("ret()");
         if self.stack_ptr == 0 {
-            panic!("Stack underflow!");
+            let pc = self.script_ptr - self.resource.seg_bytecode - 1;
+            self.stop_thread(ResourceError::CallStackUnderflow(pc));
+            return;
         }
         self.stack_ptr -= 1;
         self.script_ptr = self.resource.seg_bytecode + self.script_stack_calls[self.stack_ptr]
@@ -541,18 +1313,203 @@ impl VirtualMachine {
         trace!("blit_frame_buffer({})", page_id);
         //inp_handle_special_keys();
 
-        let delay = self.sys.get_timestamp() - self.last_timestamp;
+        let slices = self.variables[VM_VARIABLE_PAUSE_SLICES].max(0) as u64;
+        let pause_time = match self.pacing {
+            PacingMode::Realtime => slices * 1000 / self.frame_rate as u64,
+            PacingMode::Unpaced => slices * 1000 / ORIGINAL_FRAME_RATE as u64,
+        };
+        self.game_time += slices * 1000 / ORIGINAL_FRAME_RATE as u64;
+        let delay = self.timestamp() - self.last_timestamp;
 
-        let pause_time = self.variables[VM_VARIABLE_PAUSE_SLICES] as u64 * 20;
-        if pause_time > delay {
+        if self.pacing == PacingMode::Realtime && !self.fast_forward && pause_time > delay {
             let time_to_sleep = pause_time - delay;
             self.sys.sleep(time_to_sleep);
             trace!("Delay: {}, time_to_sleep: {}", delay, time_to_sleep);
         }
-        self.last_timestamp = self.sys.get_timestamp();
+        let timestamp = self.timestamp();
+        debug!(
+            "Frame interval: {} ms, target: {} ms",
+            timestamp - self.last_timestamp,
+            pause_time
+        );
+        self.last_timestamp = timestamp;
 
         self.variables[0xf7] = 0;
-        self.video.update_display(&mut self.sys, page_id);
+        // Running late means the previous frame took longer than the pause,
+        // drawing this one would only put the game further behind
+        let late = pause_time > 0 && delay > pause_time;
+        if late && self.skipped_frames < self.max_frameskip {
+            trace!("Skipping frame, delay: {}", delay);
+            self.skipped_frames += 1;
+            self.video.flip(&mut self.sys, page_id);
+        } else {
+            self.skipped_frames = 0;
+            let overlay = self.debug_overlay();
+            self.video.update_display(&mut self.sys, page_id, &overlay);
+        }
+        self.log_checksum();
+        if let Some(frame_dump) = self.frame_dump.as_mut() {
+            let grayscale = Palette::grayscale();
+            let palette = self.video.palette().unwrap_or(&grayscale);
+            let page = self.video.page(self.video.displayed_page());
+            let slices = self.variables[VM_VARIABLE_PAUSE_SLICES].max(0) as u32;
+            let size = (self.video.width, self.video.height);
+            frame_dump.push(size, palette, &page.data, slices);
+        }
+        self.frame_number += 1;
+    }
+
+    /// Replaces the game input with the next frame of the demo being played
+    fn play_demo_frame(&mut self, input: &mut PlayerInput) {
+        let demo = match self.demo.as_mut() {
+            Some(demo) => demo,
+            None => return,
+        };
+        match demo.next_input() {
+            Some(recorded) => {
+                input.direction = recorded.direction;
+                input.button = recorded.button;
+                input.code = recorded.code;
+                input.last_char = recorded.last_char;
+            }
+            None => {
+                info!("Demo finished at frame {}", self.frame_number);
+                self.demo = None;
+            }
+        }
+    }
+
+    fn record_demo_frame(&mut self, input: &PlayerInput) {
+        let recorder = match self.demo_recorder.as_mut() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        if let Err(e) = recorder.record(input) {
+            warn!("Could not write demo, disabling it: {}", e);
+            self.demo_recorder = None;
+        }
+    }
+
+    fn log_checksum(&mut self) {
+        let log = match self.checksum_log.as_mut() {
+            Some(log) => log,
+            None => return,
+        };
+        let page = self.video.page(self.video.displayed_page());
+        let checksum = util::crc32(&page.data);
+        let mut result = writeln!(log, "{}: {:08x}", self.frame_number, checksum);
+        if result.is_ok() && self.frame_number.is_multiple_of(CHECKSUM_FLUSH_FRAMES) {
+            result = log.flush();
+        }
+        if let Err(e) = result {
+            warn!("Could not write checksum log, disabling it: {}", e);
+            self.checksum_log = None;
+        }
+    }
+
+    /// Saves the displayed page without overlays
+    fn screenshot(&self) {
+        let timestamp = capture::timestamp();
+        let index = self.video.displayed_page();
+        if let Some(path) = self.save_page(index, "screenshot", &timestamp) {
+            info!("Saved screenshot to {}", path.display());
+        }
+    }
+
+    /// Saves all four video pages as `page<n>` captures
+    fn dump_pages(&self) {
+        let timestamp = capture::timestamp();
+        for index in 0..4 {
+            let name = format!("page{}", index);
+            if let Some(path) = self.save_page(index, &name, &timestamp) {
+                let displayed = if index == self.video.displayed_page() {
+                    " (displayed)"
+                } else {
+                    ""
+                };
+                info!("Saved page {}{} to {}", index, displayed, path.display());
+            }
+        }
+    }
+
+    /// Writes a video page to a PNG file using the current palette, errors
+    /// are logged
+    fn save_page(&self, index: usize, name: &str, timestamp: &str) -> Option<PathBuf> {
+        let grayscale = Palette::grayscale();
+        let palette = self.video.palette().unwrap_or(&grayscale);
+        let path = match self.capture_output.path(name, timestamp) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Could not save {}: {}", name, e);
+                return None;
+            }
+        };
+        let page = self.video.page(index);
+        let size = (self.video.width, self.video.height);
+        let export = self.capture_output.export();
+        match export.save(&path, size, palette, &page.data) {
+            Ok(()) => Some(path),
+            Err(e) => {
+                error!("Could not save {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Debug information drawn on top of each displayed frame
+    fn debug_overlay(&self) -> Overlay {
+        let mut overlay = Overlay::new();
+        let (background, foreground) = self.video.overlay_colors();
+        if self.vu_meter {
+            let mixer = sync::read(&self.mixer);
+            let peaks = mixer.peak_levels();
+            let (x, y, height) = (4, 156, 32);
+            overlay.rect(x, y, peaks.len() as u16 * 6 + 2, height + 4, background);
+            for (i, &peak) in peaks.iter().enumerate() {
+                let level = peak as u16 * height / 128;
+                let bar_x = x + 2 + i as u16 * 6;
+                overlay.rect(bar_x, y + 2 + height - level, 4, level, foreground);
+            }
+            if mixer.is_muted() {
+                overlay.rect(x, y - 12, 36, 12, background);
+                overlay.text(x + 2, y - 10, foreground, "MUTE");
+            }
+        }
+        if self.palette_overlay {
+            // One swatch per color along the bottom, labeled with its index
+            // in the brightest or darkest color, whichever stands out
+            let (width, height) = (20, 14);
+            let y = 200 - height;
+            for i in 0..16u8 {
+                let x = i as u16 * width;
+                overlay.rect(x, y, width, height, i);
+                let label = match self.video.palette() {
+                    Some(palette) if palette.entries[i as usize].luma() > 128 => background,
+                    Some(_) => foreground,
+                    None if i >= 8 => background,
+                    None => foreground,
+                };
+                overlay.text(x + 6, y + 3, label, &format!("{:X}", i));
+            }
+        }
+        if self.memory_usage {
+            let usage = self.resource.memory_usage();
+            let loaded: Vec<String> = usage
+                .loaded
+                .iter()
+                .map(|(entry_type, count)| format!("{}{}", entry_type_abbrev(*entry_type), count))
+                .collect();
+            let lines = [
+                format!("MEM {}K/{}K", usage.used / 1024, usage.limit / 1024),
+                format!("FREE {}", usage.free()),
+                loaded.join(" "),
+            ];
+            overlay.rect(0, 0, 248, lines.len() as u16 * 10 + 4, background);
+            for (i, line) in lines.iter().enumerate() {
+                overlay.text(4, 3 + i as u16 * 10, foreground, line);
+            }
+        }
+        overlay
     }
 
     fn op_kill_thread(&mut self) {
@@ -561,6 +1518,12 @@ impl VirtualMachine {
         self.goto_next_thread = true;
     }
 
+    /// Kills the running thread after bad bytecode, keeping the error
+    fn stop_thread(&mut self, error: ResourceError) {
+        self.check_resource::<()>(Err(error));
+        self.op_kill_thread();
+    }
+
     fn op_draw_string(&mut self) {
         let string_id = self.fetch_word();
         let x = self.fetch_byte() as u16;
@@ -626,21 +1589,19 @@ impl VirtualMachine {
 
         if resource_id == 0 {
             self.player.stop();
-            self.mixer
-                .write()
-                .expect("Expected non-poisoned RwLock")
-                .stop_all();
+            sync::write(&self.mixer).stop_all();
             self.resource.invalidate_resource();
         } else if resource_id >= parts::GAME_PART_FIRST {
             debug!("Requesting new part {}", resource_id);
             self.requested_next_part = Some(resource_id);
         } else {
-            self.resource.load_memory_entry(resource_id);
+            let result = self.resource.load_memory_entry(resource_id);
+            self.check_resource(result);
             if self.resource.copy_vid_ptr {
-                let mut video_page_data = self.resource.video_page_data();
+                let mut video_page_data = self.resource.background_page_data();
                 debug!("update_memlist copy_vid_ptr: {}", video_page_data.len());
                 if self.scale != 1 {
-                    video_page_data = util::resize(&video_page_data, self.scale);
+                    video_page_data = util::scale_nearest(&video_page_data, 320, 200, self.scale);
                 }
                 self.video.copy_page_buffer(&video_page_data);
                 self.resource.copy_vid_ptr = false;
@@ -652,7 +1613,8 @@ impl VirtualMachine {
         let resource_id = self.fetch_word();
         let delay = self.fetch_word();
         let pos = self.fetch_byte();
-        self.play_music_resource(resource_id, delay, pos).unwrap();
+        let result = self.play_music_resource(resource_id, delay, pos);
+        self.check_resource(result);
     }
 
     fn op_draw_poly_sprite(&mut self, val: u8) {
@@ -762,12 +1724,12 @@ impl VirtualMachine {
     }
 
     fn stop_channel(&mut self, channel: u8) {
-        let mut write_guard = self.mixer.write().expect("Expected non-poisoned RwLock");
+        let mut write_guard = sync::write(&self.mixer);
         write_guard.stop_channel(channel);
     }
 
     fn play_channel(&mut self, channel: u8, mixer_chunk: MixerChunk, frequence: u16, vol: u8) {
-        let mut write_guard = self.mixer.write().expect("Expected non-poisoned RwLock");
+        let mut write_guard = sync::write(&self.mixer);
         let vol = cmp::min(vol, 0x3f);
         write_guard.play_channel(channel & 3, mixer_chunk, frequence, vol);
     }
@@ -777,16 +1739,32 @@ impl VirtualMachine {
             "play_sound_resource(0x{:x}, {}, {}, {})",
             resource_id, freq, vol, channel
         );
+        if !self.sys.audio() {
+            return;
+        }
         if vol == 0 {
             self.stop_channel(channel);
         } else if let Some(mixer_chunk) = self.resource.get_entry_mixer_chunk(resource_id) {
-            let frequence = mixer::FREQUENCE_TABLE[freq as usize];
+            let frequence = match mixer::FREQUENCE_TABLE.get(freq as usize) {
+                Some(&frequence) => frequence,
+                None => {
+                    warn!("play_sound: invalid frequency index {}", freq);
+                    return;
+                }
+            };
             let vol = cmp::min(vol, 0x3f);
             self.play_channel(channel & 3, mixer_chunk, frequence, vol);
         }
     }
 
-    fn play_music_resource(&mut self, resource_id: u16, delay: u16, pos: u8) -> Result<()> {
+    // Music is still sequenced when audio is disabled, since scripts wait for
+    // the marks it sets in VM_VARIABLE_MUS_MARK
+    fn play_music_resource(
+        &mut self,
+        resource_id: u16,
+        delay: u16,
+        pos: u8,
+    ) -> std::result::Result<(), ResourceError> {
         debug!(
             "play_music_resource(0x{:x}, {}, {})",
             resource_id, delay, pos
@@ -800,8 +1778,7 @@ impl VirtualMachine {
                 self.player.set_sfx_module(sfx_module);
                 self.player.set_events_delay(delay);
 
-                self.variable_receiver
-                    .replace(self.player.start(MixerAudio(self.mixer.clone())));
+                self.player.start(MixerAudio(self.mixer.clone()));
             }
         } else if delay != 0 {
             self.player.set_events_delay(delay);
@@ -811,3 +1788,176 @@ impl VirtualMachine {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::AssetPlatform;
+    use crate::system::HeadlessSys;
+
+    /// Cinematic data is read from here, away from the bytecode
+    const TEST_SEG_CINEMATIC: usize = 0x8000;
+
+    /// A VM running `code` as the bytecode segment, without game data
+    fn test_vm(code: &[u8]) -> VirtualMachine<HeadlessSys> {
+        let mut resource = Resource::new(Vec::new(), PathBuf::from("missing"), AssetPlatform::PC);
+        resource.memory[..code.len()].copy_from_slice(code);
+        resource.seg_bytecode_len = code.len();
+        resource.seg_cinematic = TEST_SEG_CINEMATIC;
+        resource.seg_video2 = TEST_SEG_CINEMATIC;
+        let video = Video::new(320, 200);
+        VirtualMachine::new(resource, video, HeadlessSys::new(320, 200), 1, Some(0))
+    }
+
+    /// One of each instruction that runs without game data, along with every
+    /// operand encoding of DrawPolySprite and CondJmp
+    #[rustfmt::skip]
+    const BYTECODE: &[u8] = &[
+        0x00, 0x10, 0x00, 0x05,             // 0000: mov_const v10, 5
+        0x01, 0x11, 0x10,                   // 0004: mov v11, v10
+        0x02, 0x11, 0x10,                   // 0007: add v11, v10
+        0x03, 0x12, 0x00, 0x01,             // 000a: add_const v12, 1
+        0x0a, 0x80, 0x10, 0x11, 0x00, 0x14, // 000e: cond_jmp v10 == v11, 0014
+        0x0a, 0x40, 0x10, 0x00, 0x05, 0x00, 0x1b,
+                                            // 0014: cond_jmp v10 == 5, 001b
+        0x0a, 0x01, 0x10, 0x05, 0x00, 0x30, // 001b: cond_jmp v10 != 5, 0030
+        0x09, 0x12, 0x00, 0x25,             // 0021: jnz v12, 0025
+        0x40, 0x00, 0x00, 0x00, 0xa0, 0x00, 0x64,
+                                            // 0025: draw_poly_sprite x, y words
+        0x55, 0x00, 0x00, 0x10, 0x11, 0x12, // 002c: x, y and zoom variables
+        0x6a, 0x00, 0x00, 0x20, 0x30, 0x40, // 0032: x, y and zoom bytes
+        0x7f, 0x00, 0x00, 0x10, 0x20,       // 0038: x + 0x100, video2
+        0x80, 0x00, 0x50, 0x60,             // 003d: draw_poly_background
+        0x0b, 0x00, 0x00,                   // 0041: set_palette
+        0x0c, 0x01, 0x02, 0x02,             // 0044: reset_thread
+        0x0d, 0x01,                         // 0048: select_video_page
+        0x0e, 0x01, 0x00,                   // 004a: fill_video_page
+        0x0f, 0x01, 0x02,                   // 004d: copy_video_page
+        0x12, 0x00, 0x01, 0x10, 0x20, 0x0f, // 0050: draw_string
+        0x13, 0x11, 0x10,                   // 0056: sub v11, v10
+        0x14, 0x11, 0x00, 0xff,             // 0059: and v11, 0xff
+        0x15, 0x11, 0x01, 0x00,             // 005d: or v11, 0x100
+        0x16, 0x11, 0x00, 0x01,             // 0061: shl v11, 1
+        0x17, 0x11, 0x00, 0x01,             // 0065: shr v11, 1
+        0x08, 0x01, 0x00, 0x00,             // 0069: set_vect
+        0x04, 0x00, 0x71,                   // 006d: call 0071
+        0x06,                               // 0070: pause_thread
+        0x05,                               // 0071: ret
+        0x07, 0x00, 0x00,                   // 0072: jmp 0000
+        0x11,                               // 0075: kill_thread
+    ];
+
+    #[test]
+    fn interpreter_fetches_the_decoded_operands() {
+        let mut vm = test_vm(BYTECODE);
+        let mut offset = 0;
+        while offset < BYTECODE.len() {
+            let instruction = Instruction::decode(BYTECODE, offset).unwrap();
+            vm.script_ptr = offset;
+            vm.execute_instruction();
+            let next = vm.script_ptr;
+            let consistent = match instruction.opcode {
+                Opcode::Ret | Opcode::KillThread => true,
+                Opcode::Call | Opcode::Jmp => instruction.branch_target() == Some(next),
+                Opcode::Jnz | Opcode::CondJmp => {
+                    next == instruction.next_offset() || instruction.branch_target() == Some(next)
+                }
+                _ => next == instruction.next_offset(),
+            };
+            assert!(
+                consistent,
+                "\"{}\" at 0x{:04x} continued at 0x{:04x}, decoded as {} bytes",
+                instruction,
+                offset,
+                next,
+                instruction.size()
+            );
+            offset = instruction.next_offset();
+        }
+    }
+
+    #[test]
+    fn recursion_stops_at_a_full_call_stack() {
+        // 0000: call 0000
+        let mut vm = test_vm(&[0x04, 0x00, 0x00]);
+        vm.script_ptr = 0;
+        vm.goto_next_thread = false;
+        let mut calls = 0;
+        while !vm.goto_next_thread {
+            vm.execute_instruction();
+            calls += 1;
+        }
+        assert_eq!(calls, STACK_SIZE + 1);
+        assert!(matches!(
+            vm.take_error(),
+            Some(ResourceError::CallStackOverflow(0))
+        ));
+    }
+
+    #[test]
+    fn return_with_an_empty_call_stack_is_an_error() {
+        // 0000: ret
+        let mut vm = test_vm(&[0x05]);
+        vm.script_ptr = 0;
+        vm.execute_instruction();
+        assert!(vm.goto_next_thread);
+        assert!(matches!(
+            vm.take_error(),
+            Some(ResourceError::CallStackUnderflow(0))
+        ));
+    }
+
+    #[test]
+    fn switching_pacing_rebases_the_frame_timing() {
+        let mut vm = test_vm(&[]);
+        vm.sleep(1000);
+        vm.set_frame_rate(ORIGINAL_FRAME_RATE);
+        assert_eq!(vm.last_timestamp, 1000);
+
+        // Unpaced runs are timed by the game time, which is still 0
+        vm.set_frame_rate(0);
+        assert_eq!(vm.pacing, PacingMode::Unpaced);
+        assert_eq!(vm.last_timestamp, 0);
+
+        vm.set_frame_rate(100);
+        assert_eq!(vm.pacing, PacingMode::Realtime);
+        assert_eq!(vm.frame_rate, 100);
+        assert_eq!(vm.last_timestamp, 1000);
+    }
+
+    #[test]
+    fn blits_sleep_for_the_pause_slices() {
+        // 0000: blit_frame_buffer 0xff
+        let mut vm = test_vm(&[0x10, 0xff]);
+        vm.set_frame_rate(ORIGINAL_FRAME_RATE);
+        vm.variables[VM_VARIABLE_PAUSE_SLICES] = 5;
+        let start = vm.sys.get_timestamp();
+        vm.script_ptr = 0;
+        vm.execute_instruction();
+        assert_eq!(vm.sys.get_timestamp() - start, 100);
+
+        // Time already spent on the frame is taken off the sleep
+        vm.sleep(30);
+        let start = vm.sys.get_timestamp();
+        vm.script_ptr = 0;
+        vm.execute_instruction();
+        assert_eq!(vm.sys.get_timestamp() - start, 70);
+    }
+
+    #[test]
+    fn synced_music_follows_the_game_time() {
+        // 0000: blit_frame_buffer 0xff
+        let mut vm = test_vm(&[0x10, 0xff]);
+        vm.set_frame_synced_music(true);
+        vm.set_frame_rate(100);
+        vm.variables[VM_VARIABLE_PAUSE_SLICES] = 5;
+        vm.script_ptr = 0;
+        vm.execute_instruction();
+        // A slow frame doesn't move the music further than the pause of the
+        // frame at the original frame rate
+        vm.sleep(1000);
+        vm.host_frame();
+        assert_eq!(vm.game_time, 100);
+        assert_eq!(vm.music_timestamp, 100);
+    }
+}
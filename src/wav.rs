@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// Writes a mono 8 bit WAV file. `samples` are signed like the game's sound
+/// data and are converted to the unsigned samples of the format.
+pub fn write_mono_i8<W: Write>(out: &mut W, sample_rate: u32, samples: &[u8]) -> Result<()> {
+    let data_len = samples.len() as u32;
+    // Chunks are padded to an even length
+    let pad = data_len % 2;
+
+    out.write_all(b"RIFF")?;
+    out.write_u32::<LittleEndian>(4 + 8 + 16 + 8 + data_len + pad)?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_u32::<LittleEndian>(16)?;
+    // PCM, one channel, one byte per frame
+    out.write_u16::<LittleEndian>(1)?;
+    out.write_u16::<LittleEndian>(1)?;
+    out.write_u32::<LittleEndian>(sample_rate)?;
+    out.write_u32::<LittleEndian>(sample_rate)?;
+    out.write_u16::<LittleEndian>(1)?;
+    out.write_u16::<LittleEndian>(8)?;
+
+    out.write_all(b"data")?;
+    out.write_u32::<LittleEndian>(data_len)?;
+    let unsigned: Vec<u8> = samples.iter().map(|&s| s ^ 0x80).collect();
+    out.write_all(&unsigned)?;
+    if pad != 0 {
+        out.write_u8(0)?;
+    }
+    Ok(())
+}
+
+pub fn save_mono_i8(path: &Path, sample_rate: u32, samples: &[u8]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_mono_i8(&mut out, sample_rate, samples)?;
+    out.flush()
+}
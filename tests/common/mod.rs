@@ -0,0 +1,43 @@
+//! Game data setup shared by the tests that run the game
+
+use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anotherworld::resource::{MemlistReader, Resource, ResourceError};
+use anotherworld::system::HeadlessSys;
+use anotherworld::video::Video;
+use anotherworld::vm::{PacingMode, VirtualMachine};
+
+pub const SEED: i16 = 0x1234;
+
+/// FNV-1a, stable across Rust releases unlike the std hashers
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Game data from `data` or `$AW_DATA`, `None` when it is missing
+pub fn resource() -> Option<Resource> {
+    let asset_path = env::var_os("AW_DATA").map_or_else(|| PathBuf::from("data"), PathBuf::from);
+    match MemlistReader::detect_platform(asset_path.clone()).read_memlist() {
+        Ok(resource) => Some(resource),
+        Err(ResourceError::Io(e)) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("No game data in {}, skipping", asset_path.display());
+            None
+        }
+        Err(e) => panic!("Could not read the game data: {}", e),
+    }
+}
+
+/// A headless VM with a fixed seed and frame-synced music, running as fast
+/// as possible
+pub fn headless_vm(resource: Resource) -> VirtualMachine<HeadlessSys> {
+    let video = Video::new(320, 200);
+    let sys = HeadlessSys::new(320, 200);
+    let mut vm = VirtualMachine::new(resource, video, sys, 1, Some(SEED));
+    vm.set_frame_synced_music(true);
+    vm.set_pacing(PacingMode::Unpaced);
+    vm
+}
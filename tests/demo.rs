@@ -0,0 +1,74 @@
+//! Records a demo of the intro with some input, then plays it back in a new
+//! VM and checks that it reaches the next part on the same frame. Needs the
+//! game data in `data` or `$AW_DATA` and is skipped without it.
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use anotherworld::demo::{Demo, DemoRecorder};
+use anotherworld::engine::Engine;
+use anotherworld::parts;
+use anotherworld::player::{PlayerDirection, PlayerInput};
+use anotherworld::system::HeadlessSys;
+
+const PART: u8 = 2;
+/// Gives up if the intro never ends
+const MAX_STEPS: usize = 100_000;
+
+/// Steps until the part changes and returns the frame number, the new part
+/// and the hash of the last displayed frame at that point
+fn run_to_next_part(engine: &mut Engine<HeadlessSys>) -> (u64, u16, u64) {
+    for _ in 0..MAX_STEPS {
+        let info = engine.step().expect("The game quit");
+        if info.part_id != parts::GAME_PART2 {
+            let hash = common::fnv1a(engine.vm().sys().framebuffer());
+            return (info.frame_number, info.part_id, hash);
+        }
+    }
+    panic!("Still in part {} after {} steps", PART, MAX_STEPS);
+}
+
+fn record(path: &Path) -> (u64, u16, u64) {
+    let mut vm = common::headless_vm(common::resource().unwrap());
+    for frame in 0..300 {
+        let mut input = PlayerInput::new();
+        if frame % 40 < 20 {
+            input.direction = PlayerDirection::RIGHT;
+        }
+        input.button = frame % 60 == 0;
+        vm.sys_mut().push_input(input);
+    }
+    vm.set_demo_recorder(DemoRecorder::create(path, parts::GAME_PART2, common::SEED).unwrap());
+    let mut engine = Engine::new(vm, PART).unwrap();
+    let transition = run_to_next_part(&mut engine);
+    engine.finish();
+    assert!(engine.take_error().is_none());
+    transition
+}
+
+fn replay(path: &Path) -> (u64, u16, u64) {
+    let demo = Demo::load(path).unwrap();
+    assert_eq!(demo.part_id, parts::GAME_PART2);
+    let mut vm = common::headless_vm(common::resource().unwrap());
+    vm.set_demo(demo);
+    let mut engine = Engine::new(vm, PART).unwrap();
+    let transition = run_to_next_part(&mut engine);
+    engine.finish();
+    assert!(engine.take_error().is_none());
+    transition
+}
+
+#[test]
+fn replaying_a_demo_reaches_the_same_part_transition() {
+    if common::resource().is_none() {
+        return;
+    }
+    let path = env::temp_dir().join(format!("aw-demo-{}.awd", std::process::id()));
+    let recorded = record(&path);
+    let replayed = replay(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(recorded, replayed, "The demo played differently");
+}
@@ -0,0 +1,65 @@
+//! Runs the intro on the headless backend and compares a hash of the frame
+//! displayed at each checkpoint against `tests/golden/part2.txt`. Needs the
+//! game data in `data` or `$AW_DATA` and is skipped without it. The hashes
+//! are only written when `AW_BLESS` is set, a missing file is a failure.
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anotherworld::engine::Engine;
+
+const PART: u8 = 2;
+const FRAMES: usize = 600;
+/// Displayed frames whose hashes are compared, spread over the intro
+const CHECKPOINTS: [usize; 8] = [0, 50, 100, 200, 300, 400, 500, 599];
+
+#[test]
+fn intro_frames_match_golden_hashes() {
+    let resource = match common::resource() {
+        Some(resource) => resource,
+        None => return,
+    };
+    let mut engine = Engine::new(common::headless_vm(resource), PART).unwrap();
+
+    let mut hashes = Vec::new();
+    let mut displayed = 0;
+    while hashes.len() < FRAMES {
+        if engine.step().is_none() {
+            break;
+        }
+        let sys = engine.vm().sys();
+        if sys.frames_displayed() != displayed {
+            displayed = sys.frames_displayed();
+            hashes.push(common::fnv1a(sys.framebuffer()));
+        }
+    }
+    engine.finish();
+    assert!(engine.take_error().is_none());
+    assert_eq!(hashes.len(), FRAMES, "The intro ended early");
+    let actual: String = CHECKPOINTS
+        .iter()
+        .map(|&frame| format!("{} {:016x}\n", frame, hashes[frame]))
+        .collect();
+
+    let golden = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/part2.txt");
+    if env::var_os("AW_BLESS").is_some() {
+        fs::create_dir_all(golden.parent().unwrap()).unwrap();
+        fs::write(&golden, actual).unwrap();
+        eprintln!("Wrote {}", golden.display());
+        return;
+    }
+    let expected = fs::read_to_string(&golden).unwrap_or_else(|e| {
+        panic!(
+            "Could not read {}: {}. Run with AW_BLESS=1 to write it.",
+            golden.display(),
+            e
+        )
+    });
+    for (a, e) in actual.lines().zip(expected.lines()) {
+        assert_eq!(a, e, "Frame hashes differ from {}", golden.display());
+    }
+    assert_eq!(actual.lines().count(), expected.lines().count());
+}
@@ -0,0 +1,53 @@
+//! Saves a state while playing, runs on, then resumes from the state in a
+//! new VM and checks that the same frames come out. Needs the game data in
+//! `data` or `$AW_DATA` and is skipped without it.
+
+mod common;
+
+use anotherworld::engine::Engine;
+use anotherworld::state::SaveState;
+use anotherworld::system::HeadlessSys;
+
+/// The first part with gameplay. The music isn't part of a save state, so
+/// this runs a part without music marks to wait on.
+const PART: u8 = 3;
+const FRAMES_BEFORE_SAVE: usize = 100;
+const FRAMES_AFTER_SAVE: usize = 200;
+
+/// Steps until `frames` frames were displayed and returns their hashes
+fn run(engine: &mut Engine<HeadlessSys>, frames: usize) -> Vec<u64> {
+    let mut hashes = Vec::new();
+    let mut displayed = engine.vm().sys().frames_displayed();
+    while hashes.len() < frames {
+        engine.step().expect("The game quit");
+        let sys = engine.vm().sys();
+        if sys.frames_displayed() != displayed {
+            displayed = sys.frames_displayed();
+            hashes.push(common::fnv1a(sys.framebuffer()));
+        }
+    }
+    hashes
+}
+
+#[test]
+fn resuming_from_a_save_state_replays_the_same_frames() {
+    let resource = match common::resource() {
+        Some(resource) => resource,
+        None => return,
+    };
+    let mut engine = Engine::new(common::headless_vm(resource), PART).unwrap();
+    run(&mut engine, FRAMES_BEFORE_SAVE);
+    let data = engine.vm().save_state().to_bytes(true).unwrap();
+    let played = run(&mut engine, FRAMES_AFTER_SAVE);
+    engine.finish();
+
+    let state = SaveState::from_bytes(&data).unwrap();
+    let resource = common::resource().unwrap();
+    let mut engine = Engine::from_state(common::headless_vm(resource), &state).unwrap();
+    let replayed = run(&mut engine, FRAMES_AFTER_SAVE);
+    engine.finish();
+
+    for (frame, (a, b)) in played.iter().zip(&replayed).enumerate() {
+        assert_eq!(a, b, "Frame {} after loading differs", frame);
+    }
+}